@@ -10,6 +10,8 @@ pub use server::*;
 pub struct Config {
     pub log_files: LogFilesConfig,
     pub http: HttpConfig,
+    #[serde(skip)]
+    pub mime_types: MimeTypes,
 }
 
 impl Config {
@@ -24,8 +26,14 @@ impl Config {
                 access_log_format: String::new(),
                 timeout: 0,
                 size_limit: 0,
+                mime_types_file: None,
+                max_redirects: HttpConfig::default_max_depth(),
+                max_rewrites: HttpConfig::default_max_depth(),
+                upstreams: HashMap::new(),
+                session: SessionConfig::default(),
                 servers: HashMap::new(),
             },
+            mime_types: MimeTypes::builtin(),
         }
     }
 }
@@ -42,18 +50,133 @@ pub struct HttpConfig {
     pub access_log_format: String,
     pub timeout: u64,
     pub size_limit: usize,
+    #[serde(default)]
+    pub mime_types_file: Option<String>,
+    #[serde(default = "HttpConfig::default_max_depth")]
+    pub max_redirects: usize,
+    #[serde(default = "HttpConfig::default_max_depth")]
+    pub max_rewrites: usize,
+    /// Groupes de serveurs amont désignés par `proxy_pass`.
+    #[serde(default)]
+    pub upstreams: HashMap<String, Upstream>,
+    /// Stockage et nettoyage des sessions.
+    #[serde(default)]
+    pub session: SessionConfig,
     pub servers: HashMap<String, Server>,
 }
 
+impl HttpConfig {
+    fn default_max_depth() -> usize {
+        10
+    }
+}
+
+/// En-tête ajouté aux réponses d'un serveur ou d'une route.
+/// Sans `always`, il n'est envoyé qu'avec les réponses qui ne sont pas des erreurs.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderRule {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub always: bool,
+}
+
+/// Paramètres propres à un préfixe d'URL d'un serveur.
 #[derive(Debug, Deserialize, Clone)]
-pub struct Redirection {
-    pub source: String,
-    pub target: String,
+pub struct Route {
+    pub path: String,
+    #[serde(default)]
+    pub add_headers: Vec<HeaderRule>,
+    /// Interpréteurs CGI propres à la route, ajoutés à ceux du serveur.
+    #[serde(default)]
+    pub cgi: HashMap<String, String>,
+    #[serde(default)]
+    pub cgi_executables: Option<bool>,
+    /// Backend FastCGI qui traite toutes les requêtes de la route.
+    #[serde(default)]
+    pub fastcgi_pass: Option<String>,
+    /// Relaie les requêtes de la route : `http://<upstream ou hôte:port>[/chemin]`.
+    #[serde(default)]
+    pub proxy_pass: Option<String>,
+    /// En-tête `Host` envoyé à l'amont : valeur fixe, ou `$host` pour garder celui du
+    /// client. Par défaut, l'upstream du `proxy_pass`.
+    #[serde(default)]
+    pub proxy_host: Option<String>,
+    /// Attente maximale de données de l'amont en millisecondes (30 s par défaut).
+    #[serde(default)]
+    pub proxy_timeout: Option<u64>,
+    /// Accepte les connexions WebSocket de la route : `echo`, `broadcast` (tous les
+    /// clients de la route), `tcp://hôte:port` ou `exec:commande args`.
+    #[serde(default)]
+    pub websocket: Option<String>,
+    /// Inactivité tolérée avant d'envoyer un ping, en millisecondes (30 s par défaut).
+    #[serde(default)]
+    pub websocket_ping: Option<u64>,
+    /// Authentification Basic propre à la route, à la place de celle du serveur.
+    #[serde(default)]
+    pub auth_basic: Option<AuthBasic>,
+    /// Jeton JWT exigé sur la route ; remplace l'authentification Basic du serveur.
+    #[serde(default)]
+    pub auth_jwt: Option<AuthJwt>,
+    /// Redirige les visiteurs non connectés vers la page `login` du serveur.
+    #[serde(default)]
+    pub require_login: bool,
 }
 
 pub fn load_config() -> Config {
     let content = fs::read_to_string("src/config.toml").unwrap_or(String::new());
-    toml::from_str(&content).unwrap()
+    let mut config: Config = toml::from_str(&content).unwrap();
+
+    // Registre MIME : table intégrée, puis fichier au format /etc/mime.types
+    if let Some(path) = &config.http.mime_types_file {
+        if let Err(e) = config.mime_types.load_file(path) {
+            eprintln!("Impossible de charger le fichier mime_types {} : {}", path, e);
+        }
+    }
+
+    // Une configuration invalide empêche le démarrage : toutes les erreurs sont affichées
+    let mut valid = true;
+    let errors = config.http.session.check();
+    if !errors.is_empty() {
+        eprintln!("Configuration invalide pour les sessions :");
+        for error in errors {
+            eprintln!("  - {}", error);
+        }
+        valid = false;
+    }
+
+    let mut upstreams = config.http.upstreams.keys().cloned().collect::<Vec<String>>();
+    upstreams.sort();
+    for name in upstreams {
+        let errors = config.http.upstreams[&name].check();
+        if !errors.is_empty() {
+            eprintln!("Configuration invalide pour l'upstream {} :", name);
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            valid = false;
+        }
+    }
+
+    let mut names = config.http.servers.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    for name in names {
+        for warning in config.http.servers[&name].check_cgi_settings() {
+            eprintln!("Avertissement pour le serveur {} : {}", name, warning);
+        }
+        let errors = config.http.servers[&name].check_config(&config);
+        if !errors.is_empty() {
+            eprintln!("Configuration invalide pour le serveur {} :", name);
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            valid = false;
+        }
+    }
+    if !valid {
+        std::process::exit(1);
+    }
+    config
 }
 
 pub fn remove_suffix(str: String, suffix: &str) -> String {
@@ -1,34 +1,1069 @@
-use std::process::Command;
+use mio::net::TcpStream;
+use mio::unix::pipe::{ Receiver, Sender };
+use mio::{ Interest, Registry, Token };
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{ self, Read, Write };
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{ Child, Command, Stdio };
+use std::time::{ Duration, Instant };
+
+use super::{ Outgoing, Request, Response, Server, ServerError, Session, SessionCookie, SessionUpdate, SERVER_SIGNATURE };
+use crate::Config;
 
 // -------------------------------------------------------------------------------------
 // CGI
 // -------------------------------------------------------------------------------------
-// const FILETYPE: &str = ".rb";
+/// Script résolu à partir du chemin de la requête (RFC 3875, 4.1.5 et 4.1.13).
+#[derive(Debug, Clone)]
+pub struct CgiScript {
+    /// Chemin du fichier sur le disque.
+    pub filename: String,
+    /// Partie de l'URL qui désigne le script.
+    pub script_name: String,
+    /// Reste de l'URL après le script.
+    pub path_info: String,
+    pub runner: CgiRunner,
+}
+
+/// Programme lancé pour un script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CgiRunner {
+    /// `interpréteur script`
+    Interpreter(String),
+    /// Le script est exécuté directement (bit exécutable).
+    Executable,
+    /// Requête transmise à un backend FastCGI (`unix:/chemin` ou `hôte:port`).
+    FastCgi(String),
+}
+
+/// Réponse produite par un script après analyse de ses en-têtes.
+#[derive(Debug, Clone)]
+pub struct CgiOutput {
+    pub status: u16,
+    pub reason: String,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Ce qu'il advient d'une requête CGI quand `max_processes` scripts tournent déjà.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyPolicy {
+    /// La requête attend qu'un script se termine.
+    #[default]
+    Queue,
+    /// Réponse 503 immédiate.
+    Reject,
+}
+
+/// Limites et isolation des scripts d'un serveur (`[http.servers.<nom>.cgi_sandbox]`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct CgiSandbox {
+    /// Répertoire de travail ; par défaut celui du script.
+    #[serde(default)]
+    pub working_directory: Option<String>,
+    /// Temps CPU maximal en secondes (`RLIMIT_CPU`).
+    #[serde(default)]
+    pub rlimit_cpu: Option<u64>,
+    /// Mémoire virtuelle maximale en octets (`RLIMIT_AS`).
+    #[serde(default)]
+    pub rlimit_as: Option<u64>,
+    /// Nombre maximal de descripteurs ouverts (`RLIMIT_NOFILE`).
+    #[serde(default)]
+    pub rlimit_nofile: Option<u64>,
+    /// Nombre maximal de scripts simultanés pour ce serveur.
+    #[serde(default)]
+    pub max_processes: Option<usize>,
+    #[serde(default)]
+    pub when_busy: BusyPolicy,
+    /// Identité sous laquelle le script est lancé (le serveur doit être root).
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Variables du serveur transmises en plus des variables CGI.
+    #[serde(default = "CgiSandbox::default_pass_env")]
+    pub pass_env: Vec<String>,
+}
+
+impl Default for CgiSandbox {
+    fn default() -> Self {
+        Self {
+            working_directory: None,
+            rlimit_cpu: None,
+            rlimit_as: None,
+            rlimit_nofile: None,
+            max_processes: None,
+            when_busy: BusyPolicy::default(),
+            uid: None,
+            gid: None,
+            pass_env: Self::default_pass_env(),
+        }
+    }
+}
+
+impl CgiSandbox {
+    /// `PATH` reste nécessaire aux scripts qui lancent `#!/usr/bin/env ruby`.
+    pub fn default_pass_env() -> Vec<String> {
+        vec!["PATH".to_string()]
+    }
+
+    /// Problèmes détectables au démarrage.
+    pub fn check(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if let Some(dir) = &self.working_directory {
+            if !Path::new(dir).is_dir() {
+                warnings.push(format!("répertoire de travail CGI introuvable : {}", dir));
+            }
+        }
+        let is_root = unsafe { libc::geteuid() } == 0;
+        if (self.uid.is_some() || self.gid.is_some()) && !is_root {
+            warnings.push(
+                "uid/gid CGI ignorés faute de droits root : les scripts échoueront".to_string()
+            );
+        }
+        if self.max_processes == Some(0) {
+            warnings.push("max_processes = 0 : aucun script ne pourra être lancé".to_string());
+        }
+        warnings
+    }
+
+    /// Applique le bac à sable à une commande : environnement réduit à `pass_env`,
+    /// dossier de travail (`default_directory` si aucun n'est configuré), groupe de
+    /// processus, identité et limites.
+    pub fn configure(&self, command: &mut Command, default_directory: &Path) {
+        let working_directory = match &self.working_directory {
+            Some(dir) => Path::new(dir),
+            None => default_directory,
+        };
+
+        command.env_clear();
+        for name in &self.pass_env {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+        command
+            .current_dir(working_directory)
+            // Permet de tuer le processus et ses enfants d'un seul signal
+            .process_group(0);
+        if let Some(gid) = self.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = self.uid {
+            command.uid(uid);
+        }
+
+        let sandbox = self.clone();
+        unsafe {
+            // Exécuté dans l'enfant entre fork et exec : uniquement des appels système
+            command.pre_exec(move || sandbox.apply_rlimits());
+        }
+    }
+
+    fn apply_rlimits(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.rlimit_cpu),
+            (libc::RLIMIT_AS, self.rlimit_as),
+            (libc::RLIMIT_NOFILE, self.rlimit_nofile),
+        ];
+        for (resource, limit) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let rlimit = libc::rlimit {
+                rlim_cur: limit as libc::rlim_t,
+                rlim_max: limit as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Requête CGI prête à être lancée, éventuellement mise en attente par le Router.
+#[derive(Debug, Clone)]
+pub struct CgiJob {
+    pub server: Server,
+    pub request: Request,
+    pub cookie: String,
+    pub script: CgiScript,
+    pub created: Instant,
+    /// Paramètres du cookie, pour le renvoyer si le script régénère la session.
+    pub session_cookie: SessionCookie,
+}
+
+impl CgiJob {
+    pub fn new(server: &Server, request: &Request, cookie: String, script: CgiScript, config: &Config) -> Self {
+        Self {
+            server: server.clone(),
+            request: request.clone(),
+            cookie,
+            script,
+            created: Instant::now(),
+            session_cookie: server.session_cookie(config).clone(),
+        }
+    }
+
+    /// Le script a trop attendu dans la file.
+    pub fn expired(&self) -> bool {
+        self.created.elapsed() >= Duration::from_millis(self.server.cgi_timeout)
+    }
+
+    /// Échéance d'un script lancé maintenant.
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + Duration::from_millis(self.server.cgi_timeout)
+    }
+
+    pub fn log_error(&self, config: &Config, message: &str) {
+        Server::error_log(
+            &self.request,
+            config,
+            "CgiJob",
+            file!(),
+            line!(),
+            ServerError::CgiError {
+                script: &self.script.filename,
+                uri: &self.request.uri,
+                message,
+            }
+        );
+    }
+
+    /// Écrit la sortie d'erreur du script dans le journal d'erreurs.
+    pub fn log_stderr(&self, config: &Config, stderr: &[u8]) {
+        if stderr.is_empty() {
+            return;
+        }
+        let stderr = String::from_utf8_lossy(stderr).trim_end().to_string();
+        self.log_error(config, &format!("stderr : {}", stderr));
+    }
+}
+
 pub struct CGI;
 
 impl CGI {
-    // pub fn new() -> Self {
-    //     Self {
-    //         file_type: FILETYPE.to_string(),
-    //     }
-    // }
+    /// Lance le script dans son propre groupe de processus, avec des tubes pour
+    /// stdin, stdout et stderr, un environnement réduit aux variables CGI et les
+    /// limites du serveur.
+    pub fn spawn(
+        script: &CgiScript,
+        env: &[(String, String)],
+        sandbox: &CgiSandbox
+    ) -> io::Result<Child> {
+        let mut command = match &script.runner {
+            CgiRunner::Interpreter(interpreter) => {
+                let mut command = Command::new(interpreter);
+                command.arg(&script.filename);
+                command
+            }
+            CgiRunner::Executable => Command::new(&script.filename),
+            CgiRunner::FastCgi(_) => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, "un script FastCGI ne se lance pas")
+                );
+            }
+        };
+
+        let working_directory = Path::new(&script.filename).parent().unwrap_or(Path::new("/"));
+        sandbox.configure(&mut command, working_directory);
+        command
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command.spawn()
+    }
+
+    /// Délai maximal d'exécution d'un script, en millisecondes.
+    pub fn default_timeout() -> u64 {
+        30_000
+    }
+
+    /// Interpréteurs utilisés quand la configuration n'en déclare aucun.
+    pub fn default_interpreters() -> HashMap<String, String> {
+        HashMap::from([(".rb".to_string(), "ruby".to_string())])
+    }
+
+    pub fn is_executable(path: &Path) -> bool {
+        path.metadata().is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+
+    /// Vérifie qu'un interpréteur existe : chemin explicite ou recherche dans le PATH.
+    pub fn interpreter_exists(interpreter: &str) -> bool {
+        if interpreter.contains('/') {
+            return Self::is_executable(Path::new(interpreter));
+        }
+        std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|dir| Self::is_executable(&dir.join(interpreter)))
+        })
+    }
+
+    /// Variables d'environnement de la RFC 3875 pour une requête donnée.
+    pub fn build_env(
+        server: &Server,
+        request: &Request,
+        script: &CgiScript
+    ) -> Vec<(String, String)> {
+        let body_len = request.body_bytes().len();
+        let remote_ip = request.remote_addr
+            .rsplit_once(':')
+            .map(|(ip, _)| ip.to_string())
+            .unwrap_or_else(|| request.remote_addr.clone());
+        let remote_port = request.remote_addr
+            .rsplit_once(':')
+            .map(|(_, port)| port.to_string())
+            .unwrap_or_default();
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+            ("SERVER_SOFTWARE", SERVER_SIGNATURE.to_string()),
+            ("SERVER_NAME", server.hostname.clone()),
+            ("SERVER_PORT", request.port.to_string()),
+            ("REQUEST_METHOD", request.method.clone()),
+            ("REQUEST_URI", request.uri.clone()),
+            ("QUERY_STRING", request.raw_query().to_string()),
+            ("SCRIPT_NAME", script.script_name.clone()),
+            ("SCRIPT_FILENAME", script.filename.clone()),
+            ("PATH_INFO", script.path_info.clone()),
+            ("DOCUMENT_ROOT", Self::absolute(&server.root_directory)),
+            ("REMOTE_ADDR", remote_ip),
+            ("REMOTE_PORT", remote_port),
+            ("REDIRECT_STATUS", "200".to_string()),
+        ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect::<Vec<(String, String)>>();
+
+        if !script.path_info.is_empty() {
+            let translated = format!(
+                "{}{}",
+                Self::absolute(&server.root_directory).trim_end_matches('/'),
+                script.path_info
+            );
+            env.push(("PATH_TRANSLATED".to_string(), translated));
+        }
+        if let Some(content_type) = request.header("Content-Type") {
+            env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+        }
+        if body_len > 0 || request.header("Content-Length").is_some() {
+            env.push(("CONTENT_LENGTH".to_string(), body_len.to_string()));
+        }
+
+        // Host est analysé à part par parse_http_request
+        env.push(("HTTP_HOST".to_string(), format!("{}:{}", request.host, request.port)));
+        for (name, value) in &request.headers {
+            if name.eq_ignore_ascii_case("Content-Type") || name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            // httpoxy : HTTP_PROXY serait pris par le script pour son propre proxy sortant
+            if Self::header_to_env(name) == "HTTP_PROXY" {
+                continue;
+            }
+            // Le mot de passe ou le jeton vérifié par le serveur n'est pas transmis au script
+            if (request.remote_user.is_some() || !request.claims.is_empty()) && name.eq_ignore_ascii_case("Authorization") {
+                continue;
+            }
+            env.push((Self::header_to_env(name), value.clone()));
+        }
+        if !request.claims.is_empty() {
+            env.push(("AUTH_TYPE".to_string(), "Bearer".to_string()));
+        } else if request.remote_user.is_some() {
+            let auth_type = match request.header("Authorization") {
+                Some(_) => "Basic",
+                None => "Form",
+            };
+            env.push(("AUTH_TYPE".to_string(), auth_type.to_string()));
+        }
+        if let Some(user) = &request.remote_user {
+            env.push(("REMOTE_USER".to_string(), user.clone()));
+        }
+        // Claims du jeton JWT : `JWT_SUB`, `JWT_EXP`...
+        for (name, value) in &request.claims {
+            let name = name
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() {
+                    true => c.to_ascii_uppercase(),
+                    false => '_',
+                })
+                .collect::<String>();
+            env.push((format!("JWT_{}", name), value.clone()));
+        }
+        if let Some(session) = &request.session {
+            env.extend(session.to_env());
+        }
+        env
+    }
+
+    /// Les scripts tournent dans leur propre répertoire : les chemins transmis sont absolus.
+    pub fn absolute(path: &str) -> String {
+        std::fs::canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string())
+    }
+
+    /// `User-Agent` -> `HTTP_USER_AGENT`
+    pub fn header_to_env(name: &str) -> String {
+        format!("HTTP_{}", name.trim().to_uppercase().replace('-', "_"))
+    }
+
+    /// Sépare les en-têtes CGI du corps et interprète `Status`, `Content-Type` et `Location`.
+    /// Le corps est conservé tel quel, même s'il n'est pas en UTF-8.
+    /// Une sortie sans bloc d'en-têtes valide est une erreur (502 pour le client).
+    pub fn parse_output(stdout: &[u8]) -> Result<CgiOutput, String> {
+        let mut output = CgiOutput {
+            status: 200,
+            reason: "OK".to_string(),
+            content_type: String::new(),
+            headers: vec![],
+            body: vec![],
+        };
+
+        let (head, body_start) = match Self::split_head(stdout) {
+            Some((Some(head), body_start)) => (head, body_start),
+            Some((None, _)) => {
+                return Err("en-têtes CGI non UTF-8".to_string());
+            }
+            None => {
+                return Err("aucun bloc d'en-têtes CGI".to_string());
+            }
+        };
+
+        let mut status = None;
+        for line in head.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(format!("en-tête CGI invalide : {:?}", line));
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "status" => {
+                    let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+                    match code.parse::<u16>() {
+                        Ok(code) if (100..600).contains(&code) => {
+                            status = Some((code, reason.trim().to_string()));
+                        }
+                        _ => {
+                            return Err(format!("Status CGI invalide : {:?}", value));
+                        }
+                    }
+                }
+                "content-type" => {
+                    output.content_type = value.to_string();
+                }
+                "location" => {
+                    output.headers.push(("Location".to_string(), value.to_string()));
+                }
+                _ => output.headers.push((name.trim().to_string(), value.to_string())),
+            }
+        }
+
+        let has_location = output.headers.iter().any(|(name, _)| name == "Location");
+        match status {
+            Some((code, reason)) => {
+                output.status = code;
+                output.reason = reason;
+            }
+            // Redirection demandée par le script sans Status explicite
+            None if has_location => {
+                output.status = 302;
+                output.reason = "Found".to_string();
+            }
+            // RFC 3875, 6.2 : au moins un de Content-Type, Location ou Status
+            None if output.content_type.is_empty() => {
+                return Err("ni Content-Type, ni Location, ni Status".to_string());
+            }
+            None => (),
+        }
+        output.body = stdout[body_start..].to_vec();
+        Ok(output)
+    }
+
+    /// Renvoie le bloc d'en-têtes (`None` s'il n'est pas en UTF-8) et la position du corps
+    /// (fin de ligne vide `\r\n\r\n` ou `\n\n`).
+    fn split_head(stdout: &[u8]) -> Option<(Option<String>, usize)> {
+        let crlf = stdout.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| (pos, pos + 4));
+        let lf = stdout.windows(2).position(|w| w == b"\n\n").map(|pos| (pos, pos + 2));
+        let (end, body_start) = match (crlf, lf) {
+            (Some(a), Some(b)) => if a.0 <= b.0 { a } else { b },
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => {
+                return None;
+            }
+        };
+        let head = String::from_utf8(stdout[..end].to_vec()).ok();
+        Some((head, body_start))
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// CGI PROCESS
+// -------------------------------------------------------------------------------------
+/// Taille maximale conservée de la sortie d'erreur d'un script.
+const MAX_STDERR: usize = 64 * 1024;
+/// Taille maximale du bloc d'en-têtes d'un script ; au-delà la réponse est un 502.
+pub const MAX_CGI_HEAD: usize = 64 * 1024;
+/// Sortie en attente au-delà de laquelle le script n'est plus lu tant que le client
+/// n'a pas reçu la suite.
+pub const CGI_HIGH_WATER: usize = 256 * 1024;
+
+/// État d'un script après un événement.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CgiState {
+    /// Le script tourne encore (ou sa fin n'a pas encore été constatée).
+    Running,
+    /// La réponse est complète.
+    Done,
+    /// La réponse ne peut pas être terminée : la connexion doit être fermée.
+    Aborted,
+}
 
-    pub fn execute_file(filename: String) -> String {
-        let mut input = Command::new("ruby");
+/// Sortie d'un script transmise au client au fil de l'eau : les en-têtes CGI sont
+/// analysés dès qu'ils sont complets, puis le corps part en `Transfer-Encoding: chunked`.
+/// Les octets passent par `outgoing`, vidé quand le client est prêt à recevoir.
+#[derive(Debug, Default)]
+pub struct CgiReply {
+    /// Sortie reçue tant que les en-têtes ne sont pas complets.
+    output: Vec<u8>,
+    pub headers_sent: bool,
+    /// Réponse entièrement produite : il ne reste qu'à vider `outgoing`.
+    complete: bool,
+    pub outgoing: Outgoing,
+    /// Code renvoyé au client, pour le journal d'accès.
+    pub status: u16,
+    /// Modifications de session demandées par le script, appliquées par le Router.
+    pub session_updates: Vec<SessionUpdate>,
+}
+
+impl CgiReply {
+    /// Nombre d'octets que la réponse peut encore accepter avant que la lecture du
+    /// script ne soit suspendue (0 quand le client ne suit pas).
+    pub fn room(&self) -> usize {
+        match self.complete {
+            true => 0,
+            false => CGI_HIGH_WATER.saturating_sub(self.outgoing.len()),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Ajoute une partie de la sortie. `Err(message)` si les en-têtes sont invalides
+    /// ou dépassent `MAX_CGI_HEAD`.
+    pub fn push(&mut self, job: &CgiJob, data: &[u8]) -> Result<(), String> {
+        if self.headers_sent {
+            if !data.is_empty() {
+                self.outgoing.push(&Response::chunk(data));
+            }
+            return Ok(());
+        }
+
+        self.output.extend_from_slice(data);
+        if CGI::split_head(&self.output).is_none() {
+            return match self.output.len() > MAX_CGI_HEAD {
+                true => Err(format!("en-têtes de plus de {} octets", MAX_CGI_HEAD)),
+                false => Ok(()),
+            };
+        }
+        let output = CGI::parse_output(&self.output)?;
+        self.output.clear();
+        self.send_head(job, output);
+        Ok(())
+    }
+
+    /// Termine la réponse : dernier morceau vide, ou réponse complète si le bloc
+    /// d'en-têtes n'a pas encore été envoyé.
+    pub fn finish(&mut self, job: &CgiJob, config: &Config) -> Result<(), String> {
+        if self.headers_sent {
+            self.outgoing.push(b"0\r\n\r\n");
+            job.server.access_log(&job.request, config, self.status, &job.cookie);
+            self.complete = true;
+            return Ok(());
+        }
 
-        input.arg(filename);
-        let res = input.output();
+        let output = CGI::parse_output(&self.output)?;
+        let response = self.response(job, output);
+        self.headers_sent = true;
+        self.complete = true;
+        job.server.queue_response(&mut self.outgoing, &job.request, config, response);
+        Ok(())
+    }
+
+    /// Journalise l'erreur et prépare un 502, ou demande la fermeture de la connexion
+    /// si la réponse est déjà commencée.
+    pub fn fail(&mut self, job: &CgiJob, config: &Config, message: &str) -> CgiState {
+        job.log_error(config, message);
+        if self.headers_sent {
+            return CgiState::Aborted;
+        }
+        self.headers_sent = true;
+        self.complete = true;
+        self.status = 502;
+        let response = job.server.error_response(&job.request, config, 502, "Bad Gateway", &job.cookie);
+        job.server.queue_response(&mut self.outgoing, &job.request, config, response);
+        CgiState::Running
+    }
+
+    /// Envoie au client ce qu'il accepte ; `Done` une fois la réponse complète partie.
+    pub fn flush(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        self.outgoing.flush(stream)?;
+        match self.complete && self.outgoing.is_empty() {
+            true => Ok(CgiState::Done),
+            false => Ok(CgiState::Running),
+        }
+    }
 
-        if res.is_ok() {
-            let check_txt = String::from_utf8(res.unwrap().stdout);
-            if check_txt.is_ok() {
-                check_txt.unwrap()
-            } else {
-                String::new()
+    /// Met en file la ligne de statut, les en-têtes et le début du corps déjà reçu.
+    fn send_head(&mut self, job: &CgiJob, output: CgiOutput) {
+        let mut response = self.response(job, output);
+        let body = std::mem::take(&mut response.body);
+        response.set_header("Transfer-Encoding", "chunked");
+        job.server.prepare_response(&job.request, &mut response);
+
+        self.outgoing.push(response.to_http_head().as_bytes());
+        if !body.is_empty() {
+            self.outgoing.push(&Response::chunk(&body));
+        }
+        self.headers_sent = true;
+    }
+
+    fn response(&mut self, job: &CgiJob, output: CgiOutput) -> Response {
+        let reason = match output.reason.is_empty() {
+            true => Response::reason_phrase(output.status).to_string(),
+            false => output.reason,
+        };
+        self.status = output.status;
+        let mut response = Response::new(
+            job.cookie.clone(),
+            format!("{} {}", output.status, reason),
+            output.content_type,
+            output.body
+        );
+        for (name, value) in &output.headers {
+            // Les en-têtes de session ne sont pas transmis au client
+            match Session::parse_update(name, value) {
+                Some(update) => {
+                    if let (SessionUpdate::Regenerate(id), Some(session)) = (&update, &job.request.session) {
+                        // Le client reçoit tout de suite le nouvel identifiant
+                        let mut renewed = session.clone();
+                        renewed.id = id.clone();
+                        response.id_session = job.session_cookie.header(&renewed);
+                    }
+                    self.session_updates.push(update);
+                }
+                None => response.add_header(name, value),
             }
-        } else {
-            String::new()
         }
+        response
     }
 }
+
+/// Script en cours d'exécution, piloté par la boucle d'événements du Router.
+#[derive(Debug)]
+pub struct CgiProcess {
+    /// Token du client qui attend la réponse.
+    pub client: Token,
+    pub job: CgiJob,
+    pub child: Child,
+    stdin: Option<Sender>,
+    stdout: Option<Receiver>,
+    stderr: Option<Receiver>,
+    input: Vec<u8>,
+    written: usize,
+    pub reply: CgiReply,
+    pub stderr_output: Vec<u8>,
+    pub deadline: Instant,
+}
+
+impl CgiProcess {
+    /// Lance le script ; les tubes sont passés en mode non bloquant.
+    pub fn start(job: &CgiJob) -> io::Result<Self> {
+        let env = CGI::build_env(&job.server, &job.request, &job.script);
+        let mut child = CGI::spawn(&job.script, &env, &job.server.cgi_sandbox)?;
+
+        let stdin = child.stdin.take().map(Sender::from);
+        let stdout = child.stdout.take().map(Receiver::from);
+        let stderr = child.stderr.take().map(Receiver::from);
+        for sender in stdin.iter() {
+            sender.set_nonblocking(true)?;
+        }
+        for receiver in stdout.iter().chain(stderr.iter()) {
+            receiver.set_nonblocking(true)?;
+        }
+
+        Ok(Self {
+            client: Token(0),
+            job: job.clone(),
+            child,
+            stdin,
+            stdout,
+            stderr,
+            input: job.request.body_bytes().to_vec(),
+            written: 0,
+            reply: CgiReply::default(),
+            stderr_output: vec![],
+            deadline: job.deadline(),
+        })
+    }
+
+    /// Enregistre les tubes dans le `Poll` du Router.
+    pub fn register(
+        &mut self,
+        registry: &Registry,
+        client: Token,
+        tokens: [Token; 3]
+    ) -> io::Result<()> {
+        self.client = client;
+        if self.input.is_empty() {
+            // Rien à envoyer : le script lit directement EOF
+            self.stdin = None;
+        }
+        if let Some(stdin) = self.stdin.as_mut() {
+            registry.register(stdin, tokens[0], Interest::WRITABLE)?;
+        }
+        if let Some(stdout) = self.stdout.as_mut() {
+            registry.register(stdout, tokens[1], Interest::READABLE)?;
+        }
+        if let Some(stderr) = self.stderr.as_mut() {
+            registry.register(stderr, tokens[2], Interest::READABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Fait avancer les trois tubes et le client, puis termine la réponse si le script
+    /// est sorti.
+    pub fn on_event(
+        &mut self,
+        registry: &Registry,
+        stream: &mut TcpStream,
+        config: &Config
+    ) -> io::Result<CgiState> {
+        self.write_stdin(registry)?;
+        self.read_stderr(registry)?;
+        loop {
+            // Vider d'abord la file libère de la place pour la suite de la sortie
+            self.reply.flush(stream)?;
+            match self.read_stdout(registry)? {
+                // Le tube ne signalera plus ce qui reste à lire : on continue tant que
+                // le client suit, sinon son prochain WRITABLE relancera la lecture
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(message) => {
+                    self.kill();
+                    return match self.reply.fail(&self.job, config, &message) {
+                        CgiState::Aborted => Ok(CgiState::Aborted),
+                        _ => self.reply.flush(stream),
+                    };
+                }
+            }
+        }
+        self.try_finish(stream, config)
+    }
+
+    /// `true` quand stdout est fermé et que seule la fin du processus est attendue.
+    pub fn output_closed(&self) -> bool {
+        self.stdout.is_none()
+    }
+
+    /// Termine la réponse une fois le processus sorti, selon son code de sortie.
+    pub fn try_finish(&mut self, stream: &mut TcpStream, config: &Config) -> io::Result<CgiState> {
+        if self.reply.is_complete() {
+            return self.reply.flush(stream);
+        }
+        if !self.output_closed() {
+            return Ok(CgiState::Running);
+        }
+        let Some(exit_status) = self.child.try_wait()? else {
+            return Ok(CgiState::Running);
+        };
+        if let Some(stderr) = self.stderr.as_mut() {
+            drain(stderr, &mut self.stderr_output)?;
+            self.stderr_output.truncate(MAX_STDERR);
+        }
+        self.log_stderr(config);
+
+        let result = match exit_status.success() {
+            true => self.reply.finish(&self.job, config),
+            false => Err(format!("le script s'est terminé avec {}", exit_status)),
+        };
+        if let Err(message) = result {
+            if self.reply.fail(&self.job, config, &message) == CgiState::Aborted {
+                return Ok(CgiState::Aborted);
+            }
+        }
+        self.reply.flush(stream)
+    }
+
+    /// Écrit la sortie d'erreur accumulée dans le journal d'erreurs.
+    pub fn log_stderr(&mut self, config: &Config) {
+        self.job.log_stderr(config, &self.stderr_output);
+        self.stderr_output.clear();
+    }
+
+    fn write_stdin(&mut self, registry: &Registry) -> io::Result<()> {
+        let Some(stdin) = self.stdin.as_mut() else {
+            return Ok(());
+        };
+        while self.written < self.input.len() {
+            match stdin.write(&self.input[self.written..]) {
+                Ok(n) => {
+                    self.written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // Un script peut ne pas lire son entrée : on ignore le tube fermé
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    break;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        registry.deregister(stdin)?;
+        self.stdin = None;
+        Ok(())
+    }
+
+    fn read_stderr(&mut self, registry: &Registry) -> io::Result<()> {
+        let Some(stderr) = self.stderr.as_mut() else {
+            return Ok(());
+        };
+        let eof = drain(stderr, &mut self.stderr_output)?;
+        self.stderr_output.truncate(MAX_STDERR);
+        if eof {
+            registry.deregister(stderr)?;
+            self.stderr = None;
+        }
+        Ok(())
+    }
+
+    /// Lit la sortie disponible, sans dépasser ce que la réponse peut garder en
+    /// attente : la lecture reprend quand le client a reçu la suite.
+    /// Renvoie le nombre d'octets lus, ou `Err(message)` si les en-têtes du script
+    /// sont invalides.
+    fn read_stdout(&mut self, registry: &Registry) -> io::Result<Result<usize, String>> {
+        let room = self.reply.room();
+        let Some(stdout) = self.stdout.as_mut().filter(|_| room > 0) else {
+            return Ok(Ok(0));
+        };
+        let mut data = vec![];
+        let eof = drain_limit(stdout, &mut data, room)?;
+        if eof {
+            registry.deregister(stdout)?;
+            self.stdout = None;
+        }
+        Ok(self.reply.push(&self.job, &data).map(|_| data.len()))
+    }
+
+    /// Tue le groupe de processus du script (le script et ses éventuels enfants).
+    pub fn kill(&mut self) {
+        let pid = self.child.id() as libc::pid_t;
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+
+    /// Retire les tubes encore ouverts du `Poll`.
+    pub fn deregister(&mut self, registry: &Registry) {
+        if let Some(mut stdin) = self.stdin.take() {
+            let _ = registry.deregister(&mut stdin);
+        }
+        if let Some(mut stdout) = self.stdout.take() {
+            let _ = registry.deregister(&mut stdout);
+        }
+        if let Some(mut stderr) = self.stderr.take() {
+            let _ = registry.deregister(&mut stderr);
+        }
+    }
+}
+
+/// Lit tout ce qui est disponible sur une source non bloquante.
+/// Renvoie `true` à la fin du flux.
+pub fn drain<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    drain_limit(reader, buffer, usize::MAX)
+}
+
+/// Comme `drain`, sans lire plus de `limit` octets.
+pub fn drain_limit<R: Read>(reader: &mut R, buffer: &mut Vec<u8>, limit: usize) -> io::Result<bool> {
+    let mut chunk = [0u8; 8192];
+    let start = buffer.len();
+    while buffer.len() - start < limit {
+        let size = (limit - (buffer.len() - start)).min(chunk.len());
+        match reader.read(&mut chunk[..size]) {
+            Ok(0) => {
+                return Ok(true);
+            }
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(false);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Ok(false)
+}
 // -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_headers() {
+        let output = CGI::parse_output(
+            b"Status: 404 Not Found\r\nContent-Type: text/html\r\nX-Test: 1\r\n\r\n<h1>absent</h1>"
+        ).unwrap();
+        assert_eq!(output.status, 404);
+        assert_eq!(output.reason, "Not Found");
+        assert_eq!(output.content_type, "text/html");
+        assert_eq!(output.headers, vec![("X-Test".to_string(), "1".to_string())]);
+        assert_eq!(output.body, b"<h1>absent</h1>");
+    }
+
+    #[test]
+    fn test_parse_output_location_and_lf() {
+        let output = CGI::parse_output(b"Location: /merci\n\n").unwrap();
+        assert_eq!(output.status, 302);
+        assert_eq!(output.headers, vec![("Location".to_string(), "/merci".to_string())]);
+        assert!(output.body.is_empty());
+    }
+
+    #[test]
+    fn test_parse_output_malformed() {
+        // Pas de bloc d'en-têtes
+        assert!(CGI::parse_output(b"Hello World\n").is_err());
+        assert!(CGI::parse_output(b"").is_err());
+        // Ligne sans `:` ou Status invalide
+        assert!(CGI::parse_output(b"Hello\nWorld\n\n").is_err());
+        assert!(CGI::parse_output(b"Status: abc\n\n").is_err());
+        // Aucun des en-têtes obligatoires
+        assert!(CGI::parse_output(b"X-Test: 1\n\n").is_err());
+        // En-têtes non UTF-8
+        assert!(CGI::parse_output(b"Content-Type: text/\xff\n\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_output_binary_body() {
+        let mut stdout = b"Content-Type: image/png\r\n\r\n".to_vec();
+        let body = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0xff];
+        stdout.extend_from_slice(&body);
+        let output = CGI::parse_output(&stdout).unwrap();
+        assert_eq!(output.content_type, "image/png");
+        assert_eq!(output.body, body);
+    }
+
+    #[test]
+    fn test_spawn_with_env_and_stdin() {
+        let filename = std::env::temp_dir().join(format!("cgi_test_{}.sh", std::process::id()));
+        std::fs::write(&filename, "printf \"$QUERY_STRING:\"; cat").unwrap();
+        let script = CgiScript {
+            filename: filename.to_string_lossy().to_string(),
+            script_name: "/test.sh".to_string(),
+            path_info: String::new(),
+            runner: CgiRunner::Interpreter("/bin/sh".to_string()),
+        };
+        let env = vec![("QUERY_STRING".to_string(), "a=1".to_string())];
+
+        let mut child = CGI::spawn(&script, &env, &CgiSandbox::default()).unwrap();
+        child.stdin.take().unwrap().write_all(b"corps").unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(output.stdout, b"a=1:corps");
+    }
+
+    #[test]
+    fn test_spawn_sandbox() {
+        let dir = std::env::temp_dir();
+        let filename = dir.join(format!("cgi_sandbox_{}.sh", std::process::id()));
+        std::fs::write(&filename, "ulimit -n; pwd; echo \"${HOME:-vide}\"").unwrap();
+        let script = CgiScript {
+            filename: filename.to_string_lossy().to_string(),
+            script_name: "/sandbox.sh".to_string(),
+            path_info: String::new(),
+            runner: CgiRunner::Interpreter("/bin/sh".to_string()),
+        };
+        let sandbox: CgiSandbox = toml::from_str("rlimit_nofile = 16").unwrap();
+        assert_eq!(sandbox.pass_env, vec!["PATH".to_string()]);
+        assert_eq!(sandbox.when_busy, BusyPolicy::Queue);
+
+        let child = CGI::spawn(&script, &[], &sandbox).unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        let expected = format!("16\n{}\nvide\n", std::fs::canonicalize(&dir).unwrap().display());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    }
+
+    fn server_and_script() -> (Server, CgiScript) {
+        let server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            "src/static_files/error.html".to_string(),
+            "src/static_files/index.html".to_string(),
+            5000,
+            vec!["GET".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        let script = CgiScript {
+            filename: "test.sh".to_string(),
+            script_name: "/test.sh".to_string(),
+            path_info: String::new(),
+            runner: CgiRunner::Interpreter("/bin/sh".to_string()),
+        };
+        (server, script)
+    }
+
+    #[test]
+    fn test_reply_head_limit() {
+        let (server, script) = server_and_script();
+        let job = CgiJob::new(&server, &Request::default(), String::new(), script, &Config::new());
+
+        // Sortie sans fin d'en-têtes : refusée au-delà de MAX_CGI_HEAD
+        let mut reply = CgiReply::default();
+        assert!(reply.push(&job, &vec![b'a'; MAX_CGI_HEAD]).is_ok());
+        assert!(reply.push(&job, b"a").is_err());
+
+        // Les en-têtes complets partent en file, puis le corps par morceaux
+        let mut reply = CgiReply::default();
+        reply.push(&job, b"Content-Type: text/plain\r\n\r\nbonjour").unwrap();
+        assert!(reply.headers_sent);
+        assert!(!reply.outgoing.is_empty());
+        assert_eq!(reply.room(), CGI_HIGH_WATER - reply.outgoing.len());
+    }
+
+    #[test]
+    fn test_header_to_env() {
+        assert_eq!(CGI::header_to_env("User-Agent"), "HTTP_USER_AGENT");
+        assert_eq!(CGI::header_to_env("x-forwarded-for"), "HTTP_X_FORWARDED_FOR");
+    }
+
+    #[test]
+    fn test_build_env_skips_proxy() {
+        let (server, script) = server_and_script();
+        let mut request = Request::default();
+        request.headers.insert("Proxy".to_string(), "http://evil:8888".to_string());
+        request.headers.insert("Proxy-Agent".to_string(), "x".to_string());
+        let env = CGI::build_env(&server, &request, &script);
+        assert!(!env.iter().any(|(name, _)| name == "HTTP_PROXY"));
+        assert!(env.iter().any(|(name, _)| name == "HTTP_PROXY_AGENT"));
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Deserialize;
+
+use super::{ constant_eq, Request };
+
+// -------------------------------------------------------------------------------------
+// CSRF
+// -------------------------------------------------------------------------------------
+/// Protection des envois de fichiers, créations de dossiers et suppressions
+/// (`csrf` d'un serveur).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsrfProtection {
+    /// Jeton de la session, transmis dans l'en-tête `X-CSRF-Token` (ou le champ
+    /// `csrf_token` d'un formulaire urlencoded).
+    #[default]
+    Token,
+    /// En-tête `Origin` (ou `Referer`) du même site que la requête.
+    Origin,
+    Off,
+}
+
+impl CsrfProtection {
+    /// En-tête qui porte le jeton de session.
+    pub const HEADER: &'static str = "X-CSRF-Token";
+    /// Champ des formulaires `application/x-www-form-urlencoded` qui porte le jeton.
+    pub const FIELD: &'static str = "csrf_token";
+
+    /// Vérifie une requête qui modifie le dossier ; l'erreur donne la raison du refus.
+    pub fn verify(&self, request: &Request) -> Result<(), &'static str> {
+        match self {
+            Self::Token => {
+                let Some(expected) = request.session.as_ref().map(|session| &session.csrf_token) else {
+                    return Err("aucune session");
+                };
+                match request.header(Self::HEADER).map(str::to_string).or_else(|| Self::form_token(request)) {
+                    Some(token) if constant_eq(token.trim(), expected) => Ok(()),
+                    Some(_) => Err("jeton CSRF invalide"),
+                    None => Err("jeton CSRF absent"),
+                }
+            }
+            Self::Origin => {
+                let source = request
+                    .header("Origin")
+                    .or_else(|| request.header("Referer"))
+                    .ok_or("en-têtes Origin et Referer absents")?;
+                match Self::authority(source) {
+                    Some((host, port)) if host.eq_ignore_ascii_case(&request.host) && port == request.port => Ok(()),
+                    _ => Err("origine différente"),
+                }
+            }
+            Self::Off => Ok(()),
+        }
+    }
+
+    /// Jeton envoyé dans le corps d'un formulaire urlencoded.
+    fn form_token(request: &Request) -> Option<String> {
+        if !request.header("Content-Type")?.starts_with("application/x-www-form-urlencoded") {
+            return None;
+        }
+        request.body
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == Self::FIELD)
+            .and_then(|(_, value)| urlencoding::decode(value).ok())
+            .map(|value| value.into_owned())
+    }
+
+    /// Hôte et port d'une URL `http(s)://hôte[:port]/...`.
+    fn authority(url: &str) -> Option<(&str, u16)> {
+        let (scheme, rest) = url.split_once("://")?;
+        let authority = rest.split(['/', '?', '#']).next()?;
+        let default_port = match scheme.to_ascii_lowercase().as_str() {
+            "http" => 80,
+            "https" => 443,
+            _ => return None,
+        };
+        match authority.rsplit_once(':') {
+            Some((host, port)) => Some((host, port.parse().ok()?)),
+            None => Some((authority, default_port)),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Session;
+
+    #[test]
+    fn test_csrf_protection() {
+        let session = Session::new();
+        let mut request = Request::default();
+        request.host = "mylocalhost".to_string();
+        request.port = 8080;
+        assert_eq!(CsrfProtection::Token.verify(&request), Err("aucune session"));
+
+        request.session = Some(session.clone());
+        assert_eq!(CsrfProtection::Token.verify(&request), Err("jeton CSRF absent"));
+        request.headers.insert("X-CSRF-Token".to_string(), "faux".to_string());
+        assert_eq!(CsrfProtection::Token.verify(&request), Err("jeton CSRF invalide"));
+        request.headers.insert("X-CSRF-Token".to_string(), session.csrf_token.clone());
+        assert_eq!(CsrfProtection::Token.verify(&request), Ok(()));
+        request.headers.remove("X-CSRF-Token");
+        request.body = format!("a=1&csrf_token={}", session.csrf_token);
+        assert_eq!(CsrfProtection::Token.verify(&request), Err("jeton CSRF absent"));
+        request.headers.insert("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string());
+        assert_eq!(CsrfProtection::Token.verify(&request), Ok(()));
+        request.body.clear();
+
+        assert_eq!(CsrfProtection::Origin.verify(&request), Err("en-têtes Origin et Referer absents"));
+        request.headers.insert("Referer".to_string(), "http://MyLocalHost:8080/dossier/".to_string());
+        assert_eq!(CsrfProtection::Origin.verify(&request), Ok(()));
+        request.headers.insert("Origin".to_string(), "http://mylocalhost".to_string());
+        assert_eq!(CsrfProtection::Origin.verify(&request), Err("origine différente"));
+        request.headers.insert("Origin".to_string(), "null".to_string());
+        assert_eq!(CsrfProtection::Origin.verify(&request), Err("origine différente"));
+
+        request.headers.clear();
+        assert_eq!(CsrfProtection::Off.verify(&request), Ok(()));
+    }
+}
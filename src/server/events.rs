@@ -0,0 +1,432 @@
+use mio::net::TcpStream;
+use mio::unix::SourceFd;
+use mio::{ Interest, Registry, Token };
+use regex::RegexSet;
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{ AsRawFd, FromRawFd };
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{ Duration, Instant };
+
+use super::{ drain, CgiState, Outgoing, Request, Response, Server, ServerError };
+use crate::Config;
+
+// -------------------------------------------------------------------------------------
+// DIRECTORY EVENTS
+// -------------------------------------------------------------------------------------
+/// Changements suivis dans le dossier.
+const WATCH_MASK: u32 = libc::IN_CREATE |
+    libc::IN_DELETE |
+    libc::IN_MOVED_FROM |
+    libc::IN_MOVED_TO |
+    libc::IN_DELETE_SELF |
+    libc::IN_MOVE_SELF;
+/// Taille fixe d'un `struct inotify_event`, avant le nom.
+const INOTIFY_HEADER: usize = 16;
+/// Intervalle des commentaires envoyés pour garder la connexion ouverte.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Délai de reconnexion suggéré au navigateur, en millisecondes.
+const RETRY: u64 = 3000;
+/// Flux SSE ouverts en même temps : chacun occupe une instance inotify, limitée par
+/// le noyau (`fs.inotify.max_user_instances`, 128 par défaut).
+pub const MAX_WATCHES: usize = 64;
+/// Événements en attente pour un client qui ne lit plus : au-delà, le flux est fermé
+/// et le navigateur, en se reconnectant, recharge la liste.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Changement envoyé au navigateur, en JSON dans un événement du même nom.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DirectoryEvent {
+    Create {
+        entry: String,
+        link: String,
+        is_directory: bool,
+    },
+    Delete {
+        entry: String,
+        link: String,
+        is_directory: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+        link: String,
+        is_directory: bool,
+    },
+    /// Des événements ont été perdus : la liste doit être rechargée.
+    Overflow,
+}
+
+impl DirectoryEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Create { .. } => "create",
+            Self::Delete { .. } => "delete",
+            Self::Rename { .. } => "rename",
+            Self::Overflow => "overflow",
+        }
+    }
+
+    /// Bloc `text/event-stream` : `event: <type>` puis `data: <json>`.
+    pub fn to_sse(&self) -> String {
+        let data = tera::to_value(self)
+            .map(|value| value.to_string())
+            .unwrap_or_default();
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+}
+
+/// Événement brut lu sur le descripteur inotify.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InotifyEvent {
+    pub mask: u32,
+    pub cookie: u32,
+    pub name: String,
+}
+
+impl InotifyEvent {
+    /// Découpe les `struct inotify_event` complets en tête du tampon et renvoie le
+    /// nombre d'octets consommés.
+    pub fn parse_all(buffer: &[u8]) -> (Vec<InotifyEvent>, usize) {
+        let mut events = vec![];
+        let mut offset = 0;
+        while buffer.len() >= offset + INOTIFY_HEADER {
+            let field = |i: usize| {
+                let start = offset + 4 * i;
+                u32::from_ne_bytes([buffer[start], buffer[start + 1], buffer[start + 2], buffer[start + 3]])
+            };
+            let (mask, cookie, len) = (field(1), field(2), field(3) as usize);
+            if buffer.len() < offset + INOTIFY_HEADER + len {
+                break;
+            }
+            // Le nom est complété par des octets nuls
+            let name = &buffer[offset + INOTIFY_HEADER..offset + INOTIFY_HEADER + len];
+            let name = name.split(|byte| *byte == 0).next().unwrap_or_default();
+            events.push(InotifyEvent {
+                mask,
+                cookie,
+                name: String::from_utf8_lossy(name).to_string(),
+            });
+            offset += INOTIFY_HEADER + len;
+        }
+        (events, offset)
+    }
+}
+
+/// Flux d'événements d'un dossier demandé par `GET /dossier/?events`.
+#[derive(Debug, Clone)]
+pub struct DirectoryEventsJob {
+    pub server: Server,
+    pub request: Request,
+    pub cookie: String,
+    /// Chemin du dossier sur le disque.
+    pub directory: String,
+}
+
+impl DirectoryEventsJob {
+    pub fn new(server: &Server, request: &Request, cookie: &str, directory: String) -> Self {
+        Self {
+            server: server.clone(),
+            request: request.clone(),
+            cookie: cookie.to_string(),
+            directory,
+        }
+    }
+
+    /// En-têtes du flux, envoyé en `Transfer-Encoding: chunked` tant que le client reste.
+    pub fn response(&self) -> Response {
+        let mut response = Response::with_code(200, "text/event-stream", vec![]);
+        response.id_session = self.cookie.clone();
+        response.add_header("Cache-Control", "no-cache");
+        response.add_header("Transfer-Encoding", "chunked");
+        response.add_header("X-Accel-Buffering", "no");
+        response
+    }
+
+    pub fn log_error(&self, config: &Config, e: &io::Error) {
+        Server::error_log(&self.request, config, "DirectoryEventsJob", file!(), line!(), ServerError::IOError(e));
+    }
+}
+
+/// Dossier surveillé par inotify, dont les changements partent vers un client SSE.
+#[derive(Debug)]
+pub struct DirectoryWatch {
+    /// Token du client qui reçoit le flux.
+    pub client: Token,
+    pub job: DirectoryEventsJob,
+    inotify: File,
+    incoming: Vec<u8>,
+    /// Flux en attente d'envoi au client.
+    outgoing: Outgoing,
+    /// Le dossier a disparu : le flux se termine une fois `outgoing` envoyé.
+    closing: bool,
+    exclusion: Option<RegexSet>,
+    /// Prochain commentaire de maintien de la connexion.
+    pub deadline: Instant,
+}
+
+impl DirectoryWatch {
+    /// Ouvre une instance inotify non bloquante sur le dossier.
+    pub fn start(job: &DirectoryEventsJob) -> io::Result<Self> {
+        let path = CString::new(Path::new(&job.directory).as_os_str().as_bytes()).map_err(|_|
+            io::Error::new(io::ErrorKind::InvalidInput, "chemin de dossier invalide")
+        )?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Le File ferme le descripteur, y compris en cas d'erreur ci-dessous
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), WATCH_MASK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            client: Token(0),
+            job: job.clone(),
+            inotify,
+            incoming: vec![],
+            outgoing: Outgoing::default(),
+            closing: false,
+            exclusion: RegexSet::new(&job.server.exclusion).ok(),
+            deadline: Instant::now() + KEEP_ALIVE,
+        })
+    }
+
+    pub fn register(&mut self, registry: &Registry, client: Token, token: Token) -> io::Result<()> {
+        self.client = client;
+        registry.register(&mut SourceFd(&self.inotify.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut SourceFd(&self.inotify.as_raw_fd()));
+    }
+
+    /// Envoie les en-têtes et le délai de reconnexion.
+    pub fn send_head(&mut self, stream: &mut TcpStream, config: &Config) -> io::Result<CgiState> {
+        let mut response = self.job.response();
+        self.job.server.prepare_response(&self.job.request, &mut response);
+        self.outgoing.push(response.to_http_head().as_bytes());
+        self.outgoing.push(&Response::chunk(format!("retry: {}\n\n", RETRY).as_bytes()));
+        self.job.server.access_log(&self.job.request, config, 200, &self.job.cookie);
+        self.flush(stream)
+    }
+
+    /// Transmet les changements lus sur inotify. `Done` quand le dossier a disparu.
+    pub fn on_event(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        drain(&mut self.inotify, &mut self.incoming)?;
+        let (raw, size) = InotifyEvent::parse_all(&self.incoming);
+        self.incoming.drain(..size);
+
+        let gone = raw.iter().any(|event| event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0);
+        let events = self.translate(raw);
+        if !events.is_empty() {
+            let data = events
+                .iter()
+                .map(|event| event.to_sse())
+                .collect::<String>();
+            self.outgoing.push(&Response::chunk(data.as_bytes()));
+            self.deadline = Instant::now() + KEEP_ALIVE;
+        }
+        if gone && !self.closing {
+            // Le navigateur se reconnecte et obtient un 404
+            self.outgoing.push(b"0\r\n\r\n");
+            self.closing = true;
+        }
+        self.flush(stream)
+    }
+
+    /// Commentaire SSE qui garde la connexion ouverte et révèle un client parti.
+    pub fn keep_alive(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        self.deadline = Instant::now() + KEEP_ALIVE;
+        if self.outgoing.is_empty() {
+            self.outgoing.push(&Response::chunk(b": keep-alive\n\n"));
+        }
+        self.flush(stream)
+    }
+
+    /// Envoie ce que le client accepte. `Done` une fois le flux terminé envoyé,
+    /// `Aborted` si le client ne lit plus.
+    pub fn flush(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        self.outgoing.flush(stream)?;
+        if self.outgoing.len() > MAX_PENDING {
+            return Ok(CgiState::Aborted);
+        }
+        match self.closing && self.outgoing.is_empty() {
+            true => Ok(CgiState::Done),
+            false => Ok(CgiState::Running),
+        }
+    }
+
+    /// Regroupe `IN_MOVED_FROM` et `IN_MOVED_TO` de même cookie en un renommage ; les
+    /// moitiés seules sont un départ ou une arrivée dans le dossier.
+    pub fn translate(&self, raw: Vec<InotifyEvent>) -> Vec<DirectoryEvent> {
+        let mut events = vec![];
+        let mut moved_from: Vec<InotifyEvent> = vec![];
+        for event in raw {
+            let is_directory = event.mask & libc::IN_ISDIR != 0;
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                events.push(DirectoryEvent::Overflow);
+            } else if event.mask & libc::IN_MOVED_FROM != 0 {
+                moved_from.push(event);
+            } else if event.mask & libc::IN_MOVED_TO != 0 {
+                let from = moved_from
+                    .iter()
+                    .position(|from| from.cookie == event.cookie)
+                    .map(|i| moved_from.remove(i));
+                let Some(from) = from else {
+                    events.extend(self.entry_event(&event.name, true, is_directory));
+                    continue;
+                };
+                // Un nom exclu qui devient visible est une création, et inversement
+                match (self.visible(&from.name, is_directory), self.visible(&event.name, is_directory)) {
+                    (true, true) => events.push(DirectoryEvent::Rename {
+                        link: self.link(&event.name),
+                        from: from.name,
+                        to: event.name,
+                        is_directory,
+                    }),
+                    (false, true) => events.extend(self.entry_event(&event.name, true, is_directory)),
+                    (true, false) => events.extend(self.entry_event(&from.name, false, is_directory)),
+                    (false, false) => (),
+                }
+            } else if event.mask & libc::IN_CREATE != 0 {
+                events.extend(self.entry_event(&event.name, true, is_directory));
+            } else if event.mask & libc::IN_DELETE != 0 {
+                events.extend(self.entry_event(&event.name, false, is_directory));
+            }
+        }
+        for event in moved_from {
+            events.extend(self.entry_event(&event.name, false, event.mask & libc::IN_ISDIR != 0));
+        }
+        events
+    }
+
+    fn entry_event(&self, name: &str, created: bool, is_directory: bool) -> Option<DirectoryEvent> {
+        if !self.visible(name, is_directory) {
+            return None;
+        }
+        let (entry, link) = (name.to_string(), self.link(name));
+        Some(match created {
+            true => DirectoryEvent::Create { entry, link, is_directory },
+            false => DirectoryEvent::Delete { entry, link, is_directory },
+        })
+    }
+
+    /// Mêmes règles que la liste : exclusions du serveur, testées comme elle sur
+    /// `/<nom>`, et dossiers seulement si `directory_listing` est actif.
+    fn visible(&self, name: &str, is_directory: bool) -> bool {
+        if name.is_empty() || (is_directory && !self.job.server.directory_listing) {
+            return false;
+        }
+        !self.exclusion.as_ref().is_some_and(|set| set.is_match(&format!("/{}", name)))
+    }
+
+    fn link(&self, name: &str) -> String {
+        format!("{}/{}", self.job.request.path().trim_end_matches('/'), name)
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(mask: u32, cookie: u32, name: &str) -> Vec<u8> {
+        // Nom complété par des octets nuls, comme le fait le noyau
+        let len = (name.len() / 16 + 1) * 16;
+        let mut bytes = vec![];
+        for field in [1, mask, cookie, len as u32] {
+            bytes.extend_from_slice(&field.to_ne_bytes());
+        }
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.resize(INOTIFY_HEADER + len, 0);
+        bytes
+    }
+
+    fn watch(directory: &str, exclusion: Vec<String>) -> DirectoryWatch {
+        let server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            String::new(),
+            String::new(),
+            5000,
+            vec!["GET".to_string()],
+            true,
+            vec![],
+            exclusion
+        );
+        let mut request = Request::default();
+        request.location = "/docs/?events".to_string();
+        DirectoryWatch::start(&DirectoryEventsJob::new(&server, &request, "", directory.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_inotify_events() {
+        let mut buffer = raw(libc::IN_CREATE, 0, "a.txt");
+        buffer.extend(raw(libc::IN_DELETE | libc::IN_ISDIR, 0, "photos"));
+        let complete = buffer.len();
+        buffer.extend_from_slice(&raw(libc::IN_CREATE, 0, "partiel")[..20]);
+
+        let (events, size) = InotifyEvent::parse_all(&buffer);
+        assert_eq!(size, complete);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], InotifyEvent { mask: libc::IN_CREATE, cookie: 0, name: "a.txt".to_string() });
+        assert_eq!(events[1].name, "photos");
+    }
+
+    #[test]
+    fn test_translate_rename_and_exclusion() {
+        let watch = watch(".", vec![r"\.tmp$".to_string(), "^/brouillon".to_string()]);
+        let events = watch.translate(vec![
+            InotifyEvent { mask: libc::IN_MOVED_FROM, cookie: 7, name: "a.txt".to_string() },
+            InotifyEvent { mask: libc::IN_MOVED_TO, cookie: 7, name: "b.txt".to_string() },
+            InotifyEvent { mask: libc::IN_CREATE, cookie: 0, name: "upload.tmp".to_string() },
+            InotifyEvent { mask: libc::IN_CREATE, cookie: 0, name: "brouillon.txt".to_string() },
+            InotifyEvent { mask: libc::IN_MOVED_FROM, cookie: 9, name: "parti.txt".to_string() },
+        ]);
+        assert_eq!(events, vec![
+            DirectoryEvent::Rename {
+                from: "a.txt".to_string(),
+                to: "b.txt".to_string(),
+                link: "/docs/b.txt".to_string(),
+                is_directory: false,
+            },
+            DirectoryEvent::Delete {
+                entry: "parti.txt".to_string(),
+                link: "/docs/parti.txt".to_string(),
+                is_directory: false,
+            },
+        ]);
+        assert_eq!(
+            events[0].to_sse(),
+            "event: rename\ndata: {\"from\":\"a.txt\",\"is_directory\":false,\"link\":\"/docs/b.txt\",\"to\":\"b.txt\",\"type\":\"rename\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_watch_directory() {
+        let dir = std::env::temp_dir().join(format!("events_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut watch = watch(&dir.to_string_lossy(), vec![]);
+        std::fs::write(dir.join("nouveau.txt"), "x").unwrap();
+        std::fs::rename(dir.join("nouveau.txt"), dir.join("renomme.txt")).unwrap();
+        std::fs::remove_file(dir.join("renomme.txt")).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+
+        drain(&mut watch.inotify, &mut watch.incoming).unwrap();
+        let (raw, _) = InotifyEvent::parse_all(&watch.incoming);
+        let names = watch
+            .translate(raw)
+            .iter()
+            .map(|event| event.name())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["create", "rename", "delete"]);
+    }
+}
@@ -0,0 +1,608 @@
+use mio::event::Source;
+use mio::net::{ TcpStream, UnixStream };
+use mio::{ Interest, Registry, Token };
+use std::io::{ self, Read, Write };
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use super::{ drain_limit, CgiJob, CgiReply, CgiState, CGI };
+use crate::Config;
+
+// -------------------------------------------------------------------------------------
+// FASTCGI
+// -------------------------------------------------------------------------------------
+const FCGI_VERSION_1: u8 = 1;
+pub const FCGI_BEGIN_REQUEST: u8 = 1;
+pub const FCGI_END_REQUEST: u8 = 3;
+pub const FCGI_PARAMS: u8 = 4;
+pub const FCGI_STDIN: u8 = 5;
+pub const FCGI_STDOUT: u8 = 6;
+pub const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_KEEP_CONN: u8 = 1;
+const FCGI_REQUEST_COMPLETE: u8 = 0;
+/// Taille maximale du contenu d'un enregistrement.
+const MAX_CONTENT: usize = 65535;
+/// Une seule requête à la fois par connexion : l'identifiant est toujours 1.
+pub const REQUEST_ID: u16 = 1;
+/// Connexions inactives conservées par backend.
+pub const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Enregistrement du protocole FastCGI (en-tête de 8 octets, contenu, remplissage).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub kind: u8,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+impl Record {
+    pub fn encode(kind: u8, request_id: u16, content: &[u8]) -> Vec<u8> {
+        let padding = (8 - (content.len() % 8)) % 8;
+        let mut bytes = Vec::with_capacity(8 + content.len() + padding);
+        bytes.push(FCGI_VERSION_1);
+        bytes.push(kind);
+        bytes.extend_from_slice(&request_id.to_be_bytes());
+        bytes.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        bytes.push(padding as u8);
+        bytes.push(0);
+        bytes.extend_from_slice(content);
+        bytes.extend(std::iter::repeat_n(0, padding));
+        bytes
+    }
+
+    /// Découpe un flux en enregistrements de 64 Ko au plus, terminé par un
+    /// enregistrement vide.
+    pub fn encode_stream(kind: u8, request_id: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for chunk in data.chunks(MAX_CONTENT) {
+            bytes.extend(Self::encode(kind, request_id, chunk));
+        }
+        bytes.extend(Self::encode(kind, request_id, &[]));
+        bytes
+    }
+
+    /// Lit un enregistrement complet en tête du tampon et renvoie sa taille totale.
+    pub fn parse(buffer: &[u8]) -> Option<(Record, usize)> {
+        if buffer.len() < 8 {
+            return None;
+        }
+        let content_length = u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+        let total = 8 + content_length + (buffer[6] as usize);
+        if buffer.len() < total {
+            return None;
+        }
+        let record = Record {
+            kind: buffer[1],
+            request_id: u16::from_be_bytes([buffer[2], buffer[3]]),
+            content: buffer[8..8 + content_length].to_vec(),
+        };
+        Some((record, total))
+    }
+}
+
+pub struct FastCGI;
+
+impl FastCGI {
+    /// Requête complète : BEGIN_REQUEST, variables CGI en PARAMS, corps en STDIN.
+    pub fn encode_request(env: &[(String, String)], body: &[u8], keep_conn: bool) -> Vec<u8> {
+        let mut begin = FCGI_RESPONDER.to_be_bytes().to_vec();
+        begin.push(if keep_conn { FCGI_KEEP_CONN } else { 0 });
+        begin.extend_from_slice(&[0; 5]);
+
+        let mut bytes = Record::encode(FCGI_BEGIN_REQUEST, REQUEST_ID, &begin);
+        bytes.extend(Record::encode_stream(FCGI_PARAMS, REQUEST_ID, &Self::encode_params(env)));
+        bytes.extend(Record::encode_stream(FCGI_STDIN, REQUEST_ID, body));
+        bytes
+    }
+
+    /// Paires nom-valeur : longueurs sur 1 octet, ou 4 octets avec le bit de poids fort.
+    pub fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for (name, value) in params {
+            for len in [name.len(), value.len()] {
+                if len < 128 {
+                    bytes.push(len as u8);
+                } else {
+                    bytes.extend_from_slice(&((len as u32) | 0x8000_0000).to_be_bytes());
+                }
+            }
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode_params(mut data: &[u8]) -> Vec<(String, String)> {
+        fn length(data: &mut &[u8]) -> Option<usize> {
+            let first = *data.first()?;
+            if first < 128 {
+                *data = &data[1..];
+                return Some(first as usize);
+            }
+            let bytes = data.get(..4)?;
+            let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff;
+            *data = &data[4..];
+            Some(len as usize)
+        }
+
+        let mut params = vec![];
+        while let (Some(name_len), Some(value_len)) = (length(&mut data), length(&mut data)) {
+            if data.len() < name_len + value_len {
+                break;
+            }
+            let name = String::from_utf8_lossy(&data[..name_len]).to_string();
+            let value = String::from_utf8_lossy(&data[name_len..name_len + value_len]).to_string();
+            params.push((name, value));
+            data = &data[name_len + value_len..];
+        }
+        params
+    }
+
+    /// Vérifie une adresse `fastcgi_pass` : `unix:/chemin` ou `hôte:port`.
+    pub fn check_address(address: &str) -> Result<(), String> {
+        match address.strip_prefix("unix:") {
+            Some("") => Err(format!("fastcgi_pass sans chemin de socket : {}", address)),
+            Some(_) => Ok(()),
+            None => {
+                let (_, port) = address
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("fastcgi_pass sans port : {}", address))?;
+                port.parse::<u16>()
+                    .map(|_| ())
+                    .map_err(|_| format!("port fastcgi_pass invalide : {}", address))
+            }
+        }
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// FASTCGI CONNECTION
+// -------------------------------------------------------------------------------------
+/// Connexion vers un backend FastCGI (socket Unix ou TCP).
+#[derive(Debug)]
+pub enum FastCgiStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl FastCgiStream {
+    /// Connexion non bloquante : elle peut encore être en cours au retour.
+    pub fn connect(address: &str) -> io::Result<Self> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Ok(Self::Unix(UnixStream::connect(PathBuf::from(path))?));
+        }
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "adresse FastCGI introuvable"))?;
+        Ok(Self::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// `Ok(false)` tant que la connexion TCP est en cours d'établissement.
+    pub fn is_connected(&self) -> io::Result<bool> {
+        let (error, peer) = match self {
+            Self::Unix(stream) => (stream.take_error()?, stream.peer_addr().map(|_| ())),
+            Self::Tcp(stream) => (stream.take_error()?, stream.peer_addr().map(|_| ())),
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        match peer {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Une connexion inactive est réutilisable si le backend ne l'a pas fermée
+    /// et n'a rien envoyé depuis la dernière requête.
+    pub fn is_reusable(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        matches!(self.read(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl Read for FastCgiStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for FastCgiStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for FastCgiStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.register(registry, token, interests),
+            Self::Tcp(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest
+    ) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.reregister(registry, token, interests),
+            Self::Tcp(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.deregister(registry),
+            Self::Tcp(stream) => stream.deregister(registry),
+        }
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// FASTCGI REQUEST
+// -------------------------------------------------------------------------------------
+/// Requête en cours vers un backend FastCGI, pilotée par la boucle d'événements.
+#[derive(Debug)]
+pub struct FastCgiRequest {
+    /// Token du client qui attend la réponse.
+    pub client: Token,
+    pub job: CgiJob,
+    /// Adresse `fastcgi_pass`, clé du pool de connexions.
+    pub address: String,
+    connection: FastCgiStream,
+    connected: bool,
+    outgoing: Vec<u8>,
+    written: usize,
+    incoming: Vec<u8>,
+    pub reply: CgiReply,
+    stderr_output: Vec<u8>,
+    /// `END_REQUEST` reçu : la connexion peut retourner dans le pool.
+    ended: bool,
+    pub deadline: Instant,
+}
+
+impl FastCgiRequest {
+    /// Prépare la requête sur une connexion du pool ou une nouvelle connexion.
+    pub fn start(job: &CgiJob, address: &str, idle: Option<FastCgiStream>) -> io::Result<Self> {
+        let (connection, connected) = match idle {
+            Some(connection) => (connection, true),
+            None => (FastCgiStream::connect(address)?, false),
+        };
+        let env = CGI::build_env(&job.server, &job.request, &job.script);
+
+        Ok(Self {
+            client: Token(0),
+            job: job.clone(),
+            address: address.to_string(),
+            connection,
+            connected,
+            outgoing: FastCGI::encode_request(&env, job.request.body_bytes(), true),
+            written: 0,
+            incoming: vec![],
+            reply: CgiReply::default(),
+            stderr_output: vec![],
+            ended: false,
+            deadline: job.deadline(),
+        })
+    }
+
+    pub fn register(&mut self, registry: &Registry, client: Token, token: Token) -> io::Result<()> {
+        self.client = client;
+        registry.register(&mut self.connection, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    /// Envoie la requête et transmet la réponse au client. Une erreur du backend donne
+    /// un 502 ; une erreur d'écriture vers le client est renvoyée telle quelle.
+    pub fn on_event(&mut self, stream: &mut TcpStream, config: &Config) -> io::Result<CgiState> {
+        if self.reply.is_complete() {
+            return self.reply.flush(stream);
+        }
+        loop {
+            // Vider d'abord la file libère de la place pour la suite de la réponse
+            let state = self.reply.flush(stream)?;
+            match self.exchange(config) {
+                // La connexion ne signalera plus ce qui reste à lire : on continue tant
+                // que le client suit, sinon son prochain WRITABLE relancera la lecture
+                Ok(0) => return Ok(state),
+                Ok(_) => (),
+                Err(BackendError::Client(e)) => return Err(e),
+                Err(BackendError::Backend(message)) => {
+                    return match self.reply.fail(&self.job, config, &message) {
+                        CgiState::Aborted => Ok(CgiState::Aborted),
+                        _ => self.reply.flush(stream),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Écrit la requête et lit la réponse du backend ; renvoie le nombre d'octets lus.
+    fn exchange(&mut self, config: &Config) -> Result<usize, BackendError> {
+        if !self.connected {
+            self.connected = self.connection
+                .is_connected()
+                .map_err(|e| BackendError::backend("connexion au backend FastCGI impossible", e))?;
+            if !self.connected {
+                return Ok(0);
+            }
+        }
+
+        while self.written < self.outgoing.len() {
+            match self.connection.write(&self.outgoing[self.written..]) {
+                Ok(n) => {
+                    self.written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Err(BackendError::backend("écriture vers le backend FastCGI", e));
+                }
+            }
+        }
+
+        // Le backend n'est plus lu tant que le client n'a pas reçu la sortie en attente
+        let room = self.reply.room();
+        if room == 0 {
+            return Ok(0);
+        }
+        let start = self.incoming.len();
+        let eof = drain_limit(&mut self.connection, &mut self.incoming, room).map_err(|e|
+            BackendError::backend("lecture depuis le backend FastCGI", e)
+        )?;
+        let read = self.incoming.len() - start;
+
+        while let Some((record, size)) = Record::parse(&self.incoming) {
+            self.incoming.drain(..size);
+            if record.request_id != REQUEST_ID {
+                continue;
+            }
+            match record.kind {
+                FCGI_STDOUT => {
+                    self.reply.push(&self.job, &record.content).map_err(BackendError::Backend)?;
+                }
+                FCGI_STDERR => self.stderr_output.extend_from_slice(&record.content),
+                FCGI_END_REQUEST => {
+                    return self.end_request(&record.content, config).map(|_| read);
+                }
+                _ => (),
+            }
+        }
+
+        if eof {
+            return Err(
+                BackendError::Backend(
+                    "connexion FastCGI fermée avant la fin de la requête".to_string()
+                )
+            );
+        }
+        Ok(read)
+    }
+
+    fn end_request(&mut self, content: &[u8], config: &Config) -> Result<(), BackendError> {
+        self.job.log_stderr(config, &self.stderr_output);
+        self.stderr_output.clear();
+        // Tout ce qui suit END_REQUEST rendrait la connexion inutilisable
+        self.ended = self.incoming.is_empty();
+
+        if content.len() < 5 {
+            return Err(BackendError::Backend("END_REQUEST FastCGI tronqué".to_string()));
+        }
+        let app_status = u32::from_be_bytes([content[0], content[1], content[2], content[3]]);
+        let protocol_status = content[4];
+        if protocol_status != FCGI_REQUEST_COMPLETE {
+            return Err(
+                BackendError::Backend(
+                    format!("requête refusée par le backend FastCGI (statut {})", protocol_status)
+                )
+            );
+        }
+        if app_status != 0 {
+            return Err(
+                BackendError::Backend(format!("le backend FastCGI a renvoyé {}", app_status))
+            );
+        }
+        self.reply.finish(&self.job, config).map_err(BackendError::Backend)
+    }
+
+    /// Rend la connexion si elle peut servir à une autre requête.
+    pub fn into_idle(mut self, registry: &Registry) -> Option<FastCgiStream> {
+        let _ = registry.deregister(&mut self.connection);
+        match self.ended && self.written == self.outgoing.len() {
+            true => Some(self.connection),
+            false => None,
+        }
+    }
+}
+
+/// Distingue une panne du backend (502) d'une erreur d'écriture vers le client.
+enum BackendError {
+    Backend(String),
+    Client(io::Error),
+}
+
+impl BackendError {
+    fn backend(context: &str, e: io::Error) -> Self {
+        Self::Backend(format!("{} : {}", context, e))
+    }
+}
+
+impl From<io::Error> for BackendError {
+    fn from(e: io::Error) -> Self {
+        Self::Client(e)
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() {
+        let bytes = Record::encode(FCGI_STDOUT, REQUEST_ID, b"hello");
+        assert_eq!(bytes.len() % 8, 0);
+        let (record, size) = Record::parse(&bytes).unwrap();
+        assert_eq!(size, bytes.len());
+        assert_eq!(record, Record { kind: FCGI_STDOUT, request_id: 1, content: b"hello".to_vec() });
+        // Enregistrement incomplet
+        assert!(Record::parse(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let long = "x".repeat(300);
+        let params = vec![
+            ("SCRIPT_NAME".to_string(), "/index.php".to_string()),
+            ("HTTP_COOKIE".to_string(), long.clone()),
+            ("EMPTY".to_string(), String::new()),
+        ];
+        let encoded = FastCGI::encode_params(&params);
+        // 300 > 127 : longueur sur 4 octets
+        assert_eq!(encoded[23..28], [11, 0x80, 0, 1, 44]);
+        assert_eq!(FastCGI::decode_params(&encoded), params);
+    }
+
+    #[test]
+    fn test_large_stream_is_split() {
+        let body = vec![b'a'; MAX_CONTENT + 10];
+        let bytes = Record::encode_stream(FCGI_STDIN, REQUEST_ID, &body);
+        let mut rest = &bytes[..];
+        let mut sizes = vec![];
+        while let Some((record, size)) = Record::parse(rest) {
+            sizes.push(record.content.len());
+            rest = &rest[size..];
+        }
+        assert_eq!(sizes, vec![MAX_CONTENT, 10, 0]);
+    }
+
+    #[test]
+    fn test_check_address() {
+        assert!(FastCGI::check_address("unix:/run/php-fpm.sock").is_ok());
+        assert!(FastCGI::check_address("127.0.0.1:9000").is_ok());
+        assert!(FastCGI::check_address("unix:").is_err());
+        assert!(FastCGI::check_address("127.0.0.1").is_err());
+        assert!(FastCGI::check_address("localhost:php").is_err());
+    }
+
+    /// Répondeur FastCGI minimal : renvoie SCRIPT_NAME suivi du corps reçu.
+    fn responder(listener: std::os::unix::net::UnixListener) {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut incoming = vec![];
+        let (mut params, mut stdin) = (vec![], vec![]);
+        let mut buffer = [0; 1024];
+        loop {
+            let n = socket.read(&mut buffer).unwrap();
+            incoming.extend_from_slice(&buffer[..n]);
+            let mut done = false;
+            while let Some((record, size)) = Record::parse(&incoming) {
+                incoming.drain(..size);
+                match record.kind {
+                    FCGI_PARAMS => params.extend(record.content),
+                    FCGI_STDIN if record.content.is_empty() => done = true,
+                    FCGI_STDIN => stdin.extend(record.content),
+                    _ => (),
+                }
+            }
+            if done || n == 0 {
+                break;
+            }
+        }
+        let params = FastCGI::decode_params(&params);
+        let script_name = params
+            .iter()
+            .find(|(name, _)| name == "SCRIPT_NAME")
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+
+        let mut output = format!("Content-Type: text/plain\r\n\r\n{}:", script_name).into_bytes();
+        output.extend(stdin);
+        let mut reply = Record::encode_stream(FCGI_STDOUT, REQUEST_ID, &output);
+        reply.extend(Record::encode(FCGI_STDERR, REQUEST_ID, b"avertissement"));
+        reply.extend(Record::encode(FCGI_END_REQUEST, REQUEST_ID, &[0; 8]));
+        socket.write_all(&reply).unwrap();
+    }
+
+    #[test]
+    fn test_request_with_responder() {
+        let path = std::env::temp_dir().join(format!("fcgi_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let backend = std::thread::spawn(move || responder(listener));
+
+        // Client HTTP : une paire de sockets TCP locale
+        let client_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let mut stream = TcpStream::from_std(client_listener.accept().unwrap().0);
+
+        let server = crate::Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            String::new(),
+            String::new(),
+            5000,
+            vec!["POST".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        let mut request = crate::Request::default();
+        request.method = "POST".to_string();
+        request.body_byte = b"POST /app HTTP/1.1\r\n\r\ncorps".to_vec();
+        let address = format!("unix:{}", path.display());
+        let script = crate::CgiScript {
+            filename: "/srv/app.php".to_string(),
+            script_name: "/app".to_string(),
+            path_info: String::new(),
+            runner: crate::CgiRunner::FastCgi(address.clone()),
+        };
+        let config = Config::new();
+        let job = CgiJob::new(&server, &request, String::new(), script, &config);
+
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut fastcgi = FastCgiRequest::start(&job, &address, None).unwrap();
+        fastcgi.register(poll.registry(), Token(1), Token(2)).unwrap();
+        let mut state = CgiState::Running;
+        while state == CgiState::Running {
+            poll.poll(&mut events, Some(std::time::Duration::from_secs(5))).unwrap();
+            assert!(!events.is_empty(), "le backend FastCGI ne répond pas");
+            state = fastcgi.on_event(&mut stream, &config).unwrap();
+        }
+        backend.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state, CgiState::Done);
+        assert!(fastcgi.into_idle(poll.registry()).is_some());
+
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\na\r\n/app:corps\r\n0\r\n\r\n"));
+    }
+}
@@ -0,0 +1,172 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{ Htpasswd, Request, Response, Server, Session, SessionCookie };
+
+// -------------------------------------------------------------------------------------
+// FORM LOGIN
+// -------------------------------------------------------------------------------------
+/// Connexion par formulaire d'un serveur ; l'utilisateur est conservé dans la session.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FormLogin {
+    /// Fichier htpasswd des comptes autorisés.
+    pub user_file: String,
+    #[serde(default = "FormLogin::default_login_path")]
+    pub login_path: String,
+    #[serde(default = "FormLogin::default_logout_path")]
+    pub logout_path: String,
+    /// Template Tera du formulaire, sous `src/`.
+    #[serde(default = "FormLogin::default_template")]
+    pub template: String,
+}
+
+/// Champs envoyés par le formulaire de connexion.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    pub next: String,
+}
+
+/// Formulaire de connexion à afficher : retour après connexion et message d'échec.
+pub struct LoginPage<'a> {
+    pub login: &'a FormLogin,
+    pub next: &'a str,
+    pub error: Option<&'a str>,
+}
+
+impl FormLogin {
+    fn default_login_path() -> String {
+        "/login".to_string()
+    }
+
+    fn default_logout_path() -> String {
+        "/logout".to_string()
+    }
+
+    fn default_template() -> String {
+        "src/static_files/login.html".to_string()
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if let Err(e) = std::fs::metadata(&self.user_file) {
+            errors.push(format!("user_file {} illisible : {}", self.user_file, e));
+        }
+        for path in [&self.login_path, &self.logout_path] {
+            if !path.starts_with('/') || path.contains('?') {
+                errors.push(format!("chemin invalide : {}", path));
+            }
+        }
+        if self.login_path == self.logout_path {
+            errors.push("login_path et logout_path doivent être différents".to_string());
+        }
+        if !self.template.starts_with("src/") || !Path::new(&self.template).is_file() {
+            errors.push(format!("template introuvable sous src/ : {}", self.template));
+        }
+        errors
+    }
+
+    /// Page de connexion qui renverra ensuite vers `next`.
+    pub fn login_url(&self, next: &str) -> String {
+        format!("{}?next={}", self.login_path, urlencoding::encode(next))
+    }
+
+    /// Vérifie le mot de passe contre le fichier htpasswd.
+    pub fn authenticate(&self, form: &LoginForm) -> bool {
+        match Htpasswd::find(&self.user_file, &form.username) {
+            Ok(Some(hash)) => Htpasswd::verify(&form.password, &hash),
+            _ => false,
+        }
+    }
+
+    /// Cible de retour après connexion : un chemin local uniquement, pour ne pas
+    /// servir de redirection ouverte vers un autre site. Les navigateurs ignorent les
+    /// tabulations et retours à la ligne (`/\t/evil.com` devient `//evil.com`) : tout
+    /// caractère de contrôle ou blanc est refusé.
+    pub fn safe_next(next: &str) -> String {
+        let local = next.starts_with('/') && !next.starts_with("//") && !next.contains('\\');
+        match local && !next.chars().any(|c| c.is_control() || c.is_whitespace()) {
+            true => next.to_string(),
+            false => "/".to_string(),
+        }
+    }
+}
+
+impl LoginForm {
+    /// Lit un corps `application/x-www-form-urlencoded` (ou une query string).
+    pub fn parse(body: &str) -> Self {
+        let fields = body
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| {
+                let value = value.replace('+', " ");
+                let value = urlencoding::decode(&value).map_or(value.clone(), |v| v.into_owned());
+                (name.to_string(), value)
+            })
+            .collect::<HashMap<String, String>>();
+        Self {
+            username: fields.get("username").cloned().unwrap_or_default(),
+            password: fields.get("password").cloned().unwrap_or_default(),
+            next: FormLogin::safe_next(fields.get("next").map(String::as_str).unwrap_or("/")),
+        }
+    }
+}
+
+/// Connexion ou déconnexion réussie : le Router met à jour la session, change son
+/// identifiant puis redirige le client.
+#[derive(Debug)]
+pub struct LoginJob {
+    pub server: Server,
+    pub request: Request,
+    pub settings: SessionCookie,
+    /// Utilisateur connecté, `None` pour une déconnexion.
+    pub user: Option<String>,
+    pub target: String,
+}
+
+impl LoginJob {
+    /// Redirection 303 qui transmet le nouvel identifiant de session.
+    pub fn response(&self, session: &Session) -> Response {
+        let mut response = Response::with_code(303, "", vec![]);
+        response.id_session = self.settings.header(session);
+        response.add_header("Location", &self.target);
+        response
+    }
+}
+
+// -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_form() {
+        let form = LoginForm::parse("username=alice&password=p%40ss+word&next=%2Fd%2Fg%3Fa%3D1");
+        assert_eq!(
+            form,
+            LoginForm {
+                username: "alice".to_string(),
+                password: "p@ss word".to_string(),
+                next: "/d/g?a=1".to_string(),
+            }
+        );
+        assert_eq!(LoginForm::parse("next=https%3A%2F%2Fevil.example").next, "/");
+        assert_eq!(LoginForm::parse("next=%2F%2Fevil.example").next, "/");
+        assert_eq!(LoginForm::parse("").next, "/");
+        assert_eq!(LoginForm::parse("next=%2F%09%2Fevil.example").next, "/");
+        assert_eq!(LoginForm::parse("next=%2F%0A%2Fevil.example").next, "/");
+        assert_eq!(LoginForm::parse("next=%2Fd%20e").next, "/");
+        assert_eq!(FormLogin::safe_next("/d/\u{7f}"), "/");
+
+        let login = FormLogin {
+            user_file: String::new(),
+            login_path: FormLogin::default_login_path(),
+            logout_path: FormLogin::default_logout_path(),
+            template: FormLogin::default_template(),
+        };
+        assert_eq!(login.login_url("/d/g?a=1"), "/login?next=%2Fd%2Fg%3Fa%3D1");
+        assert!(!login.authenticate(&form));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// -------------------------------------------------------------------------------------
+// MIME TYPES
+// -------------------------------------------------------------------------------------
+/// Type renvoyé quand aucune extension ne correspond.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Table intégrée, utilisée comme base avant le fichier `mime.types` et les surcharges.
+const BUILTIN_MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("avif", "image/avif"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("rb", "application/x-ruby"),
+    ("py", "text/x-python"),
+    ("pl", "text/x-perl"),
+    ("sh", "application/x-sh"),
+];
+
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl MimeTypes {
+    /// Crée un registre contenant uniquement la table intégrée.
+    pub fn builtin() -> Self {
+        let mut mime_types = Self { types: HashMap::new() };
+        for (ext, mime) in BUILTIN_MIME_TYPES {
+            mime_types.insert(ext, mime);
+        }
+        mime_types
+    }
+
+    /// Ajoute (ou remplace) le type associé à une extension (`"svg"` ou `".svg"`).
+    pub fn insert(&mut self, ext: &str, mime: &str) {
+        self.types.insert(Self::normalize_extension(ext), mime.trim().to_string());
+    }
+
+    /// Ajoute toutes les entrées d'une table `extension -> type`.
+    pub fn extend(&mut self, overrides: &HashMap<String, String>) {
+        for (ext, mime) in overrides {
+            self.insert(ext, mime);
+        }
+    }
+
+    /// Charge un fichier au format `/etc/mime.types` (`type ext1 ext2 ...`).
+    pub fn load_file(&mut self, path: &str) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        self.load_str(&content);
+        Ok(())
+    }
+
+    /// Les types `text/*` du fichier, sans paramètres, gardent ceux de l'entrée qu'ils
+    /// remplacent (`text/html` reste `text/html; charset=utf-8`).
+    pub fn load_str(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut parts = line.split_whitespace();
+            if let Some(mime) = parts.next() {
+                for ext in parts {
+                    let params = self
+                        .get(ext)
+                        .filter(|_| mime.starts_with("text/") && !mime.contains(';'))
+                        .and_then(|current| current.split_once(';'))
+                        .map(|(_, params)| params.trim().to_string());
+                    match params {
+                        Some(params) => self.insert(ext, &format!("{}; {}", mime, params)),
+                        None => self.insert(ext, mime),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, ext: &str) -> Option<&str> {
+        self.types.get(&Self::normalize_extension(ext)).map(|m| m.as_str())
+    }
+
+    /// Renvoie le type d'un chemin d'après son extension.
+    pub fn from_path(&self, path: &Path) -> &str {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.get(ext))
+            .unwrap_or(DEFAULT_MIME_TYPE)
+    }
+
+    /// Catégorie utilisée par la page de listing pour choisir l'icône.
+    pub fn entry_type(mime: &str) -> &'static str {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        match essence {
+            "application/x-ruby" => "ruby",
+            "application/pdf" => "pdf",
+            _ if essence.starts_with("image/") => "image",
+            _ if essence.starts_with("text/") => "text",
+            _ => "file",
+        }
+    }
+
+    fn normalize_extension(ext: &str) -> String {
+        ext.trim().trim_start_matches('.').to_lowercase()
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_types() {
+        let mime_types = MimeTypes::builtin();
+        assert_eq!(mime_types.from_path(Path::new("logo.SVG")), "image/svg+xml");
+        assert_eq!(mime_types.from_path(Path::new("app.wasm")), "application/wasm");
+        assert_eq!(mime_types.from_path(Path::new("inconnu.xyz")), DEFAULT_MIME_TYPE);
+        assert_eq!(mime_types.from_path(Path::new("sans_extension")), DEFAULT_MIME_TYPE);
+    }
+
+    #[test]
+    fn test_load_mime_types_format() {
+        let mut mime_types = MimeTypes::builtin();
+        mime_types.load_str(
+            "# commentaire\n\
+             application/vnd.test\ttst  tst2\n\
+             text/x-log log # fin de ligne\n\
+             text/html html htm\n\
+             text/x-csv csv\n\
+             application/javascript js\n\
+             application/empty\n"
+        );
+        assert_eq!(mime_types.get("html"), Some("text/html; charset=utf-8"));
+        assert_eq!(mime_types.get("csv"), Some("text/x-csv; charset=utf-8"));
+        assert_eq!(mime_types.get("js"), Some("application/javascript"));
+        assert_eq!(mime_types.get(".tst2"), Some("application/vnd.test"));
+        assert_eq!(mime_types.get("log"), Some("text/x-log"));
+        assert_eq!(mime_types.get("png"), Some("image/png"));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut mime_types = MimeTypes::builtin();
+        let mut overrides = HashMap::new();
+        overrides.insert(".log".to_string(), "text/plain; charset=utf-8".to_string());
+        overrides.insert("PNG".to_string(), "image/x-png".to_string());
+        mime_types.extend(&overrides);
+        assert_eq!(mime_types.from_path(Path::new("server.log")), "text/plain; charset=utf-8");
+        assert_eq!(mime_types.from_path(Path::new("a.png")), "image/x-png");
+    }
+
+    #[test]
+    fn test_entry_type() {
+        assert_eq!(MimeTypes::entry_type("image/svg+xml"), "image");
+        assert_eq!(MimeTypes::entry_type("text/plain; charset=utf-8"), "text");
+        assert_eq!(MimeTypes::entry_type("application/x-ruby"), "ruby");
+        assert_eq!(MimeTypes::entry_type("application/pdf"), "pdf");
+        assert_eq!(MimeTypes::entry_type("video/mp4"), "file");
+    }
+}
@@ -18,14 +18,34 @@ pub mod router;
 pub use router::*;
 pub mod session;
 pub use session::*;
+pub mod session_store;
+pub use session_store::*;
 use tera::{ Context, Tera };
+pub mod auth;
 pub mod cgi;
+pub mod csrf;
+pub mod events;
+pub mod fastcgi;
+pub mod login;
+pub mod mime;
+pub mod proxy;
+pub mod redirection;
 pub mod rendering_page;
+pub mod websocket;
 
+pub use auth::*;
 pub use cgi::*;
+pub use csrf::*;
+pub use events::*;
+pub use fastcgi::*;
+pub use login::*;
+pub use mime::*;
+pub use proxy::*;
+pub use redirection::*;
 pub use rendering_page::*;
+pub use websocket::*;
 
-use crate::{ remove_prefix, remove_suffix, Config, Redirection };
+use crate::{ remove_prefix, remove_suffix, Config, HeaderRule, Route };
 
 #[derive(Debug)]
 pub enum ServerError<'a> {
@@ -33,6 +53,54 @@ pub enum ServerError<'a> {
     TeraError(&'a tera::Error),
     TomlError(&'a toml::de::Error),
     RegexError(&'a regex::Error),
+    LoopDetected(&'a [String]),
+    /// Erreur d'un script CGI (chemin du script, URI demandée).
+    CgiError {
+        script: &'a str,
+        uri: &'a str,
+        message: &'a str,
+    },
+    /// Erreur d'un serveur amont (adresse du serveur, URI demandée).
+    ProxyError {
+        upstream: &'a str,
+        uri: &'a str,
+        message: &'a str,
+    },
+    /// Erreur d'une connexion WebSocket (URI de la poignée de main).
+    WebSocketError {
+        uri: &'a str,
+        message: &'a str,
+    },
+    /// Identifiants HTTP Basic refusés.
+    AuthError {
+        user: &'a str,
+        message: &'a str,
+    },
+    /// Cookie de session refusé (valeur reçue).
+    SessionError {
+        cookie: &'a str,
+        message: &'a str,
+    },
+    /// Jeton JWT refusé (URI demandée).
+    JwtError {
+        uri: &'a str,
+        message: &'a str,
+    },
+    /// Modification refusée par la protection CSRF (URI demandée).
+    CsrfError {
+        uri: &'a str,
+        message: &'a str,
+    },
+}
+
+/// Requête dont la réponse est produite par la boucle d'événements du Router.
+#[derive(Debug)]
+pub enum Job {
+    Cgi(CgiJob),
+    Proxy(ProxyJob),
+    WebSocket(WebSocketJob),
+    DirectoryEvents(DirectoryEventsJob),
+    Login(LoginJob),
 }
 
 // -------------------------------------------------------------------------------------
@@ -50,7 +118,37 @@ pub struct Server {
     pub accepted_methods: Vec<String>,
     pub directory_listing: bool,
     pub redirections: Vec<Redirection>,
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>,
     pub exclusion: Vec<String>,
+    #[serde(default)]
+    pub mime_types: HashMap<String, String>,
+    #[serde(default)]
+    pub add_headers: Vec<HeaderRule>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Interpréteur CGI par extension (`".py" = "/usr/bin/python3"`).
+    #[serde(default = "CGI::default_interpreters")]
+    pub cgi: HashMap<String, String>,
+    /// Exécute directement les fichiers qui ont le bit exécutable.
+    #[serde(default)]
+    pub cgi_executables: bool,
+    /// Durée maximale d'un script en millisecondes ; au-delà il est tué (504).
+    #[serde(default = "CGI::default_timeout")]
+    pub cgi_timeout: u64,
+    #[serde(default)]
+    pub cgi_sandbox: CgiSandbox,
+    /// Cookie de session propre au serveur, à la place de celui de `[http.session]`.
+    #[serde(default)]
+    pub session: Option<SessionCookie>,
+    #[serde(default)]
+    pub auth_basic: Option<AuthBasic>,
+    /// Pages `/login` et `/logout` ; l'utilisateur connecté est gardé dans la session.
+    #[serde(default)]
+    pub login: Option<FormLogin>,
+    /// Vérification des envois, créations de dossiers et suppressions.
+    #[serde(default)]
+    pub csrf: CsrfProtection,
 }
 
 impl Server {
@@ -78,7 +176,183 @@ impl Server {
             accepted_methods,
             directory_listing,
             redirections,
+            rewrites: vec![],
             exclusion,
+            mime_types: HashMap::new(),
+            add_headers: vec![],
+            routes: vec![],
+            cgi: CGI::default_interpreters(),
+            cgi_executables: false,
+            cgi_timeout: CGI::default_timeout(),
+            cgi_sandbox: CgiSandbox::default(),
+            session: None,
+            auth_basic: None,
+            login: None,
+            csrf: CsrfProtection::default(),
+        }
+    }
+
+    /// Cookie de session du serveur, ou celui de `[http.session]`.
+    pub fn session_cookie<'a>(&'a self, config: &'a Config) -> &'a SessionCookie {
+        self.session.as_ref().unwrap_or(&config.http.session.cookie)
+    }
+
+    /// Le serveur répond-il à l'hôte et au port de la requête ?
+    pub fn serves(&self, request: &Request) -> bool {
+        (self.ip_addr == request.host || self.hostname == request.host)
+            && self.ports.contains(&request.port)
+    }
+
+    /// Vérifie la configuration du serveur et renvoie la liste des erreurs trouvées.
+    pub fn check_config(&self, config: &Config) -> Vec<String> {
+        let mut errors = vec![];
+
+        for redirection in &self.redirections {
+            if !Redirection::ALLOWED_CODES.contains(&redirection.code) {
+                errors.push(
+                    format!(
+                        "code de redirection {} invalide pour {} (attendu : 301, 302, 303, 307 ou 308)",
+                        redirection.code,
+                        redirection.source
+                    )
+                );
+            }
+        }
+
+        // Les expressions des règles sont compilées ici une fois pour toutes
+        let compiled = self.redirections
+            .iter()
+            .map(|r| (&r.source, r.compile()))
+            .chain(self.rewrites.iter().map(|r| (&r.source, r.compile())))
+            .chain(self.exclusion.iter().map(|source| (source, Regex::new(source).map(|_| ()))));
+        let mut regex_valid = true;
+        for (source, result) in compiled {
+            if let Err(e) = result {
+                errors.push(format!("expression régulière invalide {} : {}", source, e));
+                regex_valid = false;
+            }
+        }
+
+        // Inutile de chercher des cycles si une expression ne compile pas
+        if regex_valid {
+            errors.extend(
+                find_rule_cycles(
+                    &self.redirections,
+                    &self.rewrites,
+                    config.http.max_redirects,
+                    config.http.max_rewrites
+                )
+            );
+        }
+
+        if let Some(session) = &self.session {
+            errors.extend(session.check().into_iter().map(|e| format!("session : {}", e)));
+        }
+        if let Some(auth) = &self.auth_basic {
+            errors.extend(auth.check().into_iter().map(|e| format!("auth_basic : {}", e)));
+        }
+        if let Some(login) = &self.login {
+            errors.extend(login.check().into_iter().map(|e| format!("login : {}", e)));
+        }
+
+        for route in &self.routes {
+            if let Some(Err(e)) = route.fastcgi_pass.as_deref().map(FastCGI::check_address) {
+                errors.push(format!("route {} : {}", route.path, e));
+            }
+            if let Some(proxy_pass) = &route.proxy_pass {
+                if route.fastcgi_pass.is_some() {
+                    errors.push(format!("route {} : fastcgi_pass et proxy_pass sont exclusifs", route.path));
+                }
+                // Sans port, l'autorité doit être le nom d'un upstream
+                let check = ProxyPass::parse(proxy_pass).and_then(|pass| {
+                    match config.http.upstreams.contains_key(&pass.authority) {
+                        true => Ok(()),
+                        false if pass.authority.contains(':') => Proxy::check_address(&pass.authority),
+                        false => Err(format!("upstream inconnu : {}", pass.authority)),
+                    }
+                });
+                if let Err(e) = check {
+                    errors.push(format!("route {} : {}", route.path, e));
+                }
+            }
+            if let Some(Err(e)) = route.websocket.as_deref().map(WebSocketTarget::parse) {
+                errors.push(format!("route {} : {}", route.path, e));
+            }
+            if let Some(auth) = &route.auth_basic {
+                errors.extend(auth.check().into_iter().map(|e| format!("route {} : auth_basic : {}", route.path, e)));
+            }
+            if let Some(jwt) = &route.auth_jwt {
+                errors.extend(jwt.check().into_iter().map(|e| format!("route {} : auth_jwt : {}", route.path, e)));
+                if route.auth_basic.is_some() {
+                    errors.push(format!("route {} : auth_basic et auth_jwt sont exclusifs", route.path));
+                }
+            }
+            if route.require_login && self.login.is_none() {
+                errors.push(format!("route {} : require_login nécessite la section login du serveur", route.path));
+            }
+        }
+        errors
+    }
+
+    /// Méthodes de l'en-tête `Allow` : celles des pages de connexion, sinon celles du serveur.
+    pub fn allowed_methods(&self, request: &Request) -> String {
+        match &self.login {
+            Some(login) if request.path() == login.logout_path => "POST".to_string(),
+            Some(login) if request.path() == login.login_path => "GET, POST".to_string(),
+            _ => self.accepted_methods.join(", "),
+        }
+    }
+
+    /// Authentification Basic de la requête : celle de la route, sinon celle du serveur
+    /// (sauf sur une route `auth_jwt`).
+    pub fn find_auth_basic(&self, location: &str) -> Option<&AuthBasic> {
+        match self.find_route(location) {
+            Some(route) if route.auth_basic.is_some() || route.auth_jwt.is_some() => route.auth_basic.as_ref(),
+            _ => self.auth_basic.as_ref(),
+        }
+    }
+
+    /// Authentification JWT de la route de la requête.
+    pub fn find_auth_jwt(&self, location: &str) -> Option<&AuthJwt> {
+        self.find_route(location).and_then(|route| route.auth_jwt.as_ref())
+    }
+
+    /// La requête peut être traitée avant d'avoir reçu tout son corps : une route
+    /// `proxy_pass` relaie la suite au serveur amont au fil de son arrivée.
+    pub fn streams_body(&self, request: &Request) -> bool {
+        request.content_length.is_some() &&
+            Request::normalize_path(request.path())
+                .and_then(|path| self.find_route(&path))
+                .is_some_and(|route| route.proxy_pass.is_some())
+    }
+
+    /// Route dont le préfixe est le plus long parmi celles qui correspondent au chemin.
+    pub fn find_route(&self, location: &str) -> Option<&Route> {
+        let path = location.split('?').next().unwrap_or_default();
+        self.routes
+            .iter()
+            .filter(|route| {
+                let prefix = route.path.trim_end_matches('/');
+                path == prefix ||
+                    path.starts_with(&format!("{}/", prefix)) ||
+                    prefix.is_empty()
+            })
+            .max_by_key(|route| route.path.trim_end_matches('/').len())
+    }
+
+    /// Type MIME d'un fichier : surcharges du serveur puis registre global.
+    pub fn content_type(&self, path: &Path, config: &Config) -> String {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        let server_override = self.mime_types
+            .iter()
+            .find(|(key, _)| !ext.is_empty() && key.trim_start_matches('.').eq_ignore_ascii_case(ext));
+
+        match server_override {
+            Some((_, mime)) => mime.clone(),
+            None => config.mime_types.from_path(path).to_string(),
         }
     }
 
@@ -119,7 +393,9 @@ impl Server {
 
         let addr = format!("{}:{}{}", &request.host, &request.port, &request.location);
         context.insert("remote_addr", &addr);
-        context.insert("remote_user", id_session);
+        // Utilisateur authentifié, sinon identifiant de session
+        context.insert("remote_user", request.remote_user.as_deref().unwrap_or(id_session));
+        context.insert("claims", &request.claims);
         context.insert("time_local", &format!("{}", Utc::now().format("%d-%m-%Y %H:%M:%S")));
         context.insert("method", &format!("{: <6}", &request.method));
         context.insert("status", &status_code);
@@ -179,54 +455,132 @@ impl Server {
         }
     }
 
+    /// Envoie une redirection si une règle correspond au chemin.
+    /// Renvoie `true` quand une réponse a été écrite : la requête est alors terminée.
     pub fn handle_redirection(
         &self,
-        request: &mut Request,
+        request: &Request,
         stream: &mut TcpStream,
         config: &Config,
         cookie: &String
-    ) -> Result<(), std::io::Error> {
-        let mut redirects = self.redirections.clone();
-        redirects.retain(|r| r.source == request.location);
-        let mut code = 302;
-        let mut status = "Loop Detected";
-
-        if !redirects.is_empty() {
-            if self.redirections.iter().any(|r| r.target == request.location) {
-                code = 508;
+    ) -> Result<bool, std::io::Error> {
+        let chain = match Redirection::chain(
+            &self.redirections,
+            &request.location,
+            config.http.max_redirects
+        ) {
+            Ok(chain) => chain,
+            Err(RuleError::Regex(e)) => {
+                Self::error_log(
+                    request,
+                    config,
+                    "handle_redirection",
+                    file!(),
+                    line!(),
+                    ServerError::RegexError(&e)
+                );
                 Self::send_error_response(
-                    &self,
+                    self,
                     stream,
-                    &request,
+                    request,
                     config,
-                    code,
-                    status,
-                    &cookie
+                    500,
+                    "Internal Server Error",
+                    cookie
                 )?;
-            } else {
-                status = "Found";
-                request.location = redirects[0].target.clone();
-                let re = Regex::new(r"^(?<method>[A-Z]+) /(?<location>\S+)").unwrap();
-                request.head = re
-                    .replace_all(&request.head, format!("$method {}", redirects[0].target.clone()))
-                    .to_string();
-            }
-
-            // Construire la réponse de redirection
-            let response = format!(
-                "HTTP/1.1 {code} {status}\r\n\
-                Location: {}\r\n\
-                Connection: keep-alive\r\n\
-                Content-Length: 0\r\n\r\n",
-                request.location
-            );
+                return Ok(true);
+            }
+            Err(RuleError::Loop(steps) | RuleError::TooDeep(steps)) => {
+                Self::error_log(
+                    request,
+                    config,
+                    "handle_redirection",
+                    file!(),
+                    line!(),
+                    ServerError::LoopDetected(&steps)
+                );
+                Self::send_error_response(
+                    self,
+                    stream,
+                    request,
+                    config,
+                    508,
+                    "Loop Detected",
+                    cookie
+                )?;
+                return Ok(true);
+            }
+        };
 
-            // Envoyer la réponse via le TcpStream
-            stream.write_all(response.as_bytes())?;
-            stream.flush()?;
-        }
+        // Seul le premier saut est envoyé, le client suivra les suivants
+        let Some((redirection, target)) = chain.into_iter().next() else {
+            return Ok(false);
+        };
 
-        Ok(())
+        // Construire la réponse de redirection
+        let mut response = Response::with_code(redirection.status_code(), "", vec![]);
+        response.id_session = cookie.clone();
+        response.add_header("Location", &target);
+        self.send_response(stream, request, config, response)?;
+        Ok(true)
+    }
+
+    /// Applique les réécritures internes ; le client ne voit pas le changement de chemin.
+    /// Renvoie `false` si une erreur a déjà été envoyée au client.
+    pub fn handle_rewrites(
+        &self,
+        request: &mut Request,
+        stream: &mut TcpStream,
+        config: &Config,
+        cookie: &String
+    ) -> Result<bool, std::io::Error> {
+        match Rewrite::apply_all(&self.rewrites, &request.location, config.http.max_rewrites) {
+            Ok(Some(location)) => {
+                request.set_location(&location);
+                Ok(true)
+            }
+            Ok(None) => Ok(true),
+            Err(RuleError::Regex(e)) => {
+                Self::error_log(
+                    request,
+                    config,
+                    "handle_rewrites",
+                    file!(),
+                    line!(),
+                    ServerError::RegexError(&e)
+                );
+                Self::send_error_response(
+                    self,
+                    stream,
+                    request,
+                    config,
+                    500,
+                    "Internal Server Error",
+                    cookie
+                )?;
+                Ok(false)
+            }
+            Err(RuleError::Loop(steps) | RuleError::TooDeep(steps)) => {
+                Self::error_log(
+                    request,
+                    config,
+                    "handle_rewrites",
+                    file!(),
+                    line!(),
+                    ServerError::LoopDetected(&steps)
+                );
+                Self::send_error_response(
+                    self,
+                    stream,
+                    request,
+                    config,
+                    508,
+                    "Loop Detected",
+                    cookie
+                )?;
+                Ok(false)
+            }
+        }
     }
 
     pub fn handle_request(
@@ -235,8 +589,76 @@ impl Server {
         mut request: Request,
         cookie: String,
         config: &Config
-    ) -> Result<(), std::io::Error> {
-        self.handle_redirection(&mut request, stream, config, &cookie)?;
+    ) -> Result<Option<Job>, std::io::Error> {
+        // Un seul chemin normalisé pour les règles, les routes, les fichiers et les scripts
+        if !request.normalize_location() {
+            self.send_error_response(stream, &request, config, 400, "Bad Request", &cookie)?;
+            return Ok(None);
+        }
+        if self.handle_redirection(&request, stream, config, &cookie)? {
+            return Ok(None);
+        }
+        if !self.handle_rewrites(&mut request, stream, config, &cookie)? {
+            return Ok(None);
+        }
+        // Une réécriture peut produire `//` ou `..`
+        if !request.normalize_location() {
+            self.send_error_response(stream, &request, config, 400, "Bad Request", &cookie)?;
+            return Ok(None);
+        }
+
+        // Authentification Basic du serveur ou de la route
+        if let Some(auth) = self.find_auth_basic(&request.location) {
+            match auth.authenticate(&request) {
+                AuthResult::Granted(user) => request.remote_user = Some(user),
+                result => {
+                    if let AuthResult::Denied(user, message) = &result {
+                        Self::error_log(&request, config, "handle_request", file!(), line!(), ServerError::AuthError { user, message });
+                    }
+                    self.send_error_response(stream, &request, config, 401, "Unauthorized", &cookie)?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        // Jeton JWT des routes d'API
+        if let Some(jwt) = self.find_auth_jwt(&request.location) {
+            match jwt.authenticate(&request) {
+                JwtResult::Granted(claims) => {
+                    request.remote_user = claims.get("sub").cloned();
+                    request.claims = claims;
+                }
+                result => {
+                    if let JwtResult::Denied(message) = result {
+                        Self::error_log(&request, config, "handle_request", file!(), line!(), ServerError::JwtError { uri: &request.uri, message });
+                    }
+                    let mut response = self.error_response(&request, config, 401, "Unauthorized", &cookie);
+                    response.add_header("WWW-Authenticate", &jwt.challenge(&result));
+                    self.send_response(stream, &request, config, response)?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        // Connexion par formulaire : pages de connexion (GET et POST, quelles que soient les
+        // `accepted_methods`) et routes réservées
+        if let Some(login) = &self.login {
+            if request.path() == login.login_path || request.path() == login.logout_path {
+                return self.handle_login(stream, request, cookie, config, login);
+            }
+            let user = request.session.as_ref().and_then(|session| session.user.clone());
+            if request.remote_user.is_none() {
+                request.remote_user = user;
+            }
+            let required = self.find_route(&request.location).is_some_and(|route| route.require_login);
+            if required && request.remote_user.is_none() {
+                let mut response = Response::with_code(303, "", vec![]);
+                response.id_session = cookie.clone();
+                response.add_header("Location", &login.login_url(&request.uri));
+                self.send_response(stream, &request, config, response)?;
+                return Ok(None);
+            }
+        }
 
         // Vérification de la méthode
         if !self.accepted_methods.iter().any(|m| m.to_uppercase() == request.method.to_uppercase()) {
@@ -249,10 +671,10 @@ impl Server {
                 "Method Not Allowed",
                 &cookie
             )?;
-            return Ok(());
+            return Ok(None);
         }
-        // Size limit
-        if request.length > config.http.size_limit * 1024 {
+        // Size limit, sur le corps annoncé quand il n'est pas encore entièrement reçu
+        if request.length.max(request.content_length.unwrap_or_default()) > config.http.size_limit * 1024 {
             Self::send_error_response(
                 &self,
                 &mut stream,
@@ -262,7 +684,55 @@ impl Server {
                 "Content Too Large",
                 &cookie
             )?;
-            return Ok(());
+            return Ok(None);
+        }
+
+        // Route websocket : le Router termine la poignée de main puis gère la connexion
+        let route = self.find_route(request.path());
+        if let Some(route) = route.filter(|route| route.websocket.is_some() && WebSocket::is_upgrade(&request)) {
+            match WebSocket::handshake(&request) {
+                Ok(accept) => {
+                    if let Some(job) = WebSocketJob::new(self, &request, &cookie, route, accept) {
+                        return Ok(Some(Job::WebSocket(job)));
+                    }
+                }
+                Err((status_code, status_message)) => {
+                    self.send_error_response(stream, &request, config, status_code, status_message, &cookie)?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        // Route proxy_pass : le Router relaie la requête vers l'upstream
+        if let Some(job) = route.and_then(|route| ProxyJob::new(self, &request, &cookie, route)) {
+            return Ok(Some(Job::Proxy(job)));
+        }
+        // Seul le relais lit la suite du corps : une réécriture a pu quitter la route
+        if !request.complete && request.method != "GET" {
+            self.send_error_response(stream, &request, config, 400, "Bad Request", &cookie)?;
+            return Ok(None);
+        }
+
+        // Scripts CGI, quelle que soit la méthode : le Router lance et suit le processus
+        if let Some(script) = self.find_cgi_script(request.path()) {
+            return Ok(Some(Job::Cgi(CgiJob::new(self, &request, cookie, script, config))));
+        }
+
+        // Flux SSE des changements d'un dossier : `/dossier/?events`
+        if request.method == "GET" && request.query() == Some("events") {
+            let directory = format!("./{}{}", self.root_directory.trim_end_matches('/'), request.path());
+            if Path::new(&directory).is_dir() && !request.path().split('/').any(|segment| segment == "..") {
+                return Ok(Some(Job::DirectoryEvents(DirectoryEventsJob::new(self, &request, &cookie, directory))));
+            }
+        }
+
+        // Envois, créations de dossiers et suppressions : protection CSRF
+        if ["POST", "DELETE"].contains(&request.method.as_str()) {
+            if let Err(message) = self.csrf.verify(&request) {
+                Self::error_log(&request, config, "handle_request", file!(), line!(), ServerError::CsrfError { uri: &request.uri, message });
+                self.send_error_response(stream, &request, config, 403, "Forbidden", &cookie)?;
+                return Ok(None);
+            }
         }
 
         let location_path;
@@ -270,7 +740,7 @@ impl Server {
         let mut root = self.root_directory.clone();
         root = remove_suffix(root, "/");
 
-        let location = "./".to_string() + &root + &request.location;
+        let location = "./".to_string() + &root + request.path();
 
         let path_entity = Path::new(&location);
 
@@ -283,7 +753,7 @@ impl Server {
             location_path = "/index.html".to_string();
             dir_path = "src/static_files".to_string();
         } else {
-            location_path = Self::check_and_clean_path(&request.location);
+            location_path = Self::check_and_clean_path(request.path());
             dir_path = self.root_directory.clone();
         }
 
@@ -328,32 +798,10 @@ impl Server {
                                 entry: entry_name.clone(),
                                 entry_type: match el.is_dir() {
                                     true => "folder".to_string(),
-                                    _ => {
-                                        let filename_parts = entry_name
-                                            .split(".")
-                                            .collect::<Vec<&str>>();
-                                        match filename_parts.len() {
-                                            2 => {
-                                                let ext = format!("{}{}", ".", filename_parts[1]);
-                                                let mut file_formats: HashMap<
-                                                    &str,
-                                                    &str
-                                                > = HashMap::new();
-                                                file_formats.insert(".rb", "ruby");
-                                                file_formats.insert(".jpg", "image");
-                                                file_formats.insert(".jpeg", "image");
-                                                file_formats.insert(".png", "image");
-                                                file_formats.insert(".txt", "text");
-                                                file_formats.insert(".pdf", "pdf");
-
-                                                match file_formats.get(ext.as_str()) {
-                                                    Some(filetype) => filetype.to_string(),
-                                                    None => "file".to_string(),
-                                                }
-                                            }
-                                            _ => "file".to_string(),
-                                        }
-                                    }
+                                    _ =>
+                                        MimeTypes::entry_type(
+                                            &self.content_type(&el, config)
+                                        ).to_string(),
                                 },
                                 link: request.location.clone() + &name,
                                 is_directory: el.is_dir(),
@@ -398,7 +846,105 @@ impl Server {
                 &cookie
             )?;
         }
-        Ok(())
+        Ok(None)
+    }
+
+    /// Cherche un script CGI dans le chemin : `/cgi/script.rb/a/b` donne le script
+    /// `/cgi/script.rb` et le PATH_INFO `/a/b`. Sous une route `fastcgi_pass`, tout
+    /// chemin est transmis au backend, qu'un fichier existe ou non.
+    pub fn find_cgi_script(&self, path: &str) -> Option<CgiScript> {
+        let root = self.root_directory.trim_end_matches('/');
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<&str>>();
+        if segments.contains(&"..") {
+            return None;
+        }
+
+        let route = self.find_route(path);
+        let fastcgi = route.and_then(|route| route.fastcgi_pass.clone());
+        let mut script_name = String::new();
+        for (i, segment) in segments.iter().enumerate() {
+            script_name.push('/');
+            script_name.push_str(segment);
+            let filename = format!("./{}{}", root, script_name);
+            let candidate = Path::new(&filename);
+
+            if candidate.is_file() {
+                let runner = match &fastcgi {
+                    Some(address) => CgiRunner::FastCgi(address.clone()),
+                    None => self.cgi_runner(candidate, route)?,
+                };
+                let filename = CGI::absolute(&filename);
+                let path_info = segments[i + 1..]
+                    .iter()
+                    .map(|segment| format!("/{}", segment))
+                    .collect::<String>();
+                return Some(CgiScript { filename, script_name, path_info, runner });
+            }
+            if !candidate.is_dir() {
+                break;
+            }
+        }
+
+        let address = fastcgi?;
+        Some(CgiScript {
+            filename: CGI::absolute(&format!("./{}{}", root, path)),
+            script_name: path.to_string(),
+            path_info: String::new(),
+            runner: CgiRunner::FastCgi(address),
+        })
+    }
+
+    /// Façon d'exécuter un fichier : interpréteur associé à son extension (la route
+    /// l'emporte sur le serveur), ou exécution directe si le bit exécutable est autorisé.
+    fn cgi_runner(&self, path: &Path, route: Option<&Route>) -> Option<CgiRunner> {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        let find = |interpreters: &HashMap<String, String>| {
+            interpreters
+                .iter()
+                .find(|(key, _)| !ext.is_empty() && key.trim_start_matches('.') == ext)
+                .map(|(_, interpreter)| interpreter.clone())
+        };
+
+        let interpreter = route
+            .and_then(|route| find(&route.cgi))
+            .or_else(|| find(&self.cgi));
+        if let Some(interpreter) = interpreter {
+            return Some(CgiRunner::Interpreter(interpreter));
+        }
+
+        let executables = route
+            .and_then(|route| route.cgi_executables)
+            .unwrap_or(self.cgi_executables);
+        match executables && CGI::is_executable(path) {
+            true => Some(CgiRunner::Executable),
+            false => None,
+        }
+    }
+
+    /// Signale les interpréteurs CGI introuvables (serveur et routes) et les
+    /// réglages d'isolation inapplicables.
+    pub fn check_cgi_settings(&self) -> Vec<String> {
+        let interpreters = self.cgi
+            .iter()
+            .chain(self.routes.iter().flat_map(|route| route.cgi.iter()));
+        let mut warnings = vec![];
+        for (ext, interpreter) in interpreters {
+            if !CGI::interpreter_exists(interpreter) {
+                warnings.push(
+                    format!("interpréteur CGI introuvable pour {} : {}", ext, interpreter)
+                );
+            }
+        }
+        warnings.sort();
+        warnings.dedup();
+        warnings.extend(self.cgi_sandbox.check());
+        warnings
     }
 
     fn create_folder(
@@ -527,74 +1073,17 @@ impl Server {
         cookie: String
     ) -> Result<(), std::io::Error> {
         // Déterminer le type de contenu en fonction de l'extension du fichier
-        let mut to_cgi = false;
-        let mut content_disposition = "";
-        let content_type = match
-            Path::new(path)
-                .extension()
-                .and_then(|ext| ext.to_str())
-        {
-            Some("html") => "text/html",
-            Some("css") => "text/css",
-            Some("js") => "application/javascript",
-            Some("png") => "image/png",
-            Some("jpg") | Some("jpeg") => "image/jpeg",
-            Some("gif") => "image/gif",
-            Some("json") => "application/json",
-            Some("pdf") => {
-                content_disposition = "\r\nContent-Disposition: inline";
-                "application/pdf" },
-            Some("rb") => {
-                to_cgi = true;
-                "text/plain"
-            }
-            _ => "text/plain", // Type par défaut
-        };
+        let content_type = self.content_type(Path::new(path), config);
 
         // Lire le fichier
         match fs::read(path) {
-            Ok(mut content) => {
-                if to_cgi {
-                    content = CGI::execute_file(path.to_string()).into();
-                }
-
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: {}{}\r\nConnection: keep-alive\r\ncontent-Length: {}\r\n{}\r\n",
-                    content_type,
-                    content_disposition,
-                    content.len(),
-                    cookie
-                );
-
-                if let Err(e) = stream.write_all(response.as_bytes()) {
-                    Self::error_log(
-                        &request,
-                        config,
-                        "handle_static_file",
-                        file!(),
-                        line!(),
-                        ServerError::IOError(&e)
-                    );
-
-                    return Err(e);
-                } else {
-                    // Log request
-                    self.access_log(&request, config, 200, &cookie);
-                    let _ = stream.flush();
-                }
-                if let Err(e) = stream.write_all(&content) {
-                    Self::error_log(
-                        &request,
-                        config,
-                        "handle_static_file",
-                        file!(),
-                        line!(),
-                        ServerError::IOError(&e)
-                    );
-
-                    return Err(e);
+            Ok(content) => {
+                let mut response = Response::with_code(200, &content_type, content);
+                response.id_session = cookie;
+                if content_type == "application/pdf" {
+                    response.add_header("Content-Disposition", "inline");
                 }
-                Ok(())
+                self.send_response(stream, &request, config, response)
             }
             Err(e) => {
                 Self::error_log(
@@ -606,7 +1095,7 @@ impl Server {
                     ServerError::IOError(&e)
                 );
                 Self::send_error_response(
-                    &self,
+                    self,
                     stream,
                     &request,
                     config,
@@ -619,6 +1108,108 @@ impl Server {
         }
     }
 
+    /// Formulaire de connexion (GET), vérification des identifiants (POST) et
+    /// déconnexion. Le Router enregistre l'utilisateur dans la session.
+    fn handle_login(
+        &self,
+        stream: &mut TcpStream,
+        request: Request,
+        cookie: String,
+        config: &Config,
+        login: &FormLogin
+    ) -> Result<Option<Job>, std::io::Error> {
+        let settings = self.session_cookie(config).clone();
+        if request.path() == login.logout_path {
+            // Pas de déconnexion par un simple lien ou une image d'un autre site
+            if request.method != "POST" {
+                self.send_error_response(stream, &request, config, 405, "Method Not Allowed", &cookie)?;
+                return Ok(None);
+            }
+            if let Err(message) = self.csrf.verify(&request) {
+                Self::error_log(&request, config, "handle_login", file!(), line!(), ServerError::CsrfError { uri: &request.uri, message });
+                self.send_error_response(stream, &request, config, 403, "Forbidden", &cookie)?;
+                return Ok(None);
+            }
+            return Ok(
+                Some(
+                    Job::Login(LoginJob {
+                        server: self.clone(),
+                        request,
+                        settings,
+                        user: None,
+                        target: login.login_path.clone(),
+                    })
+                )
+            );
+        }
+
+        match request.method.as_str() {
+            "POST" => {
+                let form = LoginForm::parse(&request.body);
+                if login.authenticate(&form) {
+                    return Ok(
+                        Some(
+                            Job::Login(LoginJob {
+                                server: self.clone(),
+                                request,
+                                settings,
+                                user: Some(form.username),
+                                target: form.next,
+                            })
+                        )
+                    );
+                }
+                Self::error_log(&request, config, "handle_login", file!(), line!(), ServerError::AuthError {
+                    user: &form.username,
+                    message: "identifiants refusés",
+                });
+                let page = LoginPage { login, next: &form.next, error: Some("Identifiants incorrects") };
+                self.send_login_form(stream, &request, config, cookie, page)?;
+            }
+            "GET" => {
+                let next = LoginForm::parse(request.query().unwrap_or_default()).next;
+                self.send_login_form(stream, &request, config, cookie, LoginPage { login, next: &next, error: None })?;
+            }
+            _ => self.send_error_response(stream, &request, config, 405, "Method Not Allowed", &cookie)?,
+        }
+        Ok(None)
+    }
+
+    /// Affiche le formulaire de connexion (403 après un échec : le formulaire n'est pas
+    /// un défi HTTP et n'a pas d'en-tête `WWW-Authenticate`).
+    fn send_login_form(
+        &self,
+        stream: &mut TcpStream,
+        request: &Request,
+        config: &Config,
+        cookie: String,
+        page: LoginPage
+    ) -> Result<(), std::io::Error> {
+        let LoginPage { login, next, error } = page;
+        let tera = Tera::new("src/**/*.html").unwrap();
+        let mut context = Context::new();
+        context.insert("action", &login.login_path);
+        context.insert("next", next);
+        context.insert("error", &error);
+        context.insert("user", &request.session.as_ref().and_then(|session| session.user.clone()));
+        context.insert("hostname", &self.hostname);
+        context.insert("logout", &login.logout_path);
+        context.insert("csrf_token", &request.session.as_ref().map(|session| &session.csrf_token));
+
+        match tera.render(login.template.strip_prefix("src/").unwrap_or(&login.template), &context) {
+            Ok(content) => {
+                let status = if error.is_some() { 403 } else { 200 };
+                let mut response = Response::with_code(status, "text/html; charset=utf-8", content.into_bytes());
+                response.id_session = cookie;
+                self.send_response(stream, request, config, response)
+            }
+            Err(e) => {
+                Self::error_log(request, config, "send_login_form", file!(), line!(), ServerError::TeraError(&e));
+                self.send_error_response(stream, request, config, 500, "Internal Server Error", &cookie)
+            }
+        }
+    }
+
     /// Gère une requête pour un fichier statique.
     fn handle_listing_directory(
         &self,
@@ -634,31 +1225,20 @@ impl Server {
         context.insert("elements", &all);
         context.insert("size", &all.len());
         context.insert("hostname", &self.hostname);
+        context.insert("session", &request.session_data());
+        context.insert("cookies", &request.cookies);
+        context.insert("user", &request.remote_user);
+        context.insert("csrf_token", &request.session.as_ref().map(|session| &session.csrf_token));
 
         match tera.render(&self.default_file.strip_prefix("src/").unwrap(), &context) {
             Ok(content) => {
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: {}\r\n{}\r\n{}",
-                    content.len(),
-                    cookie,
-                    content
+                let mut response = Response::with_code(
+                    200,
+                    "text/html; charset=utf-8",
+                    content.into_bytes()
                 );
-
-                if let Err(e) = stream.write_all(response.as_bytes()) {
-                    Self::error_log(
-                        &request,
-                        config,
-                        "handle_listing_directory",
-                        file!(),
-                        line!(),
-                        ServerError::IOError(&e)
-                    );
-                    Err(e)
-                } else {
-                    // Log request
-                    self.access_log(&request, config, 200, &cookie);
-                    Ok(stream.flush()?)
-                }
+                response.id_session = cookie;
+                self.send_response(stream, &request, config, response)
             }
             Err(e) => {
                 Self::error_log(
@@ -684,15 +1264,28 @@ impl Server {
     }
 
     /// Envoie une réponse d'erreur HTTP.
-    fn send_error_response(
+    pub fn send_error_response(
         &self,
         stream: &mut TcpStream,
         request: &Request,
         config: &Config,
         status_code: u16,
         status_message: &str,
-        cookie: &String
+        cookie: &str
     ) -> Result<(), std::io::Error> {
+        let response = self.error_response(request, config, status_code, status_message, cookie);
+        self.send_response(stream, request, config, response)
+    }
+
+    /// Page d'erreur HTTP et ses en-têtes propres au code.
+    pub fn error_response(
+        &self,
+        request: &Request,
+        config: &Config,
+        status_code: u16,
+        status_message: &str,
+        cookie: &str
+    ) -> Response {
         // Chargement du template
         let tera = Tera::new("src/**/*.html").unwrap();
         let mut context = Context::new();
@@ -703,45 +1296,101 @@ impl Server {
                 status: status_message.to_string(),
             })
         );
+        context.insert("session", &request.session_data());
+        context.insert("cookies", &request.cookies);
 
-        match tera.render(&self.error_path.strip_prefix("src/").unwrap(), &context) {
-            Ok(content) => {
-                let response = format!(
-                    "HTTP/1.1 {} {}\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n{}",
-                    status_code,
-                    status_message,
-                    content.len(),
-                    content
-                );
-                if let Err(e) = stream.write_all(response.as_bytes()) {
-                    Self::error_log(
-                        &request,
-                        config,
-                        "send_error_response",
-                        file!(),
-                        line!(),
-                        ServerError::IOError(&e)
-                    );
-                    return Err(e);
-                }
-
-                self.access_log(&request, config, status_code, &cookie);
-                Ok(stream.flush()?)
-            }
+        let body = match tera.render(self.error_path.strip_prefix("src/").unwrap(), &context) {
+            Ok(content) => content,
             Err(e) => {
                 Self::error_log(
-                    &request,
+                    request,
                     config,
                     "send_error_response",
                     file!(),
                     line!(),
                     ServerError::TeraError(&e)
                 );
-                Ok(())
+                format!("{} {}", status_code, status_message)
+            }
+        };
+
+        let mut response = Response::new(
+            cookie.to_string(),
+            format!("{} {}", status_code, status_message),
+            "text/html; charset=utf-8".to_string(),
+            body.into_bytes()
+        );
+        if status_code == 405 {
+            response.add_header("Allow", &self.allowed_methods(request));
+        }
+        if status_code == 426 {
+            response.add_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+        }
+        if let (401, Some(auth)) = (status_code, self.find_auth_basic(&request.location)) {
+            response.add_header("WWW-Authenticate", &auth.challenge());
+        }
+        response
+    }
+
+    /// Ajoute les en-têtes standards et ceux de la configuration (serveur puis route).
+    pub fn prepare_response(&self, request: &Request, response: &mut Response) {
+        let status_code = response.status_code();
+
+        response.set_header("Date", &Response::http_date());
+        response.set_header("Server", SERVER_SIGNATURE);
+        if !response.has_header("Connection") {
+            response.add_header("Connection", "keep-alive");
+        }
+
+        // La route l'emporte en cas de doublon
+        let route_headers = self
+            .find_route(&request.location)
+            .map(|route| route.add_headers.clone())
+            .unwrap_or_default();
+        for rule in self.add_headers.iter().chain(route_headers.iter()) {
+            if status_code < 400 || rule.always {
+                response.set_header(&rule.name, &rule.value);
             }
         }
     }
 
+    /// Chemin commun d'envoi : ajoute les en-têtes standards et ceux de la configuration,
+    /// écrit la réponse et la journalise.
+    pub fn send_response(
+        &self,
+        stream: &mut TcpStream,
+        request: &Request,
+        config: &Config,
+        mut response: Response
+    ) -> Result<(), std::io::Error> {
+        let status_code = response.status_code();
+        self.prepare_response(request, &mut response);
+
+        if let Err(e) = stream.write_all(&response.to_http_response()) {
+            Self::error_log(
+                request,
+                config,
+                "send_response",
+                file!(),
+                line!(),
+                ServerError::IOError(&e)
+            );
+            return Err(e);
+        }
+
+        self.access_log(request, config, status_code, &response.id_session);
+        stream.flush()
+    }
+
+    /// Comme `send_response`, pour un client non bloquant : la réponse est mise en file
+    /// et partira quand le socket l'acceptera.
+    pub fn queue_response(&self, outgoing: &mut Outgoing, request: &Request, config: &Config, mut response: Response) {
+        let status_code = response.status_code();
+        self.prepare_response(request, &mut response);
+        outgoing.push(&response.to_http_response());
+        self.access_log(request, config, status_code, &response.id_session);
+    }
+
     fn upload_file(
         &self,
         stream: &mut TcpStream,
@@ -831,10 +1480,7 @@ impl Server {
 
         // Envoyer une réponse de redirection
         match self.send_redirect_response(stream, &*request.location, config, &request) {
-            Ok(_) => {
-                self.access_log(&request.clone(), config, 200, &request.id_session);
-                Ok(())
-            }
+            Ok(_) => Ok(()),
             Err(e) => {
                 Self::error_log(
                     request,
@@ -864,55 +1510,16 @@ impl Server {
         config: &Config,
         request: &Request
     ) -> Result<(), std::io::Error> {
-        let [to, code] = match request.method.as_str() {
-            "DELETE" => {
-                Self::access_log(&self, request, config, 200, &request.id_session);
-                ["/", "200"]
-            },
-            _ => [location, "302"],
+        let (to, code) = match request.method.as_str() {
+            "DELETE" => ("/", 200),
+            _ => (location, 302),
         };
-        // Construire la réponse HTTP
-        let response = format!(
-            "HTTP/1.1 {} Found\r\n\
-             Location: {}\r\n\
-             Content-Length: 0\r\n\
-             Connection: keep-alive\r\n
-             Cache-Control: no-cache, no-store, must-revalidate\r\n\
-             Pragma: no-cache\r\n\
-             Expires: 0\r\n\
-             \r\n",
-            code,
-            to
-        );
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => (),
-            Err(e) => {
-                Self::error_log(
-                    request,
-                    config,
-                    "upload_file",
-                    file!(),
-                    line!(),
-                    ServerError::IOError(&e)
-                );
-                return Err(e);
-            }
-        }
-        match stream.flush() {
-            Ok(_) => (),
-            Err(e) => {
-                Self::error_log(
-                    request,
-                    config,
-                    "upload_file",
-                    file!(),
-                    line!(),
-                    ServerError::IOError(&e)
-                );
-                return Err(e);
-            }
-        }
-        Ok(())
+        let mut response = Response::with_code(code, "", vec![]);
+        response.add_header("Location", to);
+        response.add_header("Cache-Control", "no-cache, no-store, must-revalidate");
+        response.add_header("Pragma", "no-cache");
+        response.add_header("Expires", "0");
+        self.send_response(stream, request, config, response)
     }
 
     /*    fn write_reponse(stream: &mut TcpStream, content: &[u8]) -> Result<(), std::io::Error> {
@@ -947,4 +1554,202 @@ impl Server {
         }
     }
 }
+
 // -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Serveur de test servant `src/www` et ses routes (en TOML).
+    fn test_server(routes: &str) -> Server {
+        let mut server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            "src/static_files/error.html".to_string(),
+            "src/static_files/index.html".to_string(),
+            5000,
+            vec!["GET".to_string(), "POST".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: Vec<Route>,
+        }
+        server.routes = toml::from_str::<Routes>(routes).unwrap().routes;
+        server
+    }
+
+    /// Traite `request` sur une paire de sockets locale et renvoie la réponse brute.
+    fn respond(server: &Server, mut request: Request) -> (String, Option<Job>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = TcpStream::from_std(listener.accept().unwrap().0);
+        request.host = "localhost".to_string();
+        request.port = 8080;
+        if request.method.is_empty() {
+            request.method = "GET".to_string();
+        }
+        request.uri = request.location.clone();
+        request.uri_decode();
+        let job = server.handle_request(&mut stream, request, String::new(), &Config::new()).unwrap();
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (response, job)
+    }
+
+    fn status(server: &Server, location: &str) -> String {
+        let mut request = Request::default();
+        request.location = location.to_string();
+        let (response, _) = respond(server, request);
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn test_protected_route_normalized_path() {
+        let path = std::env::temp_dir().join(format!("localhost-routes-{}", std::process::id()));
+        fs::write(&path, "alice:$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/\n").unwrap();
+        let server = test_server(
+            &format!(
+                "routes = [{{ path = \"/fifanela\", auth_basic = {{ realm = \"Admin\", user_file = \"{}\" }} }}]",
+                path.display()
+            )
+        );
+        for location in ["/fifanela/form.rb", "//fifanela/form.rb", "/./fifanela/form.rb", "/d/../fifanela/form.rb", "/%66ifanela/form.rb"] {
+            assert_eq!(status(&server, location), "HTTP/1.1 401 Unauthorized", "{}", location);
+        }
+        assert_eq!(status(&server, "/../fifanela/form.rb"), "HTTP/1.1 400 Bad Request");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_login_pages_and_required_login() {
+        let mut server = test_server("routes = [{ path = \"/fifanela\", require_login = true }]");
+        server.login = Some(toml::from_str("user_file = \"/nonexistent\"").unwrap());
+        for location in ["/fifanela/form.rb", "//fifanela/form.rb", "/./fifanela/form.rb"] {
+            let mut request = Request::default();
+            request.location = location.to_string();
+            let (response, _) = respond(&server, request);
+            assert!(response.starts_with("HTTP/1.1 303 See Other\r\n"), "{}", location);
+            assert!(response.contains("Location: /login?next="), "{}", location);
+        }
+
+        // Déconnexion : POST uniquement, avec le jeton CSRF de la session
+        let session = Session::new();
+        let mut request = Request::default();
+        request.location = "/logout".to_string();
+        request.session = Some(session.clone());
+        let (response, job) = respond(&server, request.clone());
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("Allow: POST\r\n"));
+        assert!(job.is_none());
+
+        request.method = "POST".to_string();
+        let (response, job) = respond(&server, request.clone());
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(job.is_none());
+
+        request.headers.insert("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string());
+        request.body = format!("csrf_token={}", session.csrf_token);
+        let (response, job) = respond(&server, request);
+        assert!(response.is_empty());
+        assert!(matches!(job, Some(Job::Login(LoginJob { user: None, .. }))));
+
+        // Échec de connexion : formulaire en 403, sans défi HTTP
+        let mut request = Request::default();
+        request.location = "/login".to_string();
+        request.method = "POST".to_string();
+        request.body = "username=alice&password=faux".to_string();
+        let (response, _) = respond(&server, request);
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!response.contains("WWW-Authenticate"));
+        assert!(response.contains("Identifiants incorrects"));
+    }
+
+    #[test]
+    fn test_jwt_route_normalized_path() {
+        let path = std::env::temp_dir().join(format!("localhost-jwt-secret-{}", std::process::id()));
+        fs::write(&path, "secret\n").unwrap();
+        let server = test_server(
+            &format!("routes = [{{ path = \"/fifanela\", auth_jwt = {{ secret_file = \"{}\" }} }}]", path.display())
+        );
+        for location in ["/fifanela/form.rb", "//fifanela/form.rb", "/./fifanela//form.rb"] {
+            let mut request = Request::default();
+            request.location = location.to_string();
+            request.headers.insert("Authorization".to_string(), "Bearer a.b.c".to_string());
+            let (response, _) = respond(&server, request);
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", location);
+            assert!(
+                response.contains("WWW-Authenticate: Bearer error=\"invalid_token\", error_description=\"malformed header\"\r\n"),
+                "{}",
+                location
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prepare_response_headers() {
+        let mut server = test_server(
+            "routes = [{ path = \"/d\", add_headers = [{ name = \"X-Frame-Options\", value = \"DENY\" }] }]"
+        );
+        server.add_headers = vec![
+            HeaderRule { name: "X-Frame-Options".to_string(), value: "SAMEORIGIN".to_string(), always: false },
+            HeaderRule { name: "Cache-Control".to_string(), value: "no-store".to_string(), always: false },
+            HeaderRule { name: "X-Content-Type-Options".to_string(), value: "nosniff".to_string(), always: true },
+        ];
+        let header = |response: &Response, name: &str| {
+            response.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.clone())
+        };
+
+        let mut request = Request::default();
+        request.location = "/d/page.html".to_string();
+        let mut response = Response::with_code(200, "text/html", vec![]);
+        server.prepare_response(&request, &mut response);
+        assert!(response.has_header("Date"));
+        assert_eq!(header(&response, "Server").as_deref(), Some(SERVER_SIGNATURE));
+        assert_eq!(header(&response, "Connection").as_deref(), Some("keep-alive"));
+        assert_eq!(header(&response, "X-Frame-Options").as_deref(), Some("DENY"));
+        assert_eq!(header(&response, "Cache-Control").as_deref(), Some("no-store"));
+        assert_eq!(header(&response, "X-Content-Type-Options").as_deref(), Some("nosniff"));
+
+        // Réponse d'erreur : seules les règles `always` s'appliquent
+        let mut response = Response::with_code(404, "text/html", vec![]);
+        response.add_header("Connection", "close");
+        server.prepare_response(&request, &mut response);
+        assert!(response.has_header("Date"));
+        assert_eq!(header(&response, "Server").as_deref(), Some(SERVER_SIGNATURE));
+        assert_eq!(header(&response, "Connection").as_deref(), Some("close"));
+        assert!(!response.has_header("X-Frame-Options"));
+        assert!(!response.has_header("Cache-Control"));
+        assert_eq!(header(&response, "X-Content-Type-Options").as_deref(), Some("nosniff"));
+
+        request.location = "/page.html".to_string();
+        let mut response = Response::with_code(200, "text/html", vec![]);
+        server.prepare_response(&request, &mut response);
+        assert_eq!(header(&response, "X-Frame-Options").as_deref(), Some("SAMEORIGIN"));
+    }
+
+    #[test]
+    fn test_rule_loops_answer_508() {
+        #[derive(Deserialize)]
+        struct Rules {
+            redirections: Vec<Redirection>,
+            rewrites: Vec<Rewrite>,
+        }
+        let rules = toml::from_str::<Rules>(
+            "redirections = [{ source = \"/r1\", target = \"/r2\" }, { source = \"/r2\", target = \"/r1\" }]\n\
+             rewrites = [{ source = \"^/w1$\", target = \"/w2\", flag = \"last\" }, { source = \"^/w2$\", target = \"/w1\", flag = \"last\" }]"
+        ).unwrap();
+        let mut server = test_server("routes = []");
+        server.redirections = rules.redirections;
+        server.rewrites = rules.rewrites;
+        assert_eq!(status(&server, "/r1"), "HTTP/1.1 508 Loop Detected");
+        assert_eq!(status(&server, "/w1"), "HTTP/1.1 508 Loop Detected");
+    }
+}
@@ -0,0 +1,1081 @@
+use mio::net::TcpStream;
+use mio::{ Interest, Registry, Token };
+use serde::Deserialize;
+use std::io::{ self, Write };
+use std::net::ToSocketAddrs;
+use std::time::{ Duration, Instant };
+
+use super::{ drain, drain_limit, CgiState, Outgoing, Request, Response, Server, ServerError, SERVER_SIGNATURE };
+use crate::{ Config, Route };
+
+// -------------------------------------------------------------------------------------
+// UPSTREAM
+// -------------------------------------------------------------------------------------
+/// En-têtes propres à une connexion, jamais relayés tels quels.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+/// Taille maximale du bloc d'en-têtes d'une réponse amont.
+const MAX_HEAD: usize = 64 * 1024;
+/// Octets en attente d'envoi au-delà desquels l'autre côté n'est plus lu : le corps
+/// du client quand l'amont ne suit pas, la réponse amont quand le client ne suit pas.
+const PROXY_HIGH_WATER: usize = 256 * 1024;
+
+/// Répartition des requêtes entre les serveurs d'un upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// Le serveur qui a le moins de requêtes en cours.
+    LeastConn,
+}
+
+/// Groupe de serveurs HTTP désigné par son nom dans `proxy_pass` (`[http.upstreams.api]`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Upstream {
+    /// Adresses `hôte:port`.
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// Échecs avant d'écarter un serveur (0 : jamais).
+    #[serde(default = "Upstream::default_max_fails")]
+    pub max_fails: u32,
+    /// Durée d'exclusion d'un serveur en échec, en millisecondes.
+    #[serde(default = "Upstream::default_fail_timeout")]
+    pub fail_timeout: u64,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+/// Vérification active : requête GET périodique vers chaque serveur.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+    pub path: String,
+    /// Intervalle entre deux vérifications, en millisecondes.
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval: u64,
+    /// Délai de réponse accordé au serveur, en millisecondes.
+    #[serde(default = "HealthCheck::default_timeout")]
+    pub timeout: u64,
+}
+
+impl HealthCheck {
+    fn default_interval() -> u64 {
+        5000
+    }
+
+    fn default_timeout() -> u64 {
+        2000
+    }
+}
+
+impl Upstream {
+    fn default_max_fails() -> u32 {
+        1
+    }
+
+    fn default_fail_timeout() -> u64 {
+        10000
+    }
+
+    /// Upstream implicite d'un `proxy_pass` qui désigne directement un serveur.
+    pub fn single(address: &str) -> Self {
+        Self {
+            servers: vec![address.to_string()],
+            balance: Balance::default(),
+            max_fails: Self::default_max_fails(),
+            fail_timeout: Self::default_fail_timeout(),
+            health_check: None,
+        }
+    }
+
+    /// Vérifie la configuration de l'upstream et renvoie la liste des erreurs trouvées.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.servers.is_empty() {
+            errors.push("aucun serveur".to_string());
+        }
+        for address in &self.servers {
+            if let Err(e) = Proxy::check_address(address) {
+                errors.push(e);
+            }
+        }
+        if let Some(check) = &self.health_check {
+            if !check.path.starts_with('/') {
+                errors.push(format!("chemin health_check invalide : {}", check.path));
+            }
+            if check.interval == 0 || check.timeout == 0 {
+                errors.push("interval et timeout de health_check doivent être positifs".to_string());
+            }
+        }
+        errors
+    }
+}
+
+/// Serveur d'un upstream et son état.
+#[derive(Debug)]
+pub struct Peer {
+    pub address: String,
+    /// Requêtes en cours (répartition `least_conn`).
+    pub active: usize,
+    fails: u32,
+    /// Serveur écarté après `max_fails` échecs, jusqu'à cette date.
+    down_until: Option<Instant>,
+    /// Résultat de la dernière vérification active.
+    pub healthy: bool,
+}
+
+impl Peer {
+    fn available(&self, now: Instant) -> bool {
+        self.healthy && self.down_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// État d'un upstream pendant l'exécution du Router.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    pub config: Upstream,
+    pub peers: Vec<Peer>,
+    next: usize,
+    /// Prochaine vérification active.
+    pub next_check: Instant,
+}
+
+impl UpstreamPool {
+    pub fn new(config: Upstream) -> Self {
+        let peers = config.servers
+            .iter()
+            .map(|address| Peer {
+                address: address.clone(),
+                active: 0,
+                fails: 0,
+                down_until: None,
+                healthy: true,
+            })
+            .collect();
+        Self { config, peers, next: 0, next_check: Instant::now() }
+    }
+
+    /// Choisit un serveur disponible qui n'a pas encore été essayé pour la requête.
+    pub fn pick(&mut self, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let count = self.peers.len();
+        let mut candidates = (0..count)
+            .map(|i| (self.next + i) % count)
+            .filter(|i| !tried.contains(i) && self.peers[*i].available(now));
+        let peer = match self.config.balance {
+            Balance::RoundRobin => candidates.next()?,
+            // À égalité, l'ordre du tourniquet départage
+            Balance::LeastConn => candidates.min_by_key(|i| self.peers[*i].active)?,
+        };
+        self.next = (peer + 1) % count;
+        Some(peer)
+    }
+
+    /// Vérification passive : compte un échec et écarte le serveur au-delà de `max_fails`.
+    /// Un serveur seul n'est jamais écarté. Renvoie `true` si le serveur vient d'être écarté.
+    pub fn failure(&mut self, peer: usize) -> bool {
+        if self.peers.len() < 2 || self.config.max_fails == 0 {
+            return false;
+        }
+        let fail_timeout = Duration::from_millis(self.config.fail_timeout);
+        let peer = &mut self.peers[peer];
+        peer.fails += 1;
+        if peer.fails < self.config.max_fails {
+            return false;
+        }
+        peer.fails = 0;
+        peer.down_until = Some(Instant::now() + fail_timeout);
+        true
+    }
+
+    pub fn success(&mut self, peer: usize) {
+        self.peers[peer].fails = 0;
+    }
+
+    /// Enregistre le résultat d'une vérification active. Renvoie `true` si l'état a changé.
+    pub fn set_health(&mut self, peer: usize, healthy: bool) -> bool {
+        let peer = &mut self.peers[peer];
+        if healthy {
+            peer.fails = 0;
+            peer.down_until = None;
+        }
+        std::mem::replace(&mut peer.healthy, healthy) != healthy
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// PROXY
+// -------------------------------------------------------------------------------------
+/// Cible d'un `proxy_pass` : `http://<upstream ou hôte:port>[/chemin]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyPass {
+    /// Nom d'un upstream ou adresse `hôte:port`.
+    pub authority: String,
+    /// Chemin qui remplace le préfixe de la route (`None` : URI transmise telle quelle).
+    pub path: Option<String>,
+}
+
+impl ProxyPass {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let rest = value
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("proxy_pass doit commencer par http:// : {}", value))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(rest[i..].to_string())),
+            None => (rest, None),
+        };
+        if authority.is_empty() {
+            return Err(format!("proxy_pass sans upstream : {}", value));
+        }
+        Ok(Self { authority: authority.to_string(), path })
+    }
+
+    /// Cible de la requête amont : le préfixe de la route est remplacé par le chemin
+    /// du `proxy_pass` (`/api/users` -> `/v1/users` pour `/api` et `http://api/v1`).
+    pub fn target(&self, route: &str, uri: &str) -> String {
+        let Some(path) = &self.path else {
+            return uri.to_string();
+        };
+        let rest = uri.strip_prefix(route.trim_end_matches('/')).unwrap_or(uri);
+        let target = format!("{}{}", path.trim_end_matches('/'), rest);
+        match target.starts_with('/') {
+            true => target,
+            false => format!("/{}", target),
+        }
+    }
+}
+
+/// Requête à relayer vers un upstream, préparée par `Server::handle_request`.
+#[derive(Debug, Clone)]
+pub struct ProxyJob {
+    pub server: Server,
+    pub request: Request,
+    pub cookie: String,
+    /// Clé de l'upstream : son nom ou l'adresse du serveur.
+    pub upstream: String,
+    /// Requête HTTP envoyée au serveur amont, avec la partie du corps déjà reçue.
+    pub outgoing: Vec<u8>,
+    /// Octets du corps encore attendus du client, relayés au fil de leur arrivée.
+    pub body_remaining: usize,
+    /// Attente maximale de données de l'amont, en millisecondes.
+    pub timeout: u64,
+}
+
+impl ProxyJob {
+    /// `None` si la route n'a pas de `proxy_pass` valide.
+    pub fn new(server: &Server, request: &Request, cookie: &str, route: &Route) -> Option<Self> {
+        let pass = ProxyPass::parse(route.proxy_pass.as_deref()?).ok()?;
+        // L'URI brute est transmise, sauf si une réécriture l'a changée
+        let uri = match urlencoding::decode(&request.uri) {
+            Ok(decoded) if decoded == request.location => request.uri.as_str(),
+            _ => request.location.as_str(),
+        };
+        let target = pass.target(&route.path, uri);
+        let host = match route.proxy_host.as_deref() {
+            None => pass.authority.clone(),
+            Some("$host") => match request.port {
+                0 => request.host.clone(),
+                port => format!("{}:{}", request.host, port),
+            },
+            Some(host) => host.to_string(),
+        };
+
+        Some(Self {
+            server: server.clone(),
+            request: request.clone(),
+            cookie: cookie.to_string(),
+            upstream: pass.authority,
+            outgoing: Proxy::encode_request(request, &target, &host),
+            body_remaining: request.content_length.map_or(0, |length|
+                length.saturating_sub(request.body_bytes().len())
+            ),
+            timeout: route.proxy_timeout.unwrap_or_else(Proxy::default_timeout),
+        })
+    }
+
+    pub fn log_error(&self, config: &Config, address: &str, message: &str) {
+        Server::error_log(
+            &self.request,
+            config,
+            "ProxyJob",
+            file!(),
+            line!(),
+            ServerError::ProxyError {
+                upstream: address,
+                uri: &self.request.uri,
+                message,
+            }
+        );
+    }
+}
+
+/// En-tête de la réponse d'un serveur amont.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UpstreamHead {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+pub struct Proxy;
+
+impl Proxy {
+    pub fn default_timeout() -> u64 {
+        30000
+    }
+
+    /// Vérifie une adresse de serveur amont : `hôte:port`.
+    pub fn check_address(address: &str) -> Result<(), String> {
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| format!("adresse upstream sans port : {}", address))?;
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(format!("adresse upstream invalide : {}", address));
+        }
+        Ok(())
+    }
+
+    /// Requête amont : en-têtes du client sans ceux de la connexion, `Host` réécrit,
+    /// `X-Forwarded-For` complété et partie du corps déjà reçue.
+    pub fn encode_request(request: &Request, target: &str, host: &str) -> Vec<u8> {
+        let body = request.body_bytes();
+        let length = request.content_length.unwrap_or(body.len());
+        let body = &body[..body.len().min(length)];
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, host);
+        let mut forwarded_for = None;
+
+        let mut headers = request.headers.iter().collect::<Vec<(&String, &String)>>();
+        headers.sort();
+        for (name, value) in headers {
+            let lower = name.to_ascii_lowercase();
+            if lower == "x-forwarded-for" {
+                forwarded_for = Some(value.clone());
+            } else if
+                !HOP_BY_HOP.contains(&lower.as_str()) &&
+                !["content-length", "transfer-encoding", "expect", "x-forwarded-proto"].contains(
+                    &lower.as_str()
+                )
+            {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+
+        let client_ip = request.remote_addr
+            .rsplit_once(':')
+            .map_or(request.remote_addr.as_str(), |(ip, _)| ip)
+            .trim_matches(|c| c == '[' || c == ']');
+        let forwarded_for = match forwarded_for {
+            Some(list) => format!("{}, {}", list, client_ip),
+            None => client_ip.to_string(),
+        };
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+        head.push_str("X-Forwarded-Proto: http\r\n");
+        // Une connexion par requête : la fin de la réponse est toujours détectable
+        head.push_str("Connection: close\r\n");
+        if length > 0 || request.method == "POST" {
+            head.push_str(&format!("Content-Length: {}\r\n", length));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// Lit la ligne de statut et les en-têtes en tête du tampon.
+    /// `Ok(None)` tant que le bloc d'en-têtes n'est pas complet.
+    pub fn parse_head(buffer: &[u8]) -> Result<Option<(UpstreamHead, usize)>, String> {
+        let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if buffer.len() > MAX_HEAD {
+                return Err("en-têtes de la réponse amont trop longs".to_string());
+            }
+            return Ok(None);
+        };
+        let head = std::str::from_utf8(&buffer[..end])
+            .map_err(|_| "en-têtes de la réponse amont invalides (UTF-8)".to_string())?;
+        let mut lines = head.split("\r\n");
+
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        let status = parts
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (100..600).contains(code));
+        let Some(status) = status.filter(|_| version.starts_with("HTTP/1.")) else {
+            return Err(format!("ligne de statut amont invalide : {}", status_line));
+        };
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = vec![];
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("en-tête amont invalide : {}", line))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(Some((UpstreamHead { status, reason, headers }, end + 4)))
+    }
+
+    /// Connexion non bloquante vers un serveur amont.
+    fn connect(address: &str) -> io::Result<TcpStream> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "adresse upstream introuvable"))?;
+        TcpStream::connect(addr)
+    }
+
+    /// `Ok(false)` tant que la connexion est en cours d'établissement.
+    fn is_connected(stream: &TcpStream) -> io::Result<bool> {
+        if let Some(e) = stream.take_error()? {
+            return Err(e);
+        }
+        match stream.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Écrit ce qui reste de `outgoing` jusqu'à ce que la socket soit pleine.
+    fn write_pending(stream: &mut TcpStream, outgoing: &[u8], written: &mut usize) -> io::Result<()> {
+        while *written < outgoing.len() {
+            match stream.write(&outgoing[*written..]) {
+                Ok(n) => {
+                    *written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// PROXY REQUEST
+// -------------------------------------------------------------------------------------
+/// Délimitation du corps de la réponse amont.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// `Content-Length` : octets restant à recevoir.
+    Length(usize),
+    /// `Transfer-Encoding: chunked`, relayé tel quel jusqu'à la fermeture.
+    Chunked,
+    /// Corps délimité par la fermeture : il est renvoyé au client en morceaux.
+    Close,
+}
+
+/// Requête en cours vers un serveur amont, pilotée par la boucle d'événements.
+#[derive(Debug)]
+pub struct ProxyRequest {
+    /// Token du client qui attend la réponse.
+    pub client: Token,
+    pub job: ProxyJob,
+    /// Index du serveur dans l'upstream.
+    pub peer: usize,
+    pub address: String,
+    /// Serveurs déjà essayés pour cette requête.
+    pub tried: Vec<usize>,
+    connection: TcpStream,
+    connected: bool,
+    /// Requête et corps en attente d'envoi vers l'amont.
+    upstream: Outgoing,
+    /// Octets déjà envoyés à l'amont.
+    sent: usize,
+    /// Octets du corps encore attendus du client.
+    body_remaining: usize,
+    /// Octets du corps lus sur le client après le lancement de la requête.
+    streamed: usize,
+    incoming: Vec<u8>,
+    framing: Option<Framing>,
+    /// Réponse en attente d'envoi au client.
+    reply: Outgoing,
+    /// Réponse amont entièrement reçue : il ne reste qu'à vider `reply`.
+    complete: bool,
+    pub headers_sent: bool,
+    pub status: u16,
+    pub deadline: Instant,
+}
+
+/// Distingue une panne du serveur amont (502, autre serveur) d'une erreur côté client.
+#[derive(Debug)]
+pub enum ProxyError {
+    Upstream(String),
+    Client(io::Error),
+}
+
+impl ProxyError {
+    fn upstream(context: &str, e: io::Error) -> Self {
+        Self::Upstream(format!("{} : {}", context, e))
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        Self::Client(e)
+    }
+}
+
+impl ProxyRequest {
+    pub fn start(job: &ProxyJob, peer: usize, address: &str, tried: Vec<usize>) -> io::Result<Self> {
+        let mut upstream = Outgoing::default();
+        upstream.push(&job.outgoing);
+        Ok(Self {
+            client: Token(0),
+            job: job.clone(),
+            peer,
+            address: address.to_string(),
+            tried,
+            connection: Proxy::connect(address)?,
+            connected: false,
+            upstream,
+            sent: 0,
+            body_remaining: job.body_remaining,
+            streamed: 0,
+            incoming: vec![],
+            framing: None,
+            reply: Outgoing::default(),
+            complete: false,
+            headers_sent: false,
+            status: 0,
+            deadline: Instant::now() + Duration::from_millis(job.timeout),
+        })
+    }
+
+    pub fn register(&mut self, registry: &Registry, client: Token, token: Token) -> io::Result<()> {
+        self.client = client;
+        registry.register(&mut self.connection, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.connection);
+    }
+
+    /// La requête peut être rejouée sur un autre serveur : rien n'a été reçu, le corps
+    /// n'a pas commencé à être lu sur le client, et une requête POST n'a pas commencé
+    /// à partir.
+    pub fn retryable(&self) -> bool {
+        !self.headers_sent &&
+            self.incoming.is_empty() &&
+            self.streamed == 0 &&
+            (self.sent == 0 || self.job.request.method != "POST")
+    }
+
+    /// Le corps de la requête arrive encore : les données du client sont pour l'amont.
+    pub fn expects_body(&self) -> bool {
+        self.body_remaining > 0
+    }
+
+    /// Envoie la requête et relaie la réponse au fil de sa réception. Chaque côté n'est
+    /// lu que tant que l'autre suit ; le WRITABLE du côté en retard relance l'échange.
+    pub fn on_event(&mut self, stream: &mut TcpStream, config: &Config) -> Result<CgiState, ProxyError> {
+        if !self.complete && !self.connected {
+            self.connected = Proxy::is_connected(&self.connection).map_err(|e|
+                ProxyError::upstream("connexion impossible", e)
+            )?;
+            if !self.connected {
+                return Ok(CgiState::Running);
+            }
+        }
+
+        // Les deux sockets ne signalent plus ce qui reste à lire : on continue tant
+        // qu'un côté avance
+        while !self.complete {
+            let queued = self.upstream.len();
+            self.upstream.flush(&mut self.connection).map_err(|e|
+                ProxyError::upstream("écriture vers le serveur amont", e)
+            )?;
+            self.sent += queued - self.upstream.len();
+            let forwarded = self.read_body(stream)?;
+            self.reply.flush(stream)?;
+            let received = self.receive(config)?;
+            if forwarded == 0 && received == 0 {
+                break;
+            }
+        }
+
+        self.reply.flush(stream)?;
+        match self.complete && self.reply.is_empty() {
+            true => Ok(CgiState::Done),
+            false => Ok(CgiState::Running),
+        }
+    }
+
+    /// Lit la suite du corps sur le client tant que l'amont la reçoit. Renvoie le nombre
+    /// d'octets lus.
+    fn read_body(&mut self, stream: &mut TcpStream) -> Result<usize, ProxyError> {
+        let room = PROXY_HIGH_WATER.saturating_sub(self.upstream.len()).min(self.body_remaining);
+        if room == 0 {
+            return Ok(0);
+        }
+        let mut data = vec![];
+        let eof = drain_limit(stream, &mut data, room)?;
+        if eof && data.len() < self.body_remaining {
+            return Err(ProxyError::Client(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if !data.is_empty() {
+            self.body_remaining -= data.len();
+            self.streamed += data.len();
+            self.upstream.push(&data);
+            // Un long envoi n'est pas coupé par le délai de l'amont
+            self.deadline = Instant::now() + Duration::from_millis(self.job.timeout);
+        }
+        Ok(data.len())
+    }
+
+    /// Lit la réponse amont tant que le client la reçoit et la met en file. Renvoie le
+    /// nombre d'octets lus.
+    fn receive(&mut self, config: &Config) -> Result<usize, ProxyError> {
+        let room = PROXY_HIGH_WATER.saturating_sub(self.reply.len());
+        if room == 0 {
+            return Ok(0);
+        }
+        let received = self.incoming.len();
+        let eof = drain_limit(&mut self.connection, &mut self.incoming, room).map_err(|e|
+            ProxyError::upstream("lecture depuis le serveur amont", e)
+        )?;
+        let read = self.incoming.len() - received;
+        if read > 0 {
+            // Le délai court depuis la dernière réception : un long flux n'est pas coupé
+            self.deadline = Instant::now() + Duration::from_millis(self.job.timeout);
+        }
+
+        // Les réponses intermédiaires (1xx) sont ignorées
+        while self.framing.is_none() {
+            let Some((head, size)) = Proxy::parse_head(&self.incoming).map_err(ProxyError::Upstream)? else {
+                if eof {
+                    return Err(ProxyError::Upstream("connexion fermée avant la réponse".to_string()));
+                }
+                return Ok(read);
+            };
+            self.incoming.drain(..size);
+            if head.status >= 200 {
+                self.send_head(head);
+            }
+        }
+
+        let data = std::mem::take(&mut self.incoming);
+        match self.framing {
+            Some(Framing::Length(remaining)) => {
+                let size = data.len().min(remaining);
+                self.reply.push(&data[..size]);
+                self.framing = Some(Framing::Length(remaining - size));
+            }
+            Some(Framing::Chunked) => self.reply.push(&data),
+            _ if !data.is_empty() => self.reply.push(&Response::chunk(&data)),
+            _ => (),
+        }
+
+        let complete = match self.framing {
+            Some(Framing::Length(remaining)) => remaining == 0,
+            _ => eof,
+        };
+        if !complete {
+            if eof {
+                return Err(ProxyError::Upstream("réponse amont tronquée".to_string()));
+            }
+            return Ok(read);
+        }
+        if self.framing == Some(Framing::Close) {
+            self.reply.push(b"0\r\n\r\n");
+        }
+        self.complete = true;
+        self.job.server.access_log(&self.job.request, config, self.status, &self.job.cookie);
+        Ok(read)
+    }
+
+    /// Met en file la ligne de statut et les en-têtes amont, complétés comme une réponse locale.
+    fn send_head(&mut self, head: UpstreamHead) {
+        let reason = match head.reason.is_empty() {
+            true => Response::reason_phrase(head.status).to_string(),
+            false => head.reason,
+        };
+        let mut response = Response::new(
+            self.job.cookie.clone(),
+            format!("{} {}", head.status, reason),
+            String::new(),
+            vec![]
+        );
+        for (name, value) in &head.headers {
+            if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+                response.add_header(name, value);
+            }
+        }
+
+        let header = |name: &str| {
+            head.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let framing = if [204, 304].contains(&head.status) {
+            Framing::Length(0)
+        } else if header("Transfer-Encoding").is_some_and(|value| value.contains("chunked")) {
+            Framing::Chunked
+        } else if let Some(length) = header("Content-Length").and_then(|value| value.parse().ok()) {
+            Framing::Length(length)
+        } else {
+            response.set_header("Transfer-Encoding", "chunked");
+            Framing::Close
+        };
+        self.job.server.prepare_response(&self.job.request, &mut response);
+
+        self.reply.push(response.to_http_head().as_bytes());
+        self.framing = Some(framing);
+        self.status = head.status;
+        self.headers_sent = true;
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// HEALTH CHECK
+// -------------------------------------------------------------------------------------
+/// Vérification active en cours vers un serveur d'un upstream.
+#[derive(Debug)]
+pub struct HealthProbe {
+    pub upstream: String,
+    pub peer: usize,
+    connection: TcpStream,
+    connected: bool,
+    outgoing: Vec<u8>,
+    written: usize,
+    incoming: Vec<u8>,
+    pub deadline: Instant,
+}
+
+impl HealthProbe {
+    pub fn start(upstream: &str, peer: usize, address: &str, check: &HealthCheck) -> io::Result<Self> {
+        let outgoing = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n",
+            check.path,
+            address,
+            SERVER_SIGNATURE
+        );
+        Ok(Self {
+            upstream: upstream.to_string(),
+            peer,
+            connection: Proxy::connect(address)?,
+            connected: false,
+            outgoing: outgoing.into_bytes(),
+            written: 0,
+            incoming: vec![],
+            deadline: Instant::now() + Duration::from_millis(check.timeout),
+        })
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut self.connection, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.connection);
+    }
+
+    /// `Some(true)` pour une réponse 2xx ou 3xx, `Some(false)` en cas d'échec,
+    /// `None` tant que la ligne de statut n'est pas reçue.
+    pub fn on_event(&mut self) -> Option<bool> {
+        self.read_status().unwrap_or(Some(false))
+    }
+
+    fn read_status(&mut self) -> io::Result<Option<bool>> {
+        if !self.connected {
+            self.connected = Proxy::is_connected(&self.connection)?;
+            if !self.connected {
+                return Ok(None);
+            }
+        }
+        Proxy::write_pending(&mut self.connection, &self.outgoing, &mut self.written)?;
+        let eof = drain(&mut self.connection, &mut self.incoming)?;
+
+        let Some(end) = self.incoming.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(if eof { Some(false) } else { None });
+        };
+        let status = String::from_utf8_lossy(&self.incoming[..end])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .unwrap_or_default();
+        Ok(Some((200..400).contains(&status)))
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn pool(balance: Balance) -> UpstreamPool {
+        let mut upstream = Upstream::single("127.0.0.1:3000");
+        upstream.servers.push("127.0.0.1:3001".to_string());
+        upstream.servers.push("127.0.0.1:3002".to_string());
+        upstream.balance = balance;
+        UpstreamPool::new(upstream)
+    }
+
+    #[test]
+    fn test_proxy_pass_target() {
+        let pass = ProxyPass::parse("http://api/v1").unwrap();
+        assert_eq!(pass.authority, "api");
+        assert_eq!(pass.target("/api", "/api/users?page=2"), "/v1/users?page=2");
+        assert_eq!(pass.target("/api/", "/api"), "/v1");
+
+        let root = ProxyPass::parse("http://127.0.0.1:3000/").unwrap();
+        assert_eq!(root.target("/api", "/api/users"), "/users");
+        assert_eq!(root.target("/api", "/api?x=1"), "/?x=1");
+
+        let unchanged = ProxyPass::parse("http://api").unwrap();
+        assert_eq!(unchanged.path, None);
+        assert_eq!(unchanged.target("/api", "/api/users"), "/api/users");
+
+        assert!(ProxyPass::parse("https://api").is_err());
+        assert!(ProxyPass::parse("http:///v1").is_err());
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let mut request = Request::default();
+        request.method = "POST".to_string();
+        request.remote_addr = "192.168.1.20:51234".to_string();
+        request.body_byte = b"POST /api HTTP/1.1\r\n\r\nid=1".to_vec();
+        for (name, value) in [
+            ("Connection", "keep-alive"),
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("X-Forwarded-For", "10.0.0.1"),
+            ("Content-Length", "99"),
+        ] {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+
+        let bytes = Proxy::encode_request(&request, "/v1", "api.local");
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("POST /v1 HTTP/1.1\r\nHost: api.local\r\n"));
+        assert!(text.contains("Content-Type: application/x-www-form-urlencoded\r\n"));
+        assert!(text.contains("X-Forwarded-For: 10.0.0.1, 192.168.1.20\r\n"));
+        assert!(text.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(text.contains("Connection: close\r\n"));
+        assert!(!text.contains("keep-alive"));
+        assert!(text.ends_with("Content-Length: 4\r\n\r\nid=1"));
+
+        // Corps incomplet : la longueur annoncée est transmise, la suite sera relayée
+        request.content_length = Some(10);
+        let text = String::from_utf8(Proxy::encode_request(&request, "/v1", "api.local")).unwrap();
+        assert!(text.ends_with("Content-Length: 10\r\n\r\nid=1"));
+    }
+
+    #[test]
+    fn test_parse_head() {
+        assert_eq!(Proxy::parse_head(b"HTTP/1.1 200 OK\r\nServer: x").unwrap(), None);
+
+        let (head, size) = Proxy::parse_head(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nabc"
+        )
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, 45);
+        assert_eq!(head.status, 404);
+        assert_eq!(head.reason, "Not Found");
+        assert_eq!(head.headers, vec![("Content-Length".to_string(), "3".to_string())]);
+
+        assert!(Proxy::parse_head(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        assert!(Proxy::parse_head(b"HTTP/1.1 200 OK\r\nbroken\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_round_robin_and_failures() {
+        let mut pool = pool(Balance::RoundRobin);
+        let picks = (0..4).map(|_| pool.pick(&[]).unwrap()).collect::<Vec<usize>>();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+
+        // max_fails = 1 : le serveur 1 est écarté après un échec
+        assert!(pool.failure(1));
+        assert_eq!(pool.pick(&[]), Some(2));
+        assert_eq!(pool.pick(&[]), Some(0));
+        assert_eq!(pool.pick(&[0, 2]), None);
+
+        // Une vérification active réussie le rétablit
+        assert!(!pool.set_health(1, true));
+        assert_eq!(pool.pick(&[0, 2]), Some(1));
+        assert!(pool.set_health(2, false));
+        assert_eq!(pool.pick(&[0, 1]), None);
+
+        // Un serveur seul n'est jamais écarté par les échecs
+        let mut single = UpstreamPool::new(Upstream::single("127.0.0.1:3000"));
+        assert!(!single.failure(0));
+        assert_eq!(single.pick(&[]), Some(0));
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let mut pool = pool(Balance::LeastConn);
+        pool.peers[0].active = 2;
+        pool.peers[1].active = 1;
+        pool.peers[2].active = 1;
+        assert_eq!(pool.pick(&[]), Some(1));
+        pool.peers[1].active += 1;
+        assert_eq!(pool.pick(&[]), Some(2));
+        assert_eq!(pool.pick(&[2]), Some(0));
+    }
+
+    #[test]
+    fn test_request_with_upstream() {
+        // Serveur amont : répond en corps délimité par la fermeture
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap().to_string();
+        let backend = std::thread::spawn(move || {
+            let (mut socket, _) = upstream.accept().unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+            socket.write_all(b"HTTP/1.1 201 Created\r\nX-App: demo\r\nConnection: close\r\n\r\n").unwrap();
+            socket.write_all(b"bonjour").unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let client_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let mut stream = TcpStream::from_std(client_listener.accept().unwrap().0);
+
+        let server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            String::new(),
+            String::new(),
+            5000,
+            vec!["GET".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        let mut request = Request::default();
+        request.method = "GET".to_string();
+        request.uri = "/api/users".to_string();
+        request.location = "/api/users".to_string();
+        request.remote_addr = "127.0.0.1:40000".to_string();
+        let route: Route = toml::from_str(
+            &format!("path = \"/api\"\nproxy_pass = \"http://{}/v1\"", address)
+        ).unwrap();
+        let job = ProxyJob::new(&server, &request, "", &route).unwrap();
+        assert_eq!(job.upstream, address);
+
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut proxy = ProxyRequest::start(&job, 0, &address, vec![0]).unwrap();
+        proxy.register(poll.registry(), Token(1), Token(2)).unwrap();
+        let config = Config::new();
+        let mut state = CgiState::Running;
+        while state == CgiState::Running {
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            assert!(!events.is_empty(), "le serveur amont ne répond pas");
+            state = proxy.on_event(&mut stream, &config).unwrap();
+        }
+        let received = backend.join().unwrap();
+        assert!(received.starts_with(&format!("GET /v1/users HTTP/1.1\r\nHost: {}\r\n", address)));
+        assert!(received.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+        assert_eq!(proxy.status, 201);
+
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.contains("X-App: demo\r\n"));
+        assert!(response.contains("Connection: keep-alive\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n7\r\nbonjour\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_request_body_streamed() {
+        // Serveur amont : attend tout le corps annoncé avant de répondre
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap().to_string();
+        let backend = std::thread::spawn(move || {
+            let (mut socket, _) = upstream.accept().unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"id=1&name=ana") {
+                let n = socket.read(&mut buffer).unwrap();
+                assert!(n > 0, "corps incomplet");
+                received.extend_from_slice(&buffer[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let client_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let mut stream = TcpStream::from_std(client_listener.accept().unwrap().0);
+
+        let server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            String::new(),
+            String::new(),
+            5000,
+            vec!["POST".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        let mut request = Request::default();
+        request.method = "POST".to_string();
+        request.uri = "/api".to_string();
+        request.location = "/api".to_string();
+        request.content_length = Some(13);
+        request.body_byte = b"POST /api HTTP/1.1\r\n\r\nid=1".to_vec();
+        let route: Route = toml::from_str(&format!("path = \"/api\"\nproxy_pass = \"http://{}\"", address)).unwrap();
+        let job = ProxyJob::new(&server, &request, "", &route).unwrap();
+        assert_eq!(job.body_remaining, 9);
+
+        // La suite du corps arrive après le lancement de la requête
+        client.write_all(b"&name=ana").unwrap();
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut proxy = ProxyRequest::start(&job, 0, &address, vec![0]).unwrap();
+        proxy.register(poll.registry(), Token(1), Token(2)).unwrap();
+        let config = Config::new();
+        let mut state = CgiState::Running;
+        while state == CgiState::Running {
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            assert!(!events.is_empty(), "le serveur amont ne répond pas");
+            state = proxy.on_event(&mut stream, &config).unwrap();
+        }
+        let received = backend.join().unwrap();
+        assert!(received.contains("Content-Length: 13\r\n"));
+        assert!(!proxy.expects_body());
+        assert!(!proxy.retryable());
+
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok"));
+    }
+}
@@ -0,0 +1,526 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+// -------------------------------------------------------------------------------------
+// REDIRECTION
+// -------------------------------------------------------------------------------------
+/// Façon de comparer `source` au chemin de la requête.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchType {
+    /// Le chemin doit être identique à `source`.
+    #[default]
+    Exact,
+    /// Le chemin commence par `source`, le reste est ajouté à `target`.
+    Prefix,
+    /// `source` est une expression régulière, `target` peut utiliser `$1`, `${nom}`...
+    Regex,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Redirection {
+    pub source: String,
+    pub target: String,
+    #[serde(default = "Redirection::default_code")]
+    pub code: u16,
+    #[serde(default, rename = "match")]
+    pub match_type: MatchType,
+    #[serde(default = "Redirection::default_preserve_query")]
+    pub preserve_query: bool,
+    /// `source` compilée au chargement de la configuration (`match = "regex"`).
+    #[serde(skip)]
+    pub regex: OnceLock<Result<Regex, regex::Error>>,
+}
+
+impl Redirection {
+    pub const ALLOWED_CODES: [u16; 5] = [301, 302, 303, 307, 308];
+
+    fn default_code() -> u16 {
+        302
+    }
+
+    fn default_preserve_query() -> bool {
+        true
+    }
+
+    /// Code HTTP envoyé ; une valeur non supportée retombe sur 302.
+    pub fn status_code(&self) -> u16 {
+        match Self::ALLOWED_CODES.contains(&self.code) {
+            true => self.code,
+            false => 302,
+        }
+    }
+
+    /// Une cible absolue (`http://`, `https://`) renvoie le client vers un autre hôte.
+    pub fn is_external(&self) -> bool {
+        is_absolute_url(&self.target)
+    }
+
+    /// Compile `source` une fois pour toutes ; les requêtes réutilisent le résultat.
+    pub fn compile(&self) -> Result<(), regex::Error> {
+        compile_source(&self.regex, self.match_type, &self.source)
+    }
+
+    /// Renvoie la cible si `path` (sans query string) correspond à la source.
+    pub fn resolve(&self, path: &str, query: Option<&str>) -> Result<Option<String>, regex::Error> {
+        let target = resolve_target(self.match_type, &self.source, &self.regex, &self.target, path)?;
+
+        Ok(target.map(|target| match self.preserve_query {
+            true => append_query(target, query),
+            false => target,
+        }))
+    }
+
+    /// Suit les redirections internes à partir de `location`.
+    /// Le premier élément est le saut envoyé au client, les suivants servent à repérer
+    /// une boucle ou une chaîne plus longue que `max_depth`.
+    pub fn chain<'a>(
+        redirections: &'a [Redirection],
+        location: &str,
+        max_depth: usize
+    ) -> Result<Vec<(&'a Redirection, String)>, RuleError> {
+        let mut chain: Vec<(&Redirection, String)> = vec![];
+        let mut visited = HashSet::new();
+        let mut current = location.to_string();
+        visited.insert(split_location(&current).0.to_string());
+
+        loop {
+            let (path, query) = split_location(&current);
+            let mut next = None;
+            for redirection in redirections {
+                if let Some(target) = redirection.resolve(path, query).map_err(RuleError::Regex)? {
+                    next = Some((redirection, target));
+                    break;
+                }
+            }
+
+            let Some((redirection, target)) = next else {
+                return Ok(chain);
+            };
+            chain.push((redirection, target.clone()));
+            if redirection.is_external() {
+                return Ok(chain);
+            }
+
+            let steps = || {
+                let mut steps = vec![location.to_string()];
+                steps.extend(chain.iter().map(|(_, target)| target.clone()));
+                steps
+            };
+            if !visited.insert(split_location(&target).0.to_string()) {
+                return Err(RuleError::Loop(steps()));
+            }
+            if chain.len() > max_depth {
+                return Err(RuleError::TooDeep(steps()));
+            }
+            current = target;
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// REWRITE
+// -------------------------------------------------------------------------------------
+/// Comportement après une réécriture qui a correspondu.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RewriteFlag {
+    /// Arrête cette passe et recommence avec le nouveau chemin.
+    Last,
+    /// Arrête toutes les réécritures.
+    Break,
+}
+
+/// Réécriture interne : le chemin change côté serveur, sans réponse au client.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Rewrite {
+    pub source: String,
+    pub target: String,
+    #[serde(default = "Rewrite::default_match_type", rename = "match")]
+    pub match_type: MatchType,
+    #[serde(default)]
+    pub flag: Option<RewriteFlag>,
+    #[serde(default = "Redirection::default_preserve_query")]
+    pub append_query: bool,
+    /// `source` compilée au chargement de la configuration (`match = "regex"`).
+    #[serde(skip)]
+    pub regex: OnceLock<Result<Regex, regex::Error>>,
+}
+
+impl Rewrite {
+    fn default_match_type() -> MatchType {
+        MatchType::Regex
+    }
+
+    /// Compile `source` une fois pour toutes ; les requêtes réutilisent le résultat.
+    pub fn compile(&self) -> Result<(), regex::Error> {
+        compile_source(&self.regex, self.match_type, &self.source)
+    }
+
+    pub fn resolve(&self, path: &str, query: Option<&str>) -> Result<Option<String>, regex::Error> {
+        let target = resolve_target(self.match_type, &self.source, &self.regex, &self.target, path)?;
+
+        Ok(target.map(|target| match self.append_query {
+            true => append_query(target, query),
+            false => target,
+        }))
+    }
+
+    /// Applique une liste de réécritures à `location` (chemin + query string).
+    /// Renvoie `Ok(None)` si aucune règle n'a changé le chemin. Au-delà de `max_passes`
+    /// passes `last`, ou si une passe repart d'un chemin déjà vu, la réécriture échoue.
+    pub fn apply_all(
+        rewrites: &[Rewrite],
+        location: &str,
+        max_passes: usize
+    ) -> Result<Option<String>, RuleError> {
+        let mut current = location.to_string();
+        let mut steps = vec![current.clone()];
+        let mut visited = HashSet::new();
+        let mut changed = false;
+
+        for _ in 0..max_passes.max(1) {
+            if !visited.insert(split_location(&current).0.to_string()) {
+                return Err(RuleError::Loop(steps));
+            }
+            let mut restart = false;
+
+            for rewrite in rewrites {
+                let (path, query) = split_location(&current);
+                if let Some(target) = rewrite.resolve(path, query).map_err(RuleError::Regex)? {
+                    current = target;
+                    steps.push(current.clone());
+                    changed = true;
+                    match rewrite.flag {
+                        Some(RewriteFlag::Break) => return Ok(Some(current)),
+                        Some(RewriteFlag::Last) => {
+                            restart = true;
+                            break;
+                        }
+                        None => (),
+                    }
+                }
+            }
+
+            if !restart {
+                return Ok(changed.then_some(current));
+            }
+        }
+
+        Err(RuleError::TooDeep(steps))
+    }
+}
+
+/// Erreur rencontrée en appliquant des redirections ou des réécritures.
+#[derive(Debug)]
+pub enum RuleError {
+    Regex(regex::Error),
+    /// Le chemin revient sur une étape déjà visitée.
+    Loop(Vec<String>),
+    /// La chaîne dépasse la profondeur maximale configurée.
+    TooDeep(Vec<String>),
+}
+
+impl RuleError {
+    pub fn describe(&self, kind: &str) -> String {
+        match self {
+            RuleError::Regex(e) => format!("expression régulière invalide dans les {} : {}", kind, e),
+            RuleError::Loop(steps) => format!("cycle de {} : {}", kind, steps.join(" -> ")),
+            RuleError::TooDeep(steps) =>
+                format!("chaîne de {} trop longue : {}", kind, steps.join(" -> ")),
+        }
+    }
+}
+
+/// Analyse le graphe des redirections et des réécritures d'un serveur au chargement
+/// de la configuration. Chaque source littérale et chaque cible sert de point de départ.
+pub fn find_rule_cycles(
+    redirections: &[Redirection],
+    rewrites: &[Rewrite],
+    max_redirects: usize,
+    max_rewrites: usize
+) -> Vec<String> {
+    let mut errors = vec![];
+    let mut reported: Vec<HashSet<String>> = vec![];
+    let mut report = |error: RuleError, kind: &str| {
+        let nodes = match &error {
+            RuleError::Loop(steps) | RuleError::TooDeep(steps) => steps
+                .iter()
+                .map(|step| split_location(step).0.to_string())
+                .collect::<HashSet<_>>(),
+            RuleError::Regex(e) => HashSet::from([e.to_string()]),
+        };
+        if !reported.contains(&nodes) {
+            reported.push(nodes);
+            errors.push(error.describe(kind));
+        }
+    };
+
+    let redirect_seeds = seeds(
+        redirections.iter().map(|r| (r.match_type, r.source.as_str(), r.target.as_str()))
+    );
+    for seed in redirect_seeds {
+        if let Err(e) = Redirection::chain(redirections, &seed, max_redirects) {
+            report(e, "redirections");
+        }
+    }
+
+    let rewrite_seeds = seeds(
+        rewrites.iter().map(|r| (r.match_type, r.source.as_str(), r.target.as_str()))
+    );
+    for seed in rewrite_seeds {
+        if let Err(e) = Rewrite::apply_all(rewrites, &seed, max_rewrites) {
+            report(e, "réécritures");
+        }
+    }
+
+    errors
+}
+
+/// Chemins concrets à partir desquels simuler les règles : sources non régulières
+/// et cibles sans capture.
+fn seeds<'a>(rules: impl Iterator<Item = (MatchType, &'a str, &'a str)>) -> Vec<String> {
+    let mut seeds = vec![];
+    let mut seen = HashSet::new();
+    for (match_type, source, target) in rules {
+        if match_type != MatchType::Regex && seen.insert(source) {
+            seeds.push(source.to_string());
+        }
+        if !target.contains('$') && !is_absolute_url(target) && seen.insert(target) {
+            seeds.push(target.to_string());
+        }
+    }
+    seeds
+}
+
+/// Sépare `location` en chemin et query string.
+pub fn split_location(location: &str) -> (&str, Option<&str>) {
+    match location.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (location, None),
+    }
+}
+
+/// Expression d'une règle, compilée au premier appel puis conservée avec la règle.
+fn compiled<'a>(regex: &'a OnceLock<Result<Regex, regex::Error>>, source: &str) -> Result<&'a Regex, regex::Error> {
+    regex.get_or_init(|| Regex::new(source)).as_ref().map_err(Clone::clone)
+}
+
+fn compile_source(
+    regex: &OnceLock<Result<Regex, regex::Error>>,
+    match_type: MatchType,
+    source: &str
+) -> Result<(), regex::Error> {
+    match match_type {
+        MatchType::Regex => compiled(regex, source).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Calcule la cible d'une règle (redirection ou réécriture) pour un chemin donné.
+fn resolve_target(
+    match_type: MatchType,
+    source: &str,
+    regex: &OnceLock<Result<Regex, regex::Error>>,
+    target: &str,
+    path: &str
+) -> Result<Option<String>, regex::Error> {
+    Ok(match match_type {
+        MatchType::Exact => (path == source).then(|| target.to_string()),
+        MatchType::Prefix => strip_path_prefix(path, source).map(|rest| join_path(target, rest)),
+        MatchType::Regex => {
+            compiled(regex, source)?.captures(path).map(|caps| {
+                let mut expanded = String::new();
+                caps.expand(target, &mut expanded);
+                expanded
+            })
+        }
+    })
+}
+
+pub fn is_absolute_url(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://")
+}
+
+/// Retire `prefix` de `path` en respectant les limites de segments (`/doc` ne couvre pas `/docs`).
+pub fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    match rest.is_empty() || rest.starts_with('/') {
+        true => Some(rest),
+        false => None,
+    }
+}
+
+fn join_path(target: &str, rest: &str) -> String {
+    match rest.is_empty() {
+        true => target.to_string(),
+        false => format!("{}{}", target.trim_end_matches('/'), rest),
+    }
+}
+
+/// Ajoute la query string d'origine à une cible qui peut déjà en contenir une.
+pub fn append_query(target: String, query: Option<&str>) -> String {
+    match query {
+        Some(query) if !query.is_empty() => {
+            let separator = if target.contains('?') { '&' } else { '?' };
+            format!("{}{}{}", target, separator, query)
+        }
+        _ => target,
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirection(source: &str, target: &str, match_type: MatchType) -> Redirection {
+        Redirection {
+            source: source.to_string(),
+            target: target.to_string(),
+            code: 302,
+            match_type,
+            preserve_query: true,
+            regex: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn test_exact_ignores_query_string() {
+        let r = redirection("/mouton", "/", MatchType::Exact);
+        assert_eq!(r.resolve("/mouton", Some("a=1")).unwrap(), Some("/?a=1".to_string()));
+        assert_eq!(r.resolve("/mouton/x", None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_prefix_keeps_remaining_path() {
+        let r = redirection("/docs/", "/manual", MatchType::Prefix);
+        assert_eq!(r.resolve("/docs/a/b.html", None).unwrap(), Some("/manual/a/b.html".to_string()));
+        assert_eq!(r.resolve("/docs", None).unwrap(), Some("/manual".to_string()));
+        assert_eq!(r.resolve("/docsxyz", None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_regex_captures() {
+        let mut r = redirection(
+            r"^/old/(\d+)/(?<page>.*)$",
+            "https://example.com/new/$1/${page}?from=old",
+            MatchType::Regex
+        );
+        // Compilée au chargement, l'expression est gardée avec la règle
+        r.compile().unwrap();
+        assert!(matches!(r.regex.get(), Some(Ok(_))));
+        assert_eq!(
+            r.resolve("/old/42/intro", Some("lang=fr")).unwrap(),
+            Some("https://example.com/new/42/intro?from=old&lang=fr".to_string())
+        );
+        assert!(r.is_external());
+
+        r.preserve_query = false;
+        assert_eq!(
+            r.resolve("/old/1/x", Some("lang=fr")).unwrap(),
+            Some("https://example.com/new/1/x?from=old".to_string())
+        );
+    }
+
+    fn rewrite(source: &str, target: &str, flag: Option<RewriteFlag>) -> Rewrite {
+        Rewrite {
+            source: source.to_string(),
+            target: target.to_string(),
+            match_type: MatchType::Regex,
+            flag,
+            append_query: true,
+            regex: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_with_captures_and_query() {
+        let rewrites = vec![rewrite(r"^/docs/(v\d+)/(.*)$", "/archive/$1/$2.html", None)];
+        assert_eq!(
+            Rewrite::apply_all(&rewrites, "/docs/v1/foo?lang=fr", 10).unwrap(),
+            Some("/archive/v1/foo.html?lang=fr".to_string())
+        );
+        assert_eq!(Rewrite::apply_all(&rewrites, "/autre", 10).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rewrite_flags() {
+        let chain = vec![
+            rewrite("^/a$", "/b", Some(RewriteFlag::Break)),
+            rewrite("^/b$", "/c", None),
+        ];
+        assert_eq!(Rewrite::apply_all(&chain, "/a", 10).unwrap(), Some("/b".to_string()));
+
+        // Sans drapeau, la règle suivante voit le chemin réécrit
+        let chain = vec![rewrite("^/a$", "/b", None), rewrite("^/b$", "/c", None)];
+        assert_eq!(Rewrite::apply_all(&chain, "/a", 10).unwrap(), Some("/c".to_string()));
+
+        // `last` recommence la passe depuis la première règle
+        let chain = vec![rewrite("^/c$", "/d", None), rewrite("^/a$", "/c", Some(RewriteFlag::Last))];
+        assert_eq!(Rewrite::apply_all(&chain, "/a", 10).unwrap(), Some("/d".to_string()));
+    }
+
+    #[test]
+    fn test_rewrite_loop_is_bounded() {
+        let chain = vec![
+            rewrite("^/a$", "/b", Some(RewriteFlag::Last)),
+            rewrite("^/b$", "/a", Some(RewriteFlag::Last)),
+        ];
+        assert!(matches!(Rewrite::apply_all(&chain, "/a", 10), Err(RuleError::Loop(_))));
+    }
+
+    #[test]
+    fn test_redirect_chain() {
+        let redirections = vec![
+            redirection("/a", "/b", MatchType::Exact),
+            redirection("/b", "/c", MatchType::Exact),
+        ];
+        let chain = Redirection::chain(&redirections, "/a", 10).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].1, "/b");
+        assert!(matches!(Redirection::chain(&redirections, "/a", 1), Err(RuleError::TooDeep(_))));
+        assert!(Redirection::chain(&redirections, "/z", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_rule_cycles() {
+        let redirections = vec![
+            redirection("/mouton", "/", MatchType::Exact),
+            redirection("/a", "/b", MatchType::Exact),
+            redirection("/b", "/a", MatchType::Exact),
+            redirection(r"^/x/(.*)$", "/y/$1", MatchType::Regex),
+            redirection("/y/", "/x/", MatchType::Prefix),
+        ];
+        let errors = find_rule_cycles(&redirections, &[], 10, 10);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("/a -> /b -> /a"));
+
+        let rewrites = vec![rewrite("^/p$", "/q", Some(RewriteFlag::Last))];
+        assert!(find_rule_cycles(&redirections[..1], &rewrites, 10, 10).is_empty());
+
+        // Chaque chemin n'est simulé qu'une fois, même s'il revient plus loin dans la liste
+        let seeds = seeds(redirections.iter().map(|r| (r.match_type, r.source.as_str(), r.target.as_str())));
+        assert_eq!(seeds, vec!["/mouton", "/", "/a", "/b", "/y/", "/x/"]);
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let r = redirection("^/(", "/b", MatchType::Regex);
+        assert!(r.compile().is_err());
+        assert!(r.resolve("/a", None).is_err());
+        assert!(redirection("^/(", "/b", MatchType::Exact).compile().is_ok());
+    }
+
+    #[test]
+    fn test_status_code() {
+        let mut r = redirection("/a", "/b", MatchType::Exact);
+        r.code = 308;
+        assert_eq!(r.status_code(), 308);
+        r.code = 200;
+        assert_eq!(r.status_code(), 302);
+    }
+}
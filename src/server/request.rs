@@ -1,3 +1,4 @@
+use super::Session;
 use crate::{ get_boundary, get_content_length, remove_prefix, remove_suffix };
 use chrono::Utc;
 use mio::net::TcpStream;
@@ -28,7 +29,19 @@ pub struct Request {
     pub boundary: Option<String>,
     pub complete: bool,
     pub headers: HashMap<String, String>,
+    /// Cookies envoyés par le client, tous en-têtes `Cookie` confondus.
+    pub cookies: HashMap<String, String>,
     pub timestamp: i64,
+    /// Cible brute de la ligne de requête, avant décodage et réécriture.
+    pub uri: String,
+    /// Adresse `ip:port` du client.
+    pub remote_addr: String,
+    /// Session du client, rattachée par le Router avant le traitement.
+    pub session: Option<Session>,
+    /// Utilisateur authentifié (`auth_basic`, `login`, claim `sub` d'`auth_jwt`).
+    pub remote_user: Option<String>,
+    /// Claims du jeton JWT vérifié (`auth_jwt`).
+    pub claims: HashMap<String, String>,
 }
 
 impl Request {
@@ -63,10 +76,126 @@ impl Request {
             boundary: None,
             complete: false,
             headers: HashMap::new(),
+            cookies: HashMap::new(),
             timestamp: Utc::now().timestamp_millis(),
+            uri: String::new(),
+            remote_addr: String::new(),
+            session: None,
+            remote_user: None,
+            claims: HashMap::new(),
         }
     }
 
+    /// Chemin de la requête, sans la query string.
+    pub fn path(&self) -> &str {
+        self.location.split('?').next().unwrap_or_default()
+    }
+
+    /// Query string de la requête (ce qui suit le premier `?`).
+    pub fn query(&self) -> Option<&str> {
+        self.location.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Query string telle qu'envoyée par le client (non décodée).
+    pub fn raw_query(&self) -> &str {
+        self.uri.split_once('?').map(|(_, query)| query).unwrap_or_default()
+    }
+
+    /// Valeur d'un en-tête, sans tenir compte de la casse du nom.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Valeur du cookie `name` envoyé par le client.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies.get(name).cloned()
+    }
+
+    /// Ajoute les paires `nom=valeur` d'un en-tête `Cookie` (RFC 6265, 5.4). La valeur
+    /// peut contenir `=` et perd ses guillemets ; en cas de doublon, la première est gardée.
+    pub fn parse_cookies(header: &str, cookies: &mut HashMap<String, String>) {
+        for pair in header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+        }
+    }
+
+    /// Données de la session du client (vides sans session).
+    pub fn session_data(&self) -> HashMap<String, String> {
+        self.session
+            .as_ref()
+            .map(|session| session.data.clone())
+            .unwrap_or_default()
+    }
+
+    /// Corps de la requête en octets : `body_byte` contient aussi les en-têtes reçus.
+    pub fn body_bytes(&self) -> &[u8] {
+        let pattern = b"\r\n\r\n";
+        match self.body_byte.windows(pattern.len()).position(|w| w == pattern) {
+            Some(pos) => &self.body_byte[pos + pattern.len()..],
+            None => &[],
+        }
+    }
+
+    /// Remplace la cible de la requête (chemin + query string) et met à jour la ligne de requête.
+    pub fn set_location(&mut self, location: &str) {
+        self.location = location.to_string();
+        let re = Regex::new(r"^(?<method>[A-Z]+) /(?<location>\S+)").unwrap();
+        self.head = re.replace_all(&self.head, format!("$method {}", location)).to_string();
+    }
+
+    /// Chemin sans `//`, `.` ni `..`, en gardant la barre finale des dossiers ;
+    /// `None` si un `..` remonte au-dessus de la racine.
+    pub fn normalize_path(path: &str) -> Option<String> {
+        let mut segments = vec![];
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => (),
+                ".." => {
+                    segments.pop()?;
+                }
+                segment => segments.push(segment),
+            }
+        }
+        let mut normalized = format!("/{}", segments.join("/"));
+        let directory = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+        if directory && !segments.is_empty() {
+            normalized.push('/');
+        }
+        Some(normalized)
+    }
+
+    /// Normalise le chemin de la requête (déjà décodé) avant toute recherche de route,
+    /// pour que `//admin` ou `/./admin` ne contournent pas la configuration de `/admin`.
+    /// Renvoie `false` si le chemin sort de la racine.
+    pub fn normalize_location(&mut self) -> bool {
+        let Some(path) = Self::normalize_path(self.path()) else {
+            return false;
+        };
+        if path != self.path() {
+            let location = match self.query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            self.set_location(&location);
+        }
+        true
+    }
+
     pub fn default() -> Self {
         Request::new(
             String::new(),
@@ -277,7 +406,6 @@ impl Request {
         let mut location = String::new();
         let mut host = String::new();
         let mut port: u16 = 0;
-        let mut cookie = String::new();
         let mut headers = HashMap::new();
 
         let lines: Vec<&str> = request_str.lines().collect();
@@ -302,11 +430,16 @@ impl Request {
                 let mut parts = line.splitn(2, ":");
                 if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
                     let key = key.trim().trim_matches('"').to_string(); // Supprimer les espaces et les guillemets
-                    if key == "Cookie" {
-                        cookie = value.to_owned();
-                    }
                     let value = value.trim().to_string(); // Supprimer les espaces
-                    if !key.is_empty() && !value.is_empty() {
+                    if key.eq_ignore_ascii_case("Cookie") {
+                        // Plusieurs en-têtes Cookie sont réunis, comme le fait HTTP/2
+                        Self::parse_cookies(&value, &mut request.cookies);
+                        if let Some(previous) = headers.get_mut("Cookie") {
+                            *previous = format!("{}; {}", previous, value);
+                            continue;
+                        }
+                        headers.insert("Cookie".to_string(), value);
+                    } else if !key.is_empty() && !value.is_empty() {
                         headers.insert(key, value);
                     }
                 }
@@ -316,12 +449,9 @@ impl Request {
         let binding = Self::extract_header_value(&lines, "Referer:");
         let referer = binding.split(":").nth(1).unwrap_or_default();
 
+        request.uri = location.clone();
         request.location = location;
-        request.id_session = cookie
-            .trim()
-            .strip_prefix("cookie_01=")
-            .unwrap_or_default()
-            .to_owned();
+        request.headers = headers;
         request.host = host;
         request.port = port;
         request.length = request.body.len();
@@ -394,3 +524,36 @@ impl Request {
         self.head = re.replace_all(&self.head, format!("$method {}", self.location)).to_string();
     }
 }
+// -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(Request::normalize_path("//admin/x").as_deref(), Some("/admin/x"));
+        assert_eq!(Request::normalize_path("/./admin//x/").as_deref(), Some("/admin/x/"));
+        assert_eq!(Request::normalize_path("/d/../admin/.").as_deref(), Some("/admin/"));
+        assert_eq!(Request::normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(Request::normalize_path("/../etc/passwd"), None);
+
+        let mut request = Request::default();
+        request.location = "/%61dmin//x?a=1".to_string();
+        request.uri_decode();
+        assert!(request.normalize_location());
+        assert_eq!(request.location, "/admin/x?a=1");
+    }
+
+    #[test]
+    fn test_parse_cookies() {
+        let mut request = Request::default();
+        Request::parse_http_request(
+            "GET / HTTP/1.1\r\nHost: localhost:8080\r\nCookie: theme=dark; cookie_01=abc.c2ln==\r\ncookie: lang=\"fr\"; theme=light\r\n\r\n",
+            &mut request
+        );
+        assert_eq!(request.cookie("cookie_01").as_deref(), Some("abc.c2ln=="));
+        assert_eq!(request.cookie("lang").as_deref(), Some("fr"));
+        assert_eq!(request.cookie("theme").as_deref(), Some("dark"));
+        assert_eq!(request.headers["Cookie"], "theme=dark; cookie_01=abc.c2ln==; lang=\"fr\"; theme=light");
+    }
+}
@@ -1,32 +1,139 @@
+use chrono::Utc;
+use std::io::{ self, Write };
+
 // -------------------------------------------------------------------------------------
 // RESPONSE
 // -------------------------------------------------------------------------------------
+/// Valeur de l'en-tête `Server` envoyé avec chaque réponse.
+pub const SERVER_SIGNATURE: &str = concat!("localhost/", env!("CARGO_PKG_VERSION"));
+
 pub struct Response {
     pub id_session: String,
     pub status: String,
     pub content_type: String,
-    pub body: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
 }
 
 impl Response {
     /// Crée une nouvelle réponse.
-    pub fn new(id_session: String, status: String, content_type: String, body: String) -> Self {
+    pub fn new(id_session: String, status: String, content_type: String, body: Vec<u8>) -> Self {
         Self {
             id_session,
             status,
             content_type,
+            headers: vec![],
             body,
         }
     }
 
-    /// Convertit la réponse en une chaîne de caractères HTTP valide.
-    pub fn to_http_response(&self) -> String {
-        let headers = format!(
-            "Content-Type: {}\r\nContent-Length: {}\r\n",
-            self.content_type,
-            self.body.len()
-        );
-        format!("HTTP/1.1 {}\r\n{}\r\n{}", self.status, headers, self.body)
+    /// Crée une réponse à partir d'un code HTTP (`200` -> `200 OK`).
+    pub fn with_code(code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self::new(
+            String::new(),
+            format!("{} {}", code, Self::reason_phrase(code)),
+            content_type.to_string(),
+            body,
+        )
+    }
+
+    /// Code numérique extrait de la ligne de statut.
+    pub fn status_code(&self) -> u16 {
+        self.status
+            .split_whitespace()
+            .next()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(500)
+    }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remplace un en-tête existant ou l'ajoute.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.add_header(name, value);
+    }
+
+    /// Date au format HTTP (RFC 7231, IMF-fixdate).
+    pub fn http_date() -> String {
+        Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    /// Ligne de statut et en-têtes. `Content-Length` est omis pour une réponse 1xx,
+    /// une réponse envoyée en `Transfer-Encoding: chunked` ou qui fixe déjà sa longueur.
+    pub fn to_http_head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        if !self.content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {}\r\n", self.content_type));
+        }
+        let informational = self.status_code() < 200;
+        if !informational && !self.has_header("Transfer-Encoding") && !self.has_header("Content-Length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // id_session contient déjà la ligne `Set-Cookie: ...\r\n` complète
+        head.push_str(&self.id_session);
+        head.push_str("\r\n");
+        head
+    }
+
+    /// Convertit la réponse en une suite d'octets HTTP valide.
+    pub fn to_http_response(&self) -> Vec<u8> {
+        let mut bytes = self.to_http_head().into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// Encode un morceau de corps pour `Transfer-Encoding: chunked`.
+    pub fn chunk(data: &[u8]) -> Vec<u8> {
+        let mut bytes = format!("{:x}\r\n", data.len()).into_bytes();
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+
+    /// Phrase associée à un code de statut HTTP.
+    pub fn reason_phrase(code: u16) -> &'static str {
+        match code {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Content Too Large",
+            415 => "Unsupported Media Type",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            508 => "Loop Detected",
+            _ => "Unknown",
+        }
     }
 
     // -------------------------------------------------------------------------------------
@@ -39,7 +146,8 @@ impl Response {
             id_session: String::new(), // Pas de session pour les erreurs
             status: "400 Bad Request".to_string(),
             content_type: "text/plain".to_string(),
-            body: "400 Bad Request: The request could not be understood by the server.".to_string(),
+            headers: vec![],
+            body: "400 Bad Request: The request could not be understood by the server.".into(),
         }
     }
 
@@ -49,7 +157,8 @@ impl Response {
             id_session: String::new(),
             status: "404 Not Found".to_string(),
             content_type: "text/plain".to_string(),
-            body: "404 Not Found: The requested resource was not found.".to_string(),
+            headers: vec![],
+            body: "404 Not Found: The requested resource was not found.".into(),
         }
     }
 
@@ -59,7 +168,8 @@ impl Response {
             id_session: String::new(),
             status: "500 Internal Server Error".to_string(),
             content_type: "text/plain".to_string(),
-            body: "500 Internal Server Error: The server encountered an unexpected condition.".to_string(),
+            headers: vec![],
+            body: "500 Internal Server Error: The server encountered an unexpected condition.".into(),
         }
     }
 
@@ -69,7 +179,8 @@ impl Response {
             id_session: String::new(),
             status: "405 Method Not Allowed".to_string(),
             content_type: "text/plain".to_string(),
-            body: "405 Method Not Allowed: The requested method is not allowed for this resource.".to_string(),
+            headers: vec![],
+            body: "405 Method Not Allowed: The requested method is not allowed for this resource.".into(),
         }
     }
 
@@ -79,7 +190,8 @@ impl Response {
             id_session: String::new(),
             status: "401 Unauthorized".to_string(),
             content_type: "text/plain".to_string(),
-            body: "401 Unauthorized: Authentication is required to access this resource.".to_string(),
+            headers: vec![],
+            body: "401 Unauthorized: Authentication is required to access this resource.".into(),
         }
     }
 
@@ -89,7 +201,100 @@ impl Response {
             id_session: String::new(),
             status: "403 Forbidden".to_string(),
             content_type: "text/plain".to_string(),
-            body: "403 Forbidden: You do not have permission to access this resource.".to_string(),
+            headers: vec![],
+            body: "403 Forbidden: You do not have permission to access this resource.".into(),
         }
     }
-}
\ No newline at end of file
+}
+
+// -------------------------------------------------------------------------------------
+// OUTGOING
+// -------------------------------------------------------------------------------------
+/// Octets en attente vers un client non bloquant : ce que le socket refuse
+/// (`WouldBlock`) repart au prochain événement WRITABLE.
+#[derive(Debug, Default)]
+pub struct Outgoing {
+    data: Vec<u8>,
+}
+
+impl Outgoing {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Écrit tout ce que le socket accepte ; le reste est gardé pour plus tard.
+    pub fn flush<W: Write>(&mut self, stream: &mut W) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.data.len() {
+            match stream.write(&self.data[written..]) {
+                Ok(0) => {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(n) => {
+                    written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        self.data.drain(..written);
+        Ok(())
+    }
+}
+
+// -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Socket qui n'accepte que `capacity` octets avant `WouldBlock`.
+    struct SlowClient {
+        received: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for SlowClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.capacity).min(3);
+            self.capacity -= n;
+            self.received.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_outgoing_would_block() {
+        let mut client = SlowClient { received: vec![], capacity: 4 };
+        let mut outgoing = Outgoing::default();
+        outgoing.push(b"0123456789");
+        outgoing.flush(&mut client).unwrap();
+        assert_eq!(client.received, b"0123");
+        assert_eq!(outgoing.len(), 6);
+
+        client.capacity = 100;
+        outgoing.push(b"ab");
+        outgoing.flush(&mut client).unwrap();
+        assert_eq!(client.received, b"0123456789ab");
+        assert!(outgoing.is_empty());
+    }
+}
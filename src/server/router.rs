@@ -1,29 +1,73 @@
 use crate::Config;
-use super::Request;
+use super::{BusyPolicy, CgiJob, CgiProcess, CgiRunner, CgiState, DirectoryEventsJob, DirectoryWatch, drain, FastCgiRequest, FastCgiStream, HealthProbe, Job, LoginJob, ProxyError, ProxyJob, ProxyRequest, Request, ServerError, MemoryStore, SessionStore, SessionUpdate, Upstream, UpstreamPool, WebSocketConnection, WebSocketJob, CLOSE_INTERNAL_ERROR, MAX_IDLE_CONNECTIONS, MAX_WATCHES};
 pub use super::{Server, Session};
-use hostfile::{get_hostfile_path, parse_hostfile, HostEntry};
 use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::{self, Error, ErrorKind, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Error, ErrorKind};
 use std::net::ToSocketAddrs;
+use std::process::Child;
 use std::time::{Duration, Instant};
 
 // -------------------------------------------------------------------------------------
 // ROUTER
 // -------------------------------------------------------------------------------------
 const CLIENT_START: Token = Token(1000); // Token de départ pour les clients
+/// Délai minimal entre deux enregistrements des sessions modifiées.
+const SESSION_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct Router {
     pub servers: Vec<Server>,
-    pub sessions: HashMap<Token, Session>,
+    /// Sessions indexées par leur identifiant (valeur du cookie).
+    pub sessions: HashMap<String, Session>,
+    /// Token d'une connexion -> identifiant de la session de son client.
+    pub client_sessions: HashMap<Token, String>,
     pub listeners: HashMap<Token, TcpListener>, // Associe un token à un TcpListener
     pub clients: HashMap<Token, TcpStream>,     // Associe un token à un TcpStream
     pub next_token: usize,
     pub request_queue: Vec<Request>,
     pub conn_timeout: HashMap<TcpStream, Instant>,
+    /// Scripts CGI en cours, indexés par le token de leur stdout.
+    pub cgi_processes: HashMap<Token, CgiProcess>,
+    /// Token d'un tube (stdin, stdout ou stderr) -> clé dans `cgi_processes`.
+    pub cgi_pipes: HashMap<Token, Token>,
+    /// Scripts dont la sortie est terminée mais qui n'ont pas encore été récupérés.
+    pub cgi_children: Vec<Child>,
+    /// Requêtes CGI en attente d'une place (`max_processes`), avec leur client.
+    pub cgi_queue: VecDeque<(Token, CgiJob)>,
+    /// Requêtes FastCGI en cours, indexées par le token de la connexion au backend.
+    pub fastcgi_requests: HashMap<Token, FastCgiRequest>,
+    /// Connexions inactives vers chaque backend FastCGI (`fastcgi_pass`).
+    pub fastcgi_pool: HashMap<String, Vec<FastCgiStream>>,
+    /// Requêtes relayées vers un serveur amont, indexées par le token de la connexion.
+    pub proxy_requests: HashMap<Token, ProxyRequest>,
+    /// État des upstreams, par nom ou adresse de serveur.
+    pub upstreams: HashMap<String, UpstreamPool>,
+    /// Vérifications actives en cours, indexées par le token de la connexion.
+    pub health_probes: HashMap<Token, HealthProbe>,
+    /// Connexions WebSocket établies, indexées par le token de leur client.
+    pub websockets: HashMap<Token, WebSocketConnection>,
+    /// Token d'un backend WebSocket (connexion TCP, stdin ou stdout) -> client.
+    pub websocket_backends: HashMap<Token, Token>,
+    /// Dossiers surveillés pour un flux SSE, indexés par le token de leur inotify.
+    pub directory_watches: HashMap<Token, DirectoryWatch>,
+    /// Token du client d'un flux SSE -> clé dans `directory_watches`.
+    pub watch_clients: HashMap<Token, Token>,
+    /// Stockage des sessions entre deux exécutions.
+    pub session_store: Box<dyn SessionStore>,
+    /// Prochaine suppression des sessions expirées.
+    pub next_session_sweep: Instant,
+    /// Prochain enregistrement possible des sessions.
+    pub next_session_flush: Instant,
+    /// Sessions modifiées depuis le dernier enregistrement.
+    pub sessions_dirty: bool,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Router {
@@ -31,11 +75,29 @@ impl Router {
         Self {
             servers: vec![],
             sessions: HashMap::new(),
+            client_sessions: HashMap::new(),
             listeners: HashMap::new(),
             clients: HashMap::new(),
             next_token: CLIENT_START.0,
             request_queue: vec![],
             conn_timeout: HashMap::new(),
+            cgi_processes: HashMap::new(),
+            cgi_pipes: HashMap::new(),
+            cgi_children: vec![],
+            cgi_queue: VecDeque::new(),
+            fastcgi_requests: HashMap::new(),
+            fastcgi_pool: HashMap::new(),
+            proxy_requests: HashMap::new(),
+            upstreams: HashMap::new(),
+            health_probes: HashMap::new(),
+            websockets: HashMap::new(),
+            websocket_backends: HashMap::new(),
+            directory_watches: HashMap::new(),
+            watch_clients: HashMap::new(),
+            session_store: Box::new(MemoryStore),
+            next_session_sweep: Instant::now(),
+            next_session_flush: Instant::now(),
+            sessions_dirty: false,
         }
     }
 
@@ -58,12 +120,12 @@ impl Router {
                         "Erreur de résolution de l'adresse {}:{} : {}",
                         addr, port, e
                     );
-                    io::Error::new(io::ErrorKind::Other, "Failed to resolve address")
+                    io::Error::other("Failed to resolve address")
                 })?
                 .next()
                 .ok_or_else(|| {
                     eprintln!("Aucune adresse trouvée pour {}:{}", addr, port);
-                    io::Error::new(io::ErrorKind::Other, "No address found")
+                    io::Error::other("No address found")
                 })?;
             // Lier le TcpListener à l'adresse
             if let Ok(listener) = TcpListener::bind(socket_addr) {
@@ -120,26 +182,60 @@ impl Router {
         }
 
         let mut events = Events::with_capacity(config.log_files.events_limit);
+        self.upstreams = config.http.upstreams
+            .iter()
+            .map(|(name, upstream)| (name.clone(), UpstreamPool::new(upstream.clone())))
+            .collect();
+        self.open_sessions(config);
 
         loop {
-            poll.poll(&mut events, None)?;
+            // Le réveil suit l'échéance du prochain script CGI
+            poll.poll(&mut events, self.next_cgi_timeout())?;
 
             for event in events.iter() {
-                if let Some(_) = server_tokens.get(&event.token()) {
+                if event.is_writable() {
+                    // Le client d'un script CGI ou d'un relais accepte la suite de la réponse
+                    self.resume_client(event.token(), &poll, config);
+                }
+                if server_tokens.contains_key(&event.token()) {
                     // Nouvelle connexion sur un TcpListener
                     self.accept_connection(event.token(), &poll)?;
                     // println!("Nouvelle connexion sur le port {}", addr.port());
+                } else if let Some(&key) = self.cgi_pipes.get(&event.token()) {
+                    // Sortie ou entrée d'un script CGI
+                    self.handle_cgi_event(key, &poll, config);
+                } else if self.fastcgi_requests.contains_key(&event.token()) {
+                    // Connexion vers un backend FastCGI
+                    self.handle_fastcgi_event(event.token(), &poll, config);
+                } else if self.proxy_requests.contains_key(&event.token()) {
+                    // Connexion vers un serveur amont
+                    self.handle_proxy_event(event.token(), &poll, config);
+                } else if self.health_probes.contains_key(&event.token()) {
+                    self.handle_health_event(event.token(), &poll);
+                } else if let Some(&client) = self.websocket_backends.get(&event.token()) {
+                    // Backend d'une connexion WebSocket
+                    self.handle_websocket_backend_event(client, &poll, config);
+                } else if self.websockets.contains_key(&event.token()) {
+                    // Trames d'un client WebSocket
+                    self.handle_websocket_event(event.token(), &poll, config);
+                } else if self.directory_watches.contains_key(&event.token()) {
+                    // Changement dans un dossier suivi par un flux SSE
+                    self.handle_directory_event(event.token(), &poll, config);
+                } else if let Some(&key) = self.watch_clients.get(&event.token()) {
+                    // Client d'un flux SSE : sa déconnexion, ou de la place pour la suite du flux
+                    self.check_watch_client(key, &poll, config);
+                } else if let Some(token) = self.proxy_expecting_body(event.token()) {
+                    // Suite du corps d'une requête relayée vers un serveur amont
+                    self.handle_proxy_event(token, &poll, config);
                 } else if event.is_readable() {
                     // Données reçues sur un TcpStream
-                    let stream = self
-                        .clients
-                        .get_mut(&event.token())
-                        .expect("Erreur lors de la recupération du canal tcpstream");
-                    let mut req = Request::default();
-                    match Request::read_request(stream, &mut poll) {
-                        Ok(request) => {
-                            req = request;
-                        }
+                    // Le token peut appartenir à un client ou un tube CGI déjà retiré
+                    // pendant ce même tour de boucle
+                    let Some(stream) = self.clients.get_mut(&event.token()) else {
+                        continue;
+                    };
+                    let mut req = match Request::read_request(stream, &mut poll) {
+                        Ok(request) => request,
                         Err(_) => {
                             // dbg!("suppresion du client dans self.client");
                             // dbg!("Error found", e);
@@ -148,49 +244,51 @@ impl Router {
                                 eprintln!("Erreur lors de la fermeture du stream: {}", e);
                             }
                             self.clients.remove(&event.token());
+                            self.client_sessions.remove(&event.token());
                             continue;
                         }
                     };
 
                     req.uri_decode();
+                    if let Ok(addr) = stream.peer_addr() {
+                        req.remote_addr = addr.to_string();
+                    }
 
-                    let mut cookie = req.id_session.clone();
-                    // println!("cookie extract: {}",cookie);
-                    let client_token = Token(self.next_token);
-                    self.next_token += 1;
-                    // Tentative de récupération du cookie
-                    if !cookie.is_empty() {
-                        // Recherche d'une session existante avec le même cookie
-                        let mut session_found = false;
-
-                        for (old_token, session) in self.sessions.clone().iter() {
-                            if session.id.trim() == cookie && !session.is_expired() {
-                                let mut new_session = Session::new();
-                                new_session.id = session.id.clone();
-                                self.sessions.remove(&old_token);
-                                self.sessions.insert(client_token.clone(), new_session);
-                                session_found = true;
-                                break;
+                    // Cookie de session du serveur visé, ou celui de `[http.session]`
+                    let settings = self.servers
+                        .iter()
+                        .find(|server| server.serves(&req))
+                        .map_or(&config.http.session.cookie, |server| server.session_cookie(config));
+                    req.id_session = match req.cookie(&settings.cookie_name) {
+                        Some(value) => match settings.decode(&value) {
+                            Ok(id) => id,
+                            Err(message) => {
+                                // Cookie falsifié ou signé avec une clé retirée : nouvelle session
+                                Server::error_log(&req, config, "Router::run", file!(), line!(), ServerError::SessionError { cookie: &value, message });
+                                String::new()
                             }
-                        }
+                        },
+                        None => String::new(),
+                    };
 
-                        if !session_found {
-                            // Si aucune session existante n'est trouvée, créez une nouvelle session
-                            let new_session = Session::new();
-                            self.sessions
-                                .insert(client_token.clone(), new_session.clone());
+                    // Session du cookie si elle est encore valide, sinon une nouvelle session
+                    let session = match self.sessions.get_mut(req.id_session.trim()) {
+                        Some(session) if !session.is_expired() => {
+                            if settings.sliding {
+                                session.touch();
+                                self.sessions_dirty = true;
+                            }
+                            session
                         }
-                    } else {
-                        // Si aucun cookie n'est trouvé, créez une nouvelle session
-                        let new_session = Session::new();
-                        self.sessions
-                            .insert(client_token.clone(), new_session.clone());
-                    }
-
-                    if let Some(session) = self.sessions.get_mut(&client_token) {
-                        cookie =
-                            Session::make_cookie("cookie_01", &session.id, session.expiration_time);
-                    }
+                        _ => {
+                            let session = settings.new_session();
+                            self.sessions_dirty = true;
+                            self.sessions.entry(session.id.clone()).insert_entry(session).into_mut()
+                        }
+                    };
+                    let cookie = settings.header(session);
+                    req.session = Some(session.clone());
+                    self.client_sessions.insert(event.token(), session.id.clone());
 
                     if ["GET", "POST", "DELETE"].contains(&req.method.as_str()) {
                         self.request_queue.push(req.clone());
@@ -222,12 +320,12 @@ impl Router {
                         }
                     }
 
-                    let clien_would_delete = Self::route_request(
+                    let (clien_would_delete, job) = Self::route_request(
                         &mut self.request_queue,
                         self.servers.clone(),
                         stream,
                         cookie,
-                        &config,
+                        config,
                         &mut poll,
                     );
                     if clien_would_delete {
@@ -236,9 +334,1004 @@ impl Router {
                             Server::error_log(&req, config, "Router::run", file!(), line!(), crate::ServerError::IOError(&e));
                         }
                         self.clients.remove(&event.token());
+                        self.client_sessions.remove(&event.token());
                     };
+                    match job {
+                        Some(Job::Cgi(job)) => self.start_cgi(job, event.token(), &poll, config),
+                        Some(Job::Proxy(job)) => self.start_proxy(job, event.token(), vec![], &poll, config),
+                        Some(Job::WebSocket(job)) => self.start_websocket(job, event.token(), &poll, config),
+                        Some(Job::DirectoryEvents(job)) => self.start_directory_events(job, event.token(), &poll, config),
+                        Some(Job::Login(job)) => self.finish_login(job, event.token(), config),
+                        None => (),
+                    }
+                }
+            }
+
+            self.check_cgi_exits(&poll, config);
+            self.check_cgi_timeouts(&poll, config);
+            self.check_proxy_timeouts(&poll, config);
+            self.check_websocket_timeouts(&poll, config);
+            self.check_watch_keep_alive(&poll, config);
+            self.start_health_checks(&poll);
+            self.start_queued_cgi(&poll, config);
+            self.reap_cgi_children();
+            self.maintain_sessions(config);
+        }
+    }
+
+    /// Ouvre le stockage configuré et reprend les sessions encore valides.
+    fn open_sessions(&mut self, config: &Config) {
+        self.session_store = config.http.session.open();
+        match self.session_store.load() {
+            Ok(sessions) => {
+                for session in sessions {
+                    self.sessions.insert(session.id.clone(), session);
+                }
+            }
+            Err(e) => eprintln!("Impossible de charger les sessions : {}", e),
+        }
+        self.next_session_sweep = Instant::now() + Duration::from_millis(config.http.session.sweep_interval);
+    }
+
+    /// Supprime régulièrement les sessions expirées et enregistre les modifications.
+    fn maintain_sessions(&mut self, config: &Config) {
+        let now = Instant::now();
+        if now >= self.next_session_sweep {
+            let count = self.sessions.len();
+            self.sessions.retain(|_, session| !session.is_expired());
+            self.sessions_dirty |= self.sessions.len() != count;
+            self.next_session_sweep = now + Duration::from_millis(config.http.session.sweep_interval);
+        }
+        if self.sessions_dirty && now >= self.next_session_flush {
+            let sessions = self.sessions.values().collect::<Vec<&Session>>();
+            if let Err(e) = self.session_store.save(&sessions) {
+                eprintln!("Impossible d'enregistrer les sessions : {}", e);
+            }
+            self.sessions_dirty = false;
+            self.next_session_flush = now + SESSION_FLUSH_INTERVAL;
+        }
+    }
+
+    /// Lance un script, ou le met en attente si le serveur a atteint `max_processes`.
+    fn start_cgi(&mut self, job: CgiJob, client: Token, poll: &Poll, config: &Config) {
+        // La réponse est mise en file : le client signale quand il peut en recevoir la suite
+        self.watch_client_writes(client, poll, Interest::READABLE | Interest::WRITABLE);
+        if let CgiRunner::FastCgi(address) = &job.script.runner {
+            let address = address.clone();
+            self.start_fastcgi(job, &address, client, poll, config);
+            return;
+        }
+        let sandbox = &job.server.cgi_sandbox;
+        if let Some(max) = sandbox.max_processes {
+            let running = self
+                .cgi_processes
+                .values()
+                .filter(|process| Self::same_server(&process.job.server, &job.server))
+                .count();
+            if running >= max {
+                match sandbox.when_busy {
+                    BusyPolicy::Queue => self.cgi_queue.push_back((client, job)),
+                    BusyPolicy::Reject => self.reject_cgi(&job, client, 503, "Service Unavailable", config),
+                }
+                return;
+            }
+        }
+
+        let mut process = match CgiProcess::start(&job) {
+            Ok(process) => process,
+            Err(e) => {
+                // Interpréteur introuvable, droits insuffisants...
+                Server::error_log(&job.request, config, "Router::start_cgi", file!(), line!(), ServerError::CgiError {
+                    script: &job.script.filename,
+                    uri: &job.request.uri,
+                    message: &format!("impossible de lancer le script : {}", e),
+                });
+                self.reject_cgi(&job, client, 500, "Internal Server Error", config);
+                return;
+            }
+        };
+
+        let tokens = [Token(self.next_token), Token(self.next_token + 1), Token(self.next_token + 2)];
+        self.next_token += 3;
+
+        if let Err(e) = process.register(poll.registry(), client, tokens) {
+            Server::error_log(&process.job.request, config, "Router::start_cgi", file!(), line!(), ServerError::IOError(&e));
+            process.kill();
+            self.cgi_children.push(process.child);
+            return;
+        }
+        for token in tokens {
+            self.cgi_pipes.insert(token, tokens[1]);
+        }
+        self.cgi_processes.insert(tokens[1], process);
+        // Le script a pu écrire avant l'enregistrement : on lit tout de suite
+        self.handle_cgi_event(tokens[1], poll, config);
+    }
+
+    /// Envoie une requête à un backend FastCGI, sur une connexion du pool si possible.
+    fn start_fastcgi(&mut self, job: CgiJob, address: &str, client: Token, poll: &Poll, config: &Config) {
+        let idle = self.fastcgi_pool.get_mut(address).and_then(|pool| {
+            // Le backend a pu fermer une connexion inactive entre-temps
+            while let Some(mut connection) = pool.pop() {
+                if connection.is_reusable() {
+                    return Some(connection);
+                }
+            }
+            None
+        });
+
+        let mut request = match FastCgiRequest::start(&job, address, idle) {
+            Ok(request) => request,
+            Err(e) => {
+                job.log_error(config, &format!("connexion au backend FastCGI {} impossible : {}", address, e));
+                self.reject_cgi(&job, client, 502, "Bad Gateway", config);
+                return;
+            }
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = request.register(poll.registry(), client, token) {
+            Server::error_log(&job.request, config, "Router::start_fastcgi", file!(), line!(), ServerError::IOError(&e));
+            self.reject_cgi(&job, client, 502, "Bad Gateway", config);
+            return;
+        }
+        self.fastcgi_requests.insert(token, request);
+        self.handle_fastcgi_event(token, poll, config);
+    }
+
+    fn handle_fastcgi_event(&mut self, token: Token, poll: &Poll, config: &Config) {
+        let Some(request) = self.fastcgi_requests.get_mut(&token) else {
+            return;
+        };
+        let client = request.client;
+        let Some(stream) = self.clients.get_mut(&client) else {
+            self.end_fastcgi(token, poll, false);
+            return;
+        };
+
+        let state = request.on_event(stream, config);
+        let updates = std::mem::take(&mut request.reply.session_updates);
+        let job_request = request.job.request.clone();
+        self.update_session(&job_request, &updates);
+
+        let Some(request) = self.fastcgi_requests.get_mut(&token) else {
+            return;
+        };
+        match state {
+            Ok(CgiState::Running) => (),
+            Ok(CgiState::Done) => self.end_fastcgi(token, poll, true),
+            Ok(CgiState::Aborted) => {
+                self.end_fastcgi(token, poll, false);
+                self.close_client(client, poll);
+            }
+            Err(e) => {
+                Server::error_log(&request.job.request, config, "Router::handle_fastcgi_event", file!(), line!(), ServerError::IOError(&e));
+                self.end_fastcgi(token, poll, false);
+                self.close_client(client, poll);
+            }
+        }
+    }
+
+    /// Retire une requête FastCGI ; la connexion retourne au pool si elle est réutilisable.
+    fn end_fastcgi(&mut self, token: Token, poll: &Poll, reuse: bool) {
+        let Some(request) = self.fastcgi_requests.remove(&token) else {
+            return;
+        };
+        self.watch_client_writes(request.client, poll, Interest::READABLE);
+        let address = request.address.clone();
+        let connection = request.into_idle(poll.registry());
+        if let (true, Some(connection)) = (reuse, connection) {
+            let pool = self.fastcgi_pool.entry(address).or_default();
+            if pool.len() < MAX_IDLE_CONNECTIONS {
+                pool.push(connection);
+            }
+        }
+    }
+
+    /// Relaie une requête vers un serveur de l'upstream qui n'a pas encore été essayé.
+    fn start_proxy(&mut self, job: ProxyJob, client: Token, mut tried: Vec<usize>, poll: &Poll, config: &Config) {
+        let pool = self
+            .upstreams
+            .entry(job.upstream.clone())
+            .or_insert_with(|| UpstreamPool::new(Upstream::single(&job.upstream)));
+
+        let mut request = loop {
+            let Some(peer) = pool.pick(&tried) else {
+                job.log_error(config, &job.upstream, "aucun serveur amont disponible");
+                self.reject_proxy(&job, client, config);
+                return;
+            };
+            tried.push(peer);
+            let address = pool.peers[peer].address.clone();
+            match ProxyRequest::start(&job, peer, &address, tried.clone()) {
+                Ok(request) => break request,
+                Err(e) => {
+                    job.log_error(config, &address, &format!("connexion impossible : {}", e));
+                    pool.failure(peer);
                 }
             }
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = request.register(poll.registry(), client, token) {
+            Server::error_log(&job.request, config, "Router::start_proxy", file!(), line!(), ServerError::IOError(&e));
+            self.reject_proxy(&job, client, config);
+            return;
+        }
+        pool.peers[request.peer].active += 1;
+        self.proxy_requests.insert(token, request);
+        self.watch_client_writes(client, poll, Interest::READABLE | Interest::WRITABLE);
+        self.handle_proxy_event(token, poll, config);
+    }
+
+    fn handle_proxy_event(&mut self, token: Token, poll: &Poll, config: &Config) {
+        let Some(request) = self.proxy_requests.get_mut(&token) else {
+            return;
+        };
+        let client = request.client;
+        let Some(stream) = self.clients.get_mut(&client) else {
+            self.end_proxy(token, poll);
+            return;
+        };
+
+        match request.on_event(stream, config) {
+            Ok(CgiState::Running) => (),
+            Ok(_) => {
+                if let Some(request) = self.end_proxy(token, poll) {
+                    if let Some(pool) = self.upstreams.get_mut(&request.job.upstream) {
+                        pool.success(request.peer);
+                    }
+                }
+            }
+            Err(ProxyError::Client(e)) => {
+                Server::error_log(&request.job.request, config, "Router::handle_proxy_event", file!(), line!(), ServerError::IOError(&e));
+                self.end_proxy(token, poll);
+                self.close_client(client, poll);
+            }
+            Err(ProxyError::Upstream(message)) => self.fail_proxy(token, &message, poll, config),
+        }
+    }
+
+    /// Échec du serveur amont : la requête passe au serveur suivant si elle peut être
+    /// rejouée, sinon le client reçoit un 502 ou perd sa connexion.
+    fn fail_proxy(&mut self, token: Token, message: &str, poll: &Poll, config: &Config) {
+        let Some(request) = self.end_proxy(token, poll) else {
+            return;
+        };
+        request.job.log_error(config, &request.address, message);
+        if let Some(pool) = self.upstreams.get_mut(&request.job.upstream) {
+            if pool.failure(request.peer) {
+                eprintln!("Upstream {} : serveur {} écarté après des échecs", request.job.upstream, request.address);
+            }
+        }
+
+        if request.retryable() {
+            self.start_proxy(request.job, request.client, request.tried, poll, config);
+        } else if !request.headers_sent {
+            self.reject_proxy(&request.job, request.client, config);
+        } else {
+            self.close_client(request.client, poll);
+        }
+    }
+
+    /// Retire une requête relayée et libère sa place sur le serveur amont.
+    fn end_proxy(&mut self, token: Token, poll: &Poll) -> Option<ProxyRequest> {
+        let mut request = self.proxy_requests.remove(&token)?;
+        request.deregister(poll.registry());
+        self.watch_client_writes(request.client, poll, Interest::READABLE);
+        if let Some(pool) = self.upstreams.get_mut(&request.job.upstream) {
+            let peer = &mut pool.peers[request.peer];
+            peer.active = peer.active.saturating_sub(1);
+        }
+        Some(request)
+    }
+
+    fn reject_proxy(&mut self, job: &ProxyJob, client: Token, config: &Config) {
+        if let Some(stream) = self.clients.get_mut(&client) {
+            let _ = job.server.send_error_response(stream, &job.request, config, 502, "Bad Gateway", &job.cookie);
+        }
+    }
+
+    /// Répond 504 aux requêtes dont le serveur amont a dépassé `proxy_timeout`, ou ferme
+    /// la connexion si la réponse est déjà commencée.
+    fn check_proxy_timeouts(&mut self, poll: &Poll, config: &Config) {
+        let now = Instant::now();
+        let expired = self
+            .proxy_requests
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        for token in expired {
+            let Some(request) = self.end_proxy(token, poll) else {
+                continue;
+            };
+            request.job.log_error(config, &request.address, "délai de réponse dépassé");
+            if let Some(pool) = self.upstreams.get_mut(&request.job.upstream) {
+                pool.failure(request.peer);
+            }
+            let client = request.client;
+            let sent = match (request.headers_sent, self.clients.get_mut(&client)) {
+                (false, Some(stream)) => request.job.server.send_error_response(
+                    stream,
+                    &request.job.request,
+                    config,
+                    504,
+                    "Gateway Timeout",
+                    &request.job.cookie,
+                ).is_ok(),
+                _ => false,
+            };
+            if !sent {
+                self.close_client(client, poll);
+            }
+        }
+
+        let expired = self
+            .health_probes
+            .iter()
+            .filter(|(_, probe)| probe.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+        for token in expired {
+            self.end_health_probe(token, false, poll);
+        }
+    }
+
+    /// Lance les vérifications actives des upstreams dont l'intervalle est écoulé.
+    fn start_health_checks(&mut self, poll: &Poll) {
+        let now = Instant::now();
+        for (name, pool) in self.upstreams.iter_mut() {
+            let Some(check) = pool.config.health_check.clone() else {
+                continue;
+            };
+            if pool.next_check > now {
+                continue;
+            }
+            pool.next_check = now + Duration::from_millis(check.interval);
+
+            for peer in 0..pool.peers.len() {
+                // La vérification précédente n'est pas terminée
+                if self.health_probes.values().any(|probe| probe.upstream == *name && probe.peer == peer) {
+                    continue;
+                }
+                let address = pool.peers[peer].address.clone();
+                let token = Token(self.next_token);
+                self.next_token += 1;
+                let probe = HealthProbe::start(name, peer, &address, &check).and_then(|mut probe| {
+                    probe.register(poll.registry(), token)?;
+                    Ok(probe)
+                });
+                match probe {
+                    Ok(probe) => {
+                        self.health_probes.insert(token, probe);
+                    }
+                    Err(_) => {
+                        if pool.set_health(peer, false) {
+                            eprintln!("Upstream {} : serveur {} hors service", name, address);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_health_event(&mut self, token: Token, poll: &Poll) {
+        let Some(probe) = self.health_probes.get_mut(&token) else {
+            return;
+        };
+        if let Some(healthy) = probe.on_event() {
+            self.end_health_probe(token, healthy, poll);
+        }
+    }
+
+    fn end_health_probe(&mut self, token: Token, healthy: bool, poll: &Poll) {
+        let Some(mut probe) = self.health_probes.remove(&token) else {
+            return;
+        };
+        probe.deregister(poll.registry());
+        let Some(pool) = self.upstreams.get_mut(&probe.upstream) else {
+            return;
+        };
+        if pool.set_health(probe.peer, healthy) {
+            let state = if healthy { "rétabli" } else { "hors service" };
+            eprintln!("Upstream {} : serveur {} {}", probe.upstream, pool.peers[probe.peer].address, state);
+        }
+    }
+
+    /// Ouvre le backend de la route puis répond `101 Switching Protocols` : la connexion
+    /// du client est ensuite pilotée trame par trame par la boucle d'événements.
+    fn start_websocket(&mut self, job: WebSocketJob, client: Token, poll: &Poll, config: &Config) {
+        let mut connection = match WebSocketConnection::start(&job) {
+            Ok(connection) => connection,
+            Err(e) => {
+                job.log_error(config, &format!("backend injoignable : {}", e));
+                if let Some(stream) = self.clients.get_mut(&client) {
+                    let _ = job.server.send_error_response(stream, &job.request, config, 502, "Bad Gateway", &job.cookie);
+                }
+                return;
+            }
+        };
+
+        let tokens = [Token(self.next_token), Token(self.next_token + 1)];
+        self.next_token += 2;
+        let upgraded = match self.clients.get_mut(&client) {
+            Some(stream) => connection
+                .register(poll.registry(), client, tokens)
+                .and_then(|_| job.server.send_response(stream, &job.request, config, job.response()))
+                .and_then(|_| poll.registry().reregister(stream, client, Interest::READABLE | Interest::WRITABLE)),
+            None => Err(Error::new(ErrorKind::NotConnected, "client parti avant la poignée de main")),
+        };
+        if let Err(e) = upgraded {
+            Server::error_log(&job.request, config, "Router::start_websocket", file!(), line!(), ServerError::IOError(&e));
+            self.cgi_children.extend(connection.deregister(poll.registry()));
+            self.close_client(client, poll);
+            return;
+        }
+
+        for token in tokens {
+            self.websocket_backends.insert(token, client);
+        }
+        self.websockets.insert(client, connection);
+        // Le backend a pu écrire avant l'enregistrement : on lit tout de suite
+        self.handle_websocket_backend_event(client, poll, config);
+    }
+
+    fn handle_websocket_event(&mut self, client: Token, poll: &Poll, config: &Config) {
+        let Some(connection) = self.websockets.get_mut(&client) else {
+            return;
+        };
+        let Some(stream) = self.clients.get_mut(&client) else {
+            self.end_websocket(client, poll);
+            return;
+        };
+
+        let state = connection.on_client_event(stream);
+        let messages = std::mem::take(&mut connection.broadcast);
+        let channel = connection.job.channel.clone();
+        self.update_websocket(client, state, poll, config);
+        if !messages.is_empty() {
+            self.broadcast_websocket(&channel, &messages, poll, config);
+        }
+    }
+
+    fn handle_websocket_backend_event(&mut self, client: Token, poll: &Poll, config: &Config) {
+        let Some(connection) = self.websockets.get_mut(&client) else {
+            return;
+        };
+        let Some(stream) = self.clients.get_mut(&client) else {
+            self.end_websocket(client, poll);
+            return;
+        };
+        let state = connection.on_backend_event(stream);
+        self.update_websocket(client, state, poll, config);
+    }
+
+    /// Envoie des messages à tous les clients d'un canal `broadcast`, expéditeur compris.
+    fn broadcast_websocket(&mut self, channel: &str, messages: &[(u8, Vec<u8>)], poll: &Poll, config: &Config) {
+        let members = self
+            .websockets
+            .iter()
+            .filter(|(_, connection)| connection.job.channel == channel)
+            .map(|(client, _)| *client)
+            .collect::<Vec<Token>>();
+
+        for client in members {
+            let (Some(connection), Some(stream)) = (self.websockets.get_mut(&client), self.clients.get_mut(&client)) else {
+                continue;
+            };
+            for (opcode, data) in messages {
+                connection.send(*opcode, data);
+            }
+            let state = connection.flush(stream);
+            self.update_websocket(client, state, poll, config);
+        }
+    }
+
+    fn update_websocket(&mut self, client: Token, state: io::Result<CgiState>, poll: &Poll, config: &Config) {
+        let Some(connection) = self.websockets.get(&client) else {
+            return;
+        };
+        match state {
+            Ok(CgiState::Running) => (),
+            Ok(_) => {
+                self.end_websocket(client, poll);
+                self.close_client(client, poll);
+            }
+            Err(e) => {
+                connection.job.log_error(config, &e.to_string());
+                if let (Some(connection), Some(stream)) = (self.websockets.get_mut(&client), self.clients.get_mut(&client)) {
+                    connection.close(CLOSE_INTERNAL_ERROR);
+                    let _ = connection.flush(stream);
+                }
+                self.end_websocket(client, poll);
+                self.close_client(client, poll);
+            }
+        }
+    }
+
+    /// Envoie un ping aux clients silencieux et ferme ceux qui n'ont pas répondu au ping
+    /// ou à la fermeture.
+    fn check_websocket_timeouts(&mut self, poll: &Poll, config: &Config) {
+        let now = Instant::now();
+        let expired = self
+            .websockets
+            .iter()
+            .filter(|(_, connection)| connection.deadline <= now)
+            .map(|(client, _)| *client)
+            .collect::<Vec<Token>>();
+
+        for client in expired {
+            let (Some(connection), Some(stream)) = (self.websockets.get_mut(&client), self.clients.get_mut(&client)) else {
+                self.end_websocket(client, poll);
+                continue;
+            };
+            if !connection.on_timeout() {
+                self.end_websocket(client, poll);
+                self.close_client(client, poll);
+                continue;
+            }
+            let state = connection.flush(stream);
+            self.update_websocket(client, state, poll, config);
+        }
+    }
+
+    /// Oublie une connexion WebSocket : son backend est fermé et sa commande récupérée plus tard.
+    fn end_websocket(&mut self, client: Token, poll: &Poll) {
+        if let Some(mut connection) = self.websockets.remove(&client) {
+            self.cgi_children.extend(connection.deregister(poll.registry()));
+            self.websocket_backends.retain(|_, owner| *owner != client);
+        }
+    }
+
+    /// Enregistre l'utilisateur connecté (ou déconnecté) dans la session, change son
+    /// identifiant contre la fixation de session, puis redirige le client.
+    fn finish_login(&mut self, job: LoginJob, client: Token, config: &Config) {
+        let id = job.request.session.as_ref().and_then(|session| self.regenerate_session(&session.id));
+        let session = match id.and_then(|id| self.sessions.get_mut(&id)) {
+            Some(session) => session,
+            None => {
+                let session = job.settings.new_session();
+                self.sessions.entry(session.id.clone()).insert_entry(session).into_mut()
+            }
+        };
+        session.user = job.user.clone();
+        // Nouveau jeton CSRF : ceux des pages servies avant la connexion ne valent plus
+        session.csrf_token = Session::generate_id();
+        let response = job.response(session);
+        self.client_sessions.insert(client, session.id.clone());
+        self.sessions_dirty = true;
+
+        if let Some(stream) = self.clients.get_mut(&client) {
+            if let Err(e) = job.server.send_response(stream, &job.request, config, response) {
+                Server::error_log(&job.request, config, "Router::finish_login", file!(), line!(), ServerError::IOError(&e));
+            }
+        }
+    }
+
+    /// Commence un flux SSE : le dossier est surveillé par inotify et chaque changement
+    /// est envoyé au client tant qu'il reste connecté.
+    fn start_directory_events(&mut self, job: DirectoryEventsJob, client: Token, poll: &Poll, config: &Config) {
+        // Chaque flux occupe une instance inotify : au-delà, le navigateur réessaiera
+        let watch = match self.directory_watches.len() < MAX_WATCHES {
+            true => DirectoryWatch::start(&job),
+            false => Err(Error::from_raw_os_error(libc::EMFILE)),
+        };
+        let mut watch = match watch {
+            Ok(watch) => watch,
+            Err(e) => {
+                job.log_error(config, &e);
+                let (status_code, status_message) = match e.raw_os_error() {
+                    Some(libc::EMFILE) => (503, "Service Unavailable"),
+                    _ => (500, "Internal Server Error"),
+                };
+                if let Some(stream) = self.clients.get_mut(&client) {
+                    let _ = job.server.send_error_response(stream, &job.request, config, status_code, status_message, &job.cookie);
+                }
+                return;
+            }
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let started = match self.clients.get_mut(&client) {
+            Some(stream) => watch
+                .register(poll.registry(), client, token)
+                .and_then(|_| poll.registry().reregister(stream, client, Interest::READABLE | Interest::WRITABLE))
+                .and_then(|_| watch.send_head(stream, config)),
+            None => Err(Error::new(ErrorKind::NotConnected, "client parti avant le flux")),
+        };
+        self.watch_clients.insert(client, token);
+        self.directory_watches.insert(token, watch);
+        self.update_watch(token, started, poll, config);
+    }
+
+    fn handle_directory_event(&mut self, token: Token, poll: &Poll, config: &Config) {
+        let Some(watch) = self.directory_watches.get_mut(&token) else {
+            return;
+        };
+        let Some(stream) = self.clients.get_mut(&watch.client) else {
+            self.end_watch(token, poll);
+            return;
+        };
+        let state = watch.on_event(stream);
+        self.update_watch(token, state, poll, config);
+    }
+
+    /// Ferme le flux si son client s'est déconnecté, sinon lui envoie la suite.
+    fn check_watch_client(&mut self, key: Token, poll: &Poll, config: &Config) {
+        let Some(watch) = self.directory_watches.get_mut(&key) else {
+            return;
+        };
+        let mut ignored = vec![];
+        let state = match self.clients.get_mut(&watch.client) {
+            Some(stream) => match drain(stream, &mut ignored) {
+                Ok(false) => watch.flush(stream),
+                _ => Ok(CgiState::Aborted),
+            },
+            None => Ok(CgiState::Aborted),
+        };
+        self.update_watch(key, state, poll, config);
+    }
+
+    /// Termine le flux et ferme son client, sauf s'il continue.
+    fn update_watch(&mut self, token: Token, state: io::Result<CgiState>, poll: &Poll, config: &Config) {
+        let Some(watch) = self.directory_watches.get(&token) else {
+            return;
+        };
+        let client = watch.client;
+        match state {
+            Ok(CgiState::Running) => return,
+            Ok(_) => (),
+            // Le plus souvent un client parti : l'écriture échoue
+            Err(e) => {
+                if e.kind() != ErrorKind::BrokenPipe && e.kind() != ErrorKind::ConnectionReset {
+                    watch.job.log_error(config, &e);
+                }
+            }
+        }
+        self.end_watch(token, poll);
+        self.close_client(client, poll);
+    }
+
+    /// Envoie un commentaire aux flux SSE restés silencieux ; un client parti est ainsi
+    /// détecté même si le dossier ne change pas.
+    fn check_watch_keep_alive(&mut self, poll: &Poll, config: &Config) {
+        let now = Instant::now();
+        let expired = self
+            .directory_watches
+            .iter()
+            .filter(|(_, watch)| watch.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        for token in expired {
+            let Some(watch) = self.directory_watches.get_mut(&token) else {
+                continue;
+            };
+            let state = match self.clients.get_mut(&watch.client) {
+                Some(stream) => watch.keep_alive(stream),
+                None => Ok(CgiState::Aborted),
+            };
+            self.update_watch(token, state, poll, config);
+        }
+    }
+
+    /// Arrête la surveillance d'un dossier : le descripteur inotify est fermé.
+    fn end_watch(&mut self, token: Token, poll: &Poll) {
+        if let Some(mut watch) = self.directory_watches.remove(&token) {
+            watch.deregister(poll.registry());
+            self.watch_clients.remove(&watch.client);
+        }
+    }
+
+    /// Lance les scripts en attente pour lesquels une place s'est libérée ; ceux qui
+    /// ont attendu plus que `cgi_timeout` reçoivent un 503.
+    fn start_queued_cgi(&mut self, poll: &Poll, config: &Config) {
+        let queue = std::mem::take(&mut self.cgi_queue);
+        for (client, job) in queue {
+            if !self.clients.contains_key(&client) {
+                continue;
+            }
+            if job.expired() {
+                self.reject_cgi(&job, client, 503, "Service Unavailable", config);
+                continue;
+            }
+            // start_cgi remet la requête dans la file si le serveur est toujours occupé
+            self.start_cgi(job, client, poll, config);
+        }
+    }
+
+    fn reject_cgi(&mut self, job: &CgiJob, client: Token, status_code: u16, status_message: &str, config: &Config) {
+        if let Some(stream) = self.clients.get_mut(&client) {
+            let _ = job.server.send_error_response(stream, &job.request, config, status_code, status_message, &job.cookie);
+        }
+    }
+
+    fn same_server(a: &Server, b: &Server) -> bool {
+        a.hostname == b.hostname && a.ip_addr == b.ip_addr && a.ports == b.ports
+    }
+
+    fn watch_client_writes(&mut self, client: Token, poll: &Poll, interest: Interest) {
+        if let Some(stream) = self.clients.get_mut(&client) {
+            let _ = poll.registry().reregister(stream, client, interest);
+        }
+    }
+
+    /// Vide la réponse CGI, FastCGI ou relayée en attente pour ce client ; la lecture
+    /// de la sortie ou de l'amont reprend si elle était suspendue.
+    fn resume_client(&mut self, client: Token, poll: &Poll, config: &Config) {
+        let process = self
+            .cgi_processes
+            .iter()
+            .find(|(_, process)| process.client == client)
+            .map(|(key, _)| *key);
+        if let Some(key) = process {
+            self.handle_cgi_event(key, poll, config);
+            return;
+        }
+        let request = self
+            .fastcgi_requests
+            .iter()
+            .find(|(_, request)| request.client == client)
+            .map(|(token, _)| *token);
+        if let Some(token) = request {
+            self.handle_fastcgi_event(token, poll, config);
+            return;
+        }
+        let request = self
+            .proxy_requests
+            .iter()
+            .find(|(_, request)| request.client == client)
+            .map(|(token, _)| *token);
+        if let Some(token) = request {
+            self.handle_proxy_event(token, poll, config);
+        }
+    }
+
+    /// Requête relayée dont le corps arrive encore de ce client.
+    fn proxy_expecting_body(&self, client: Token) -> Option<Token> {
+        self.proxy_requests
+            .iter()
+            .find(|(_, request)| request.client == client && request.expects_body())
+            .map(|(token, _)| *token)
+    }
+
+    /// Fait avancer un script CGI après un événement sur l'un de ses tubes.
+    fn handle_cgi_event(&mut self, key: Token, poll: &Poll, config: &Config) {
+        let Some(process) = self.cgi_processes.get_mut(&key) else {
+            return;
+        };
+        let Some(stream) = self.clients.get_mut(&process.client) else {
+            // Le client est parti : inutile de laisser tourner le script
+            process.kill();
+            self.end_cgi(key, poll);
+            return;
+        };
+
+        let state = process.on_event(poll.registry(), stream, config);
+        self.update_cgi(key, state, poll, config);
+    }
+
+    /// Termine les scripts dont la sortie est close dès que leur processus est sorti.
+    fn check_cgi_exits(&mut self, poll: &Poll, config: &Config) {
+        let closed = self
+            .cgi_processes
+            .iter()
+            .filter(|(_, process)| process.output_closed())
+            .map(|(key, _)| *key)
+            .collect::<Vec<Token>>();
+
+        for key in closed {
+            let Some(process) = self.cgi_processes.get_mut(&key) else {
+                continue;
+            };
+            let Some(stream) = self.clients.get_mut(&process.client) else {
+                self.end_cgi(key, poll);
+                continue;
+            };
+            let state = process.try_finish(stream, config);
+            self.update_cgi(key, state, poll, config);
+        }
+    }
+
+    /// Change l'identifiant d'une session en gardant ses données, par exemple après
+    /// une connexion pour empêcher la fixation de session. Renvoie le nouvel identifiant.
+    pub fn regenerate_session(&mut self, id: &str) -> Option<String> {
+        let new_id = Session::generate_id();
+        self.rotate_session(id, &new_id).then_some(new_id)
+    }
+
+    /// Réindexe la session `id` sous `new_id`.
+    fn rotate_session(&mut self, id: &str, new_id: &str) -> bool {
+        let Some(mut session) = self.sessions.remove(id) else {
+            return false;
+        };
+        session.id = new_id.to_string();
+        self.sessions.insert(new_id.to_string(), session);
+        for session_id in self.client_sessions.values_mut().filter(|session_id| *session_id == id) {
+            *session_id = new_id.to_string();
+        }
+        self.sessions_dirty = true;
+        true
+    }
+
+    /// Applique à la session du client les modifications demandées par un script.
+    fn update_session(&mut self, request: &Request, updates: &[SessionUpdate]) {
+        let Some(mut id) = request.session.as_ref().map(|session| session.id.clone()) else {
+            return;
+        };
+        if updates.is_empty() {
+            return;
+        }
+        for update in updates {
+            if let SessionUpdate::Regenerate(new_id) = update {
+                if self.rotate_session(&id, new_id) {
+                    id = new_id.clone();
+                }
+            }
+        }
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.apply(updates);
+            self.sessions_dirty = true;
+        }
+    }
+
+    fn update_cgi(&mut self, key: Token, state: io::Result<CgiState>, poll: &Poll, config: &Config) {
+        let Some(process) = self.cgi_processes.get_mut(&key) else {
+            return;
+        };
+        let updates = std::mem::take(&mut process.reply.session_updates);
+        let request = process.job.request.clone();
+        self.update_session(&request, &updates);
+
+        let Some(process) = self.cgi_processes.get_mut(&key) else {
+            return;
+        };
+        let client = process.client;
+        match state {
+            Ok(CgiState::Running) => (),
+            Ok(CgiState::Done) => self.end_cgi(key, poll),
+            Ok(CgiState::Aborted) => {
+                process.kill();
+                self.end_cgi(key, poll);
+                self.close_client(client, poll);
+            }
+            Err(e) => {
+                Server::error_log(&process.job.request, config, "Router::update_cgi", file!(), line!(), crate::ServerError::IOError(&e));
+                process.kill();
+                self.end_cgi(key, poll);
+                self.close_client(client, poll);
+            }
+        }
+    }
+
+    /// Tue les scripts qui ont dépassé leur délai : 504 si rien n'a encore été envoyé,
+    /// sinon la connexion est fermée (la réponse est tronquée).
+    fn check_cgi_timeouts(&mut self, poll: &Poll, config: &Config) {
+        let now = Instant::now();
+        let expired = self
+            .cgi_processes
+            .iter()
+            .filter(|(_, process)| process.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<Token>>();
+
+        for key in expired {
+            let Some(process) = self.cgi_processes.get_mut(&key) else {
+                continue;
+            };
+            process.kill();
+            process.job.log_error(config, "délai d'exécution dépassé");
+            process.log_stderr(config);
+            let client = process.client;
+            let sent = match (process.reply.headers_sent, self.clients.get_mut(&client)) {
+                (false, Some(stream)) => process.job.server.send_error_response(
+                    stream,
+                    &process.job.request,
+                    config,
+                    504,
+                    "Gateway Timeout",
+                    &process.job.cookie,
+                ).is_ok(),
+                _ => false,
+            };
+            self.end_cgi(key, poll);
+            if !sent {
+                self.close_client(client, poll);
+            }
+        }
+
+        let expired = self
+            .fastcgi_requests
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+        for token in expired {
+            let Some(request) = self.fastcgi_requests.get_mut(&token) else {
+                continue;
+            };
+            request.job.log_error(config, "délai de réponse du backend FastCGI dépassé");
+            let client = request.client;
+            let sent = match (request.reply.headers_sent, self.clients.get_mut(&client)) {
+                (false, Some(stream)) => request.job.server.send_error_response(
+                    stream,
+                    &request.job.request,
+                    config,
+                    504,
+                    "Gateway Timeout",
+                    &request.job.cookie,
+                ).is_ok(),
+                _ => false,
+            };
+            self.end_fastcgi(token, poll, false);
+            if !sent {
+                self.close_client(client, poll);
+            }
+        }
+    }
+
+    /// Oublie un script : ses tubes sont retirés du Poll et le processus est récupéré plus tard.
+    fn end_cgi(&mut self, key: Token, poll: &Poll) {
+        if let Some(mut process) = self.cgi_processes.remove(&key) {
+            process.deregister(poll.registry());
+            self.watch_client_writes(process.client, poll, Interest::READABLE);
+            self.cgi_pipes.retain(|_, process_key| *process_key != key);
+            self.cgi_children.push(process.child);
+        }
+    }
+
+    /// Récupère les scripts terminés pour ne pas laisser de zombies.
+    fn reap_cgi_children(&mut self) {
+        self.cgi_children
+            .retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_)) | Err(_)));
+    }
+
+    /// Délai avant la prochaine échéance : script CGI, requête relayée, vérification
+    /// d'un upstream, ping WebSocket, maintien d'un flux SSE ou entretien des sessions.
+    fn next_cgi_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut timeout = self
+            .cgi_processes
+            .values()
+            .map(|process| process.deadline)
+            .chain(self.fastcgi_requests.values().map(|request| request.deadline))
+            .chain(self.proxy_requests.values().map(|request| request.deadline))
+            .chain(self.health_probes.values().map(|probe| probe.deadline))
+            .chain(self.websockets.values().map(|connection| connection.deadline))
+            .chain(self.directory_watches.values().map(|watch| watch.deadline))
+            .chain(std::iter::once(self.next_session_sweep))
+            .chain(self.sessions_dirty.then_some(self.next_session_flush))
+            .chain(
+                self.upstreams
+                    .values()
+                    .filter(|pool| pool.config.health_check.is_some())
+                    .map(|pool| pool.next_check)
+            )
+            .map(|deadline| deadline.saturating_duration_since(now))
+            .min();
+        if self.cgi_processes.values().any(|process| process.output_closed()) {
+            // Sortie close : on attend la fin du processus pour connaître son code
+            let exit = Duration::from_millis(10);
+            timeout = Some(timeout.map_or(exit, |t| t.min(exit)));
+        }
+        if !self.cgi_children.is_empty() || !self.cgi_queue.is_empty() {
+            // Des processus à récupérer ou des requêtes en attente : on repasse régulièrement
+            let reap = Duration::from_millis(100);
+            timeout = Some(timeout.map_or(reap, |t| t.min(reap)));
+        }
+        timeout
+    }
+
+    fn close_client(&mut self, client: Token, poll: &Poll) {
+        self.client_sessions.remove(&client);
+        if let Some(mut stream) = self.clients.remove(&client) {
+            let _ = poll.registry().deregister(&mut stream);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
         }
     }
 
@@ -266,43 +1359,37 @@ impl Router {
         cookie: String,
         config: &Config,
         poll: &mut Poll,
-    ) -> bool {
+    ) -> (bool, Option<Job>) {
         // On récupère le hostname, l'adresse ip et le port de la requête
         // On parcoure la liste des serveurs et on vérifie lequel a le hostname, le port et l'ip correspondant
         let mut i = 0;
         while i < request_queue.len() {
             let req = request_queue[i].clone();
             for server in servers.iter() {
-                if (server.ip_addr == req.host  || server.hostname == req.host)
-                    && server.ports.contains(&req.port)
-                {
-                    if req.method == "GET" || req.complete {
-                        match server.handle_request(stream, req.clone(), cookie.clone(), config) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                match poll.registry().deregister(stream) {
-                                    Ok(_) => println!("Client supprimer sur le register d'epoll pour cause d'erreur sur l'ecriture :  {:?}", err),
-                                    Err(err) => {
-                                        match poll.registry().deregister(stream) {
-                                            Ok(_) => println!("Client supprimer sur le register d'epoll en mod ecriture pour cause {:?}",err),
-                                            Err(e) => println!("Error while deregising on read stream on read operation: {}", e),
-                                        };
-                                    return true;
-                                    }
+                if server.serves(&req) && (req.method == "GET" || req.complete || server.streams_body(&req)) {
+                    let mut job = None;
+                    match server.handle_request(stream, req.clone(), cookie.clone(), config) {
+                        Ok(deferred) => job = deferred,
+                        Err(err) => {
+                            match poll.registry().deregister(stream) {
+                                Ok(_) => println!("Client supprimer sur le register d'epoll pour cause d'erreur sur l'ecriture :  {:?}", err),
+                                Err(err) => {
+                                    match poll.registry().deregister(stream) {
+                                        Ok(_) => println!("Client supprimer sur le register d'epoll en mod ecriture pour cause {:?}",err),
+                                        Err(e) => println!("Error while deregising on read stream on read operation: {}", e),
+                                    };
+                                return (true, None);
                                 }
                             }
-                        };
-
-                        request_queue.remove(i);
-                        if i != 0 {
-                            i -= 1;
                         }
-                        return false;
-                    }
+                    };
+
+                    request_queue.remove(i);
+                    return (false, job);
                 }
             }
             i += 1;
         }
-        false
+        (false, None)
     }
 }
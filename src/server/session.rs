@@ -1,17 +1,233 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
 use mio::net::TcpStream;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{BufReader, Read};
+use sha2::Sha256;
+use std::str::FromStr;
 use uuid::Uuid;
 
+use super::Request;
+
+type HmacSha256 = Hmac<Sha256>;
+
 // -------------------------------------------------------------------------------------
 // SESSION
 // -------------------------------------------------------------------------------------
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
     pub id: String,
+    /// Fin de validité si la session reste inactive.
     pub validity_time: DateTime<Utc>,
+    /// Durée d'inactivité tolérée, en millisecondes.
     pub expiration_time: i64,
+    /// Fin de validité quelle que soit l'activité (`absolute_timeout`).
+    #[serde(default)]
+    pub absolute_expiry: Option<DateTime<Utc>>,
+    /// Utilisateur connecté par le formulaire de connexion.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Données propres au client, partagées avec les scripts CGI et les templates.
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+    /// Jeton exigé sur les envois et suppressions des pages de listing.
+    #[serde(default = "Session::generate_id")]
+    pub csrf_token: String,
+}
+
+/// Modification de session demandée par un script CGI (`X-Session-Set: clé=valeur`,
+/// `X-Session-Unset: clé`, `X-Session-Regenerate: 1`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionUpdate {
+    Set(String, String),
+    Unset(String),
+    /// Nouvel identifiant de la session, qui garde ses données.
+    Regenerate(String),
+}
+
+/// Valeur de l'attribut `SameSite` du cookie de session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Cookie de session et durée de vie des sessions, dans `[http.session]` ou
+/// propres à un serveur (`[http.servers.<nom>.session]`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionCookie {
+    #[serde(default = "SessionCookie::default_cookie_name")]
+    pub cookie_name: String,
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default = "SessionCookie::default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default = "SessionCookie::default_http_only")]
+    pub http_only: bool,
+    #[serde(default)]
+    pub same_site: Option<SameSite>,
+    /// Attribut `Max-Age` en secondes ; sans lui, seul `Expires` est envoyé.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Inactivité tolérée avant l'expiration, en millisecondes.
+    #[serde(default = "SessionCookie::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Durée de vie maximale depuis la création, en millisecondes.
+    #[serde(default)]
+    pub absolute_timeout: Option<u64>,
+    /// Chaque requête repousse l'expiration d'un `idle_timeout`.
+    #[serde(default = "SessionCookie::default_sliding")]
+    pub sliding: bool,
+    /// Clé HMAC qui signe la valeur du cookie ; sans elle, le cookie n'est pas signé.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Anciennes clés encore acceptées, le temps que les cookies soient renouvelés.
+    #[serde(default)]
+    pub old_secrets: Vec<String>,
+}
+
+impl Default for SessionCookie {
+    fn default() -> Self {
+        Self {
+            cookie_name: Self::default_cookie_name(),
+            domain: None,
+            path: Self::default_path(),
+            secure: false,
+            http_only: Self::default_http_only(),
+            same_site: None,
+            max_age: None,
+            idle_timeout: Self::default_idle_timeout(),
+            absolute_timeout: None,
+            sliding: Self::default_sliding(),
+            secret: None,
+            old_secrets: vec![],
+        }
+    }
+}
+
+impl SessionCookie {
+    fn default_cookie_name() -> String {
+        "cookie_01".to_string()
+    }
+
+    fn default_path() -> String {
+        "/".to_string()
+    }
+
+    fn default_http_only() -> bool {
+        true
+    }
+
+    fn default_idle_timeout() -> u64 {
+        Session::SESSION_LIFETIME as u64
+    }
+
+    fn default_sliding() -> bool {
+        true
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let separators = ['(', ')', '<', '>', '@', ',', ';', ':', '\\', '"', '/', '[', ']', '?', '=', '{', '}'];
+        if self.cookie_name.is_empty()
+            || self.cookie_name.chars().any(|c| !c.is_ascii_graphic() || separators.contains(&c))
+        {
+            errors.push(format!("cookie_name invalide : {:?}", self.cookie_name));
+        }
+        if !self.path.starts_with('/') || self.path.contains(';') {
+            errors.push(format!("path de cookie invalide : {}", self.path));
+        }
+        if self.domain.as_ref().is_some_and(|domain| domain.is_empty() || domain.contains(';')) {
+            errors.push("domain de cookie invalide".to_string());
+        }
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            errors.push("same_site = \"None\" nécessite secure = true".to_string());
+        }
+        if self.idle_timeout == 0 || self.absolute_timeout == Some(0) {
+            errors.push("idle_timeout et absolute_timeout doivent être positifs".to_string());
+        }
+        if self.secret.iter().chain(&self.old_secrets).any(|secret| secret.is_empty()) {
+            errors.push("les clés de signature ne peuvent pas être vides".to_string());
+        }
+        if self.secret.is_none() && !self.old_secrets.is_empty() {
+            errors.push("old_secrets nécessite secret".to_string());
+        }
+        errors
+    }
+
+    fn signature(secret: &str, id: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepte toute taille de clé");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    /// Valeur du cookie : l'identifiant, suivi de sa signature avec la clé courante.
+    pub fn encode(&self, id: &str) -> String {
+        match &self.secret {
+            Some(secret) => {
+                let signature = Self::signature(secret, id).finalize().into_bytes();
+                format!("{}.{}", id, URL_SAFE_NO_PAD.encode(signature))
+            }
+            None => id.to_string(),
+        }
+    }
+
+    /// Identifiant contenu dans la valeur du cookie, si sa signature correspond à la clé
+    /// courante ou à une ancienne clé.
+    pub fn decode(&self, value: &str) -> Result<String, &'static str> {
+        let Some(secret) = &self.secret else {
+            return Ok(value.to_string());
+        };
+        let (id, signature) = value.rsplit_once('.').ok_or("cookie non signé")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "signature illisible")?;
+        // verify_slice compare en temps constant
+        std::iter::once(secret)
+            .chain(&self.old_secrets)
+            .any(|secret| Self::signature(secret, id).verify_slice(&signature).is_ok())
+            .then(|| id.to_string())
+            .ok_or("signature invalide")
+    }
+
+    /// Nouvelle session soumise à ces durées de vie.
+    pub fn new_session(&self) -> Session {
+        let now = Utc::now();
+        let mut session = Session::new();
+        session.expiration_time = self.idle_timeout as i64;
+        session.validity_time = now + Duration::milliseconds(session.expiration_time);
+        session.absolute_expiry = self
+            .absolute_timeout
+            .map(|timeout| now + Duration::milliseconds(timeout as i64));
+        session
+    }
+
+    /// Ligne `Set-Cookie: ...\r\n` qui transmet la session au client.
+    pub fn header(&self, session: &Session) -> String {
+        let mut header = format!("Set-Cookie: {}={}; Path={}", self.cookie_name, self.encode(&session.id), self.path);
+        if let Some(domain) = &self.domain {
+            header.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        header.push_str(&format!("; Expires={}", session.expires_at().format("%a, %d %b %Y %H:%M:%S GMT")));
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={:?}", same_site));
+        }
+        header.push_str("\r\n");
+        header
+    }
 }
 
 impl Session {
@@ -21,14 +237,101 @@ impl Session {
         let expires_duration = Duration::milliseconds(Self::SESSION_LIFETIME);
 
         Self {
-            id: Uuid::new_v4().into(),
+            id: Self::generate_id(),
             expiration_time: Self::SESSION_LIFETIME,
             validity_time: Utc::now() + expires_duration,
+            absolute_expiry: None,
+            user: None,
+            data: HashMap::new(),
+            csrf_token: Self::generate_id(),
+        }
+    }
+
+    pub fn generate_id() -> String {
+        Uuid::new_v4().into()
+    }
+
+    /// Valeur convertie dans le type demandé (`None` si absente ou invalide).
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.data.get(key).and_then(|value| value.parse().ok())
+    }
+
+    pub fn set<T: ToString>(&mut self, key: &str, value: T) {
+        self.data.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.data.remove(key)
+    }
+
+    pub fn apply(&mut self, updates: &[SessionUpdate]) {
+        for update in updates {
+            match update {
+                SessionUpdate::Set(key, value) => self.set(key, value),
+                SessionUpdate::Unset(key) => {
+                    self.remove(key);
+                }
+                // Le Router réindexe la session avant d'appliquer les autres modifications
+                SessionUpdate::Regenerate(_) => (),
+            }
+        }
+    }
+
+    /// Interprète un en-tête de sortie CGI ; `None` s'il ne concerne pas la session.
+    /// La valeur de `X-Session-Set` est décodée comme dans une URL.
+    pub fn parse_update(name: &str, value: &str) -> Option<SessionUpdate> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "x-session-set" => {
+                let (key, value) = value.split_once('=')?;
+                let value = urlencoding::decode(value.trim()).map_or(value.trim().to_string(), |v| v.into_owned());
+                Some(SessionUpdate::Set(key.trim().to_string(), value))
+            }
+            "x-session-unset" => Some(SessionUpdate::Unset(value.trim().to_string())),
+            "x-session-regenerate" => Some(SessionUpdate::Regenerate(Self::generate_id())),
+            _ => None,
+        }
+    }
+
+    /// Variables transmises aux scripts CGI : `SESSION_ID`, `SESSION_DATA` (toutes les
+    /// données, encodées comme une query string) et une `SESSION_<CLÉ>` par donnée.
+    pub fn to_env(&self) -> Vec<(String, String)> {
+        let mut keys = self.data.keys().collect::<Vec<&String>>();
+        keys.sort();
+        let encoded = keys
+            .iter()
+            .map(|key| format!("{}={}", urlencoding::encode(key), urlencoding::encode(&self.data[*key])))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let mut env = vec![
+            ("SESSION_ID".to_string(), self.id.clone()),
+            ("SESSION_DATA".to_string(), encoded),
+        ];
+        for key in keys {
+            let name = key
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect::<String>();
+            env.push((format!("SESSION_{}", name), self.data[key].clone()));
+        }
+        env
+    }
+
+    /// Prolonge la session d'une durée d'inactivité à partir de maintenant.
+    pub fn touch(&mut self) {
+        self.validity_time = Utc::now() + Duration::milliseconds(self.expiration_time);
+    }
+
+    /// Fin de validité effective : inactivité ou durée de vie maximale.
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        match self.absolute_expiry {
+            Some(absolute) => absolute.min(self.validity_time),
+            None => self.validity_time,
         }
     }
 
     pub fn is_expired(&self) -> bool {
-        Utc::now() > self.validity_time
+        Utc::now() > self.expires_at()
     }
 
     /// Récupère la valeur d'un cookie spécifique à partir d'un TcpStream.
@@ -60,50 +363,15 @@ impl Session {
 
     /// Extrait la valeur d'un cookie spécifique à partir des en-têtes HTTP.
     fn extract_cookie_from_headers(headers: &str, cookie_name: &str) -> Option<String> {
-        // Parcourt chaque ligne des en-têtes
+        let mut cookies = HashMap::new();
         for line in headers.lines() {
-            // Vérifie si la ligne contient l'en-tête "Cookie"
-            if line.starts_with("Cookie:") {
-                // Extrait la partie après "Cookie:"
-                let cookies_str = line.trim_start_matches("Cookie:").trim();
-
-                // Divise les cookies en paires clé-valeur
-                let cookies: HashMap<String, String> = cookies_str
-                    .split(';')
-                    .filter_map(|cookie| {
-                        let mut parts = cookie.trim().split('=');
-                        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                            Some((key.to_string(), value.to_string()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                // Retourne la valeur du cookie demandé
-                return cookies.get(cookie_name).cloned();
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Cookie") {
+                    Request::parse_cookies(value, &mut cookies);
+                }
             }
         }
-
-        None // Cookie non trouvé
-    }
-
-    pub fn make_cookie(cookie_name: &str, cookie_value: &str, expires_in: i64) -> String {
-        // Convertir expires_in (millisecondes) en Duration
-        let expires_duration = Duration::milliseconds(expires_in);
-
-        // Calculer la date d'expiration
-        let expires = (Utc::now() + expires_duration)
-            .format("%a, %d %b %Y %H:%M:%S GMT")
-            .to_string();
-
-        // Construire l'en-tête Set-Cookie
-        let cookie_header = format!(
-            "Set-Cookie: {}={}; Path=/; HttpOnly; Expires={}\r\n",
-            cookie_name, cookie_value, expires
-        );
-
-        cookie_header
+        cookies.remove(cookie_name)
     }
 }
 // -------------------------------------------------------------------------------------
@@ -122,6 +390,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_session_touch() {
+        let mut session = Session::new();
+        session.validity_time = Utc::now() - chrono::Duration::seconds(1);
+        assert!(session.is_expired());
+        session.touch();
+        assert!(!session.is_expired());
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let settings = SessionCookie {
+            cookie_name: "app_session".to_string(),
+            domain: Some("example.org".to_string()),
+            path: "/app".to_string(),
+            secure: true,
+            same_site: Some(SameSite::Strict),
+            max_age: Some(600),
+            idle_timeout: 60_000,
+            absolute_timeout: Some(1000),
+            ..SessionCookie::default()
+        };
+        assert!(settings.check().is_empty());
+
+        let mut session = settings.new_session();
+        session.touch();
+        assert_eq!(session.expires_at(), session.absolute_expiry.unwrap());
+        let header = settings.header(&session);
+        assert!(header.starts_with(&format!("Set-Cookie: app_session={}; Path=/app; Domain=example.org; Max-Age=600; Expires=", session.id)));
+        assert!(header.ends_with("; Secure; HttpOnly; SameSite=Strict\r\n"));
+
+        let invalid = SessionCookie {
+            cookie_name: "a;b".to_string(),
+            same_site: Some(SameSite::None),
+            ..SessionCookie::default()
+        };
+        assert_eq!(invalid.check().len(), 2);
+    }
+
+    #[test]
+    fn test_signed_cookie() {
+        let mut settings = SessionCookie {
+            secret: Some("ancienne".to_string()),
+            ..SessionCookie::default()
+        };
+        let session = Session::new();
+        let old_value = settings.encode(&session.id);
+
+        settings.secret = Some("nouvelle".to_string());
+        let value = settings.encode(&session.id);
+        assert_ne!(value, old_value);
+        assert_eq!(settings.decode(&value), Ok(session.id.clone()));
+        assert_eq!(settings.decode(&old_value), Err("signature invalide"));
+        assert_eq!(settings.decode(&session.id), Err("cookie non signé"));
+
+        // Rotation : l'ancienne clé reste acceptée
+        settings.old_secrets = vec!["ancienne".to_string()];
+        assert_eq!(settings.decode(&old_value), Ok(session.id.clone()));
+        let forged = format!("{}.{}", Session::generate_id(), old_value.rsplit_once('.').unwrap().1);
+        assert_eq!(settings.decode(&forged), Err("signature invalide"));
+    }
+
+    #[test]
+    fn test_session_data() {
+        let mut session = Session::new();
+        session.set("view", "grid");
+        session.set("uploads", 3);
+        assert_eq!(session.get::<u32>("uploads"), Some(3));
+        assert_eq!(session.get::<String>("view").as_deref(), Some("grid"));
+        assert_eq!(session.get::<u32>("view"), None);
+
+        let updates = [
+            Session::parse_update("X-Session-Set", "last-file=rapport%20final.pdf").unwrap(),
+            Session::parse_update("x-session-unset", " view ").unwrap(),
+        ];
+        assert_eq!(Session::parse_update("Content-Type", "text/html"), None);
+        session.apply(&updates);
+        assert_eq!(session.remove("view"), None);
+
+        let env = session.to_env();
+        assert!(env.contains(&("SESSION_DATA".to_string(), "last-file=rapport%20final.pdf&uploads=3".to_string())));
+        assert!(env.contains(&("SESSION_LAST_FILE".to_string(), "rapport final.pdf".to_string())));
+        assert!(env.contains(&("SESSION_UPLOADS".to_string(), "3".to_string())));
+    }
+
     #[test]
     fn test_session_expired() {
         let mut session = Session::new();
@@ -0,0 +1,248 @@
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{ self, ErrorKind };
+use std::path::{ Path, PathBuf };
+
+use super::{ Session, SessionCookie };
+
+// -------------------------------------------------------------------------------------
+// SESSION STORE
+// -------------------------------------------------------------------------------------
+/// Stockage utilisé pour les sessions (`[http.session]`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    /// Sessions perdues à l'arrêt du serveur.
+    #[default]
+    Memory,
+    /// Un fichier JSON contenant toutes les sessions.
+    File,
+    /// Un dossier avec un fichier JSON par session.
+    Directory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionConfig {
+    #[serde(default)]
+    pub store: StoreKind,
+    /// Fichier ou dossier des stockages `file` et `directory`.
+    #[serde(default)]
+    pub store_path: Option<String>,
+    /// Intervalle entre deux suppressions des sessions expirées, en millisecondes.
+    #[serde(default = "SessionConfig::default_sweep_interval")]
+    pub sweep_interval: u64,
+    /// Cookie et durées de vie par défaut, remplaçables par serveur.
+    #[serde(flatten)]
+    pub cookie: SessionCookie,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            store: StoreKind::default(),
+            store_path: None,
+            sweep_interval: Self::default_sweep_interval(),
+            cookie: SessionCookie::default(),
+        }
+    }
+}
+
+impl SessionConfig {
+    fn default_sweep_interval() -> u64 {
+        60000
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.store != StoreKind::Memory && self.store_path.is_none() {
+            errors.push("store_path est obligatoire pour un stockage file ou directory".to_string());
+        }
+        if self.sweep_interval == 0 {
+            errors.push("sweep_interval doit être positif".to_string());
+        }
+        errors.extend(self.cookie.check());
+        errors
+    }
+
+    /// Ouvre le stockage configuré.
+    pub fn open(&self) -> Box<dyn SessionStore> {
+        match (self.store, &self.store_path) {
+            (StoreKind::File, Some(path)) => Box::new(FileStore::new(path)),
+            (StoreKind::Directory, Some(path)) => Box::new(DirectoryStore::new(path)),
+            _ => Box::new(MemoryStore),
+        }
+    }
+}
+
+/// Conservation des sessions d'une exécution du serveur à l'autre.
+pub trait SessionStore: Debug {
+    /// Sessions enregistrées, sans celles qui ont expiré depuis.
+    fn load(&mut self) -> io::Result<Vec<Session>>;
+    /// Remplace le contenu du stockage par les sessions données.
+    fn save(&mut self, sessions: &[&Session]) -> io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore;
+
+impl SessionStore for MemoryStore {
+    fn load(&mut self) -> io::Result<Vec<Session>> {
+        Ok(vec![])
+    }
+
+    fn save(&mut self, _sessions: &[&Session]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    pub path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: &str) -> Self {
+        Self { path: PathBuf::from(path) }
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&mut self) -> io::Result<Vec<Session>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let sessions: Vec<Session> = serde_json
+            ::from_str(&content)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(sessions.into_iter().filter(|session| !session.is_expired()).collect())
+    }
+
+    fn save(&mut self, sessions: &[&Session]) -> io::Result<()> {
+        let content = serde_json::to_string(sessions).map_err(io::Error::from)?;
+        write_atomic(&self.path, content.as_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectoryStore {
+    pub path: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(path: &str) -> Self {
+        Self { path: PathBuf::from(path) }
+    }
+
+    /// Fichiers de session présents dans le dossier.
+    fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        Ok(
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+                .collect()
+        )
+    }
+}
+
+impl SessionStore for DirectoryStore {
+    fn load(&mut self) -> io::Result<Vec<Session>> {
+        let mut sessions = vec![];
+        for file in self.files()? {
+            // Un fichier illisible est ignoré : il sera supprimé au prochain enregistrement
+            let Ok(content) = fs::read_to_string(&file) else {
+                continue;
+            };
+            if let Ok(session) = serde_json::from_str::<Session>(&content) {
+                if !session.is_expired() {
+                    sessions.push(session);
+                }
+            }
+        }
+        Ok(sessions)
+    }
+
+    fn save(&mut self, sessions: &[&Session]) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let mut kept = vec![];
+        for session in sessions {
+            let file = self.path.join(format!("{}.json", session.id));
+            let content = serde_json::to_string(session).map_err(io::Error::from)?;
+            write_atomic(&file, content.as_bytes())?;
+            kept.push(file);
+        }
+        for file in self.files()? {
+            if !kept.contains(&file) {
+                fs::remove_file(&file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Écrit dans un fichier temporaire puis le renomme : un arrêt brutal laisse
+/// l'ancienne version intacte.
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)
+}
+
+// -------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{ Duration, Utc };
+
+    fn temporary(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("localhost-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = temporary("sessions.json");
+        let mut store = FileStore::new(path.to_str().unwrap());
+        assert!(store.load().unwrap().is_empty());
+
+        let mut session = Session::new();
+        session.set("theme", "dark");
+        let mut expired = Session::new();
+        expired.validity_time = Utc::now() - Duration::seconds(1);
+        store.save(&[&session, &expired]).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, session.id);
+        assert_eq!(loaded[0].get::<String>("theme").as_deref(), Some("dark"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_directory_store() {
+        let path = temporary("sessions");
+        let mut store = DirectoryStore::new(path.to_str().unwrap());
+        let first = Session::new();
+        let second = Session::new();
+        store.save(&[&first, &second]).unwrap();
+        assert_eq!(store.load().unwrap().len(), 2);
+
+        // Une session absente du dernier enregistrement disparaît du dossier
+        store.save(&[&second]).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, second.id);
+        assert!(!path.join(format!("{}.json", first.id)).exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
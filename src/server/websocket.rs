@@ -0,0 +1,937 @@
+use base64::prelude::{ Engine, BASE64_STANDARD };
+use mio::net::TcpStream;
+use mio::unix::pipe::{ Receiver, Sender };
+use mio::{ Interest, Registry, Token };
+use sha1::{ Digest, Sha1 };
+use std::io::{ self, Write };
+use std::net::ToSocketAddrs;
+use std::process::{ Child, Command, Stdio };
+use std::time::{ Duration, Instant };
+
+use super::{ drain_limit, CgiRunner, CgiScript, CgiState, Request, Response, Server, ServerError, CGI };
+use crate::{ Config, Route };
+
+// -------------------------------------------------------------------------------------
+// WEBSOCKET
+// -------------------------------------------------------------------------------------
+/// Seule version du protocole acceptée (RFC 6455).
+pub const WEBSOCKET_VERSION: &str = "13";
+/// Constante concaténée à `Sec-WebSocket-Key` pour calculer `Sec-WebSocket-Accept`.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Taille maximale d'un message, fragments compris.
+const MAX_MESSAGE: usize = 1024 * 1024;
+/// Données en attente (trames pour le client, messages pour le backend) au-delà
+/// desquelles la lecture s'arrête jusqu'à ce que l'autre côté ait lu.
+const WEBSOCKET_HIGH_WATER: usize = 256 * 1024;
+/// Trames en attente pour un client qui ne lit plus (diffusion) : au-delà, il est abandonné.
+const MAX_OUTGOING: usize = 4 * MAX_MESSAGE;
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Destination des messages d'une route `websocket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketTarget {
+    /// Chaque message est renvoyé à son expéditeur.
+    Echo,
+    /// Chaque message est envoyé à tous les clients connectés à la route.
+    Broadcast,
+    /// Le flux est relayé vers un serveur TCP (`tcp://hôte:port`).
+    Tcp(String),
+    /// Commande lancée pour chaque connexion (`exec:commande args`), à la manière de
+    /// websocketd : une ligne de sa sortie par message, un message par ligne d'entrée.
+    Command(Vec<String>),
+}
+
+impl WebSocketTarget {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value == "echo" {
+            return Ok(Self::Echo);
+        }
+        if value == "broadcast" {
+            return Ok(Self::Broadcast);
+        }
+        if let Some(address) = value.strip_prefix("tcp://") {
+            let valid = address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            return match valid {
+                true => Ok(Self::Tcp(address.to_string())),
+                false => Err(format!("adresse websocket invalide : {}", value)),
+            };
+        }
+        if let Some(command) = value.strip_prefix("exec:") {
+            let args = command
+                .split_whitespace()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<String>>();
+            if args.is_empty() {
+                return Err(format!("commande websocket vide : {}", value));
+            }
+            return Ok(Self::Command(args));
+        }
+        Err(format!("websocket doit valoir echo, broadcast, tcp://hôte:port ou exec:commande : {}", value))
+    }
+}
+
+/// Trame reçue d'un client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Trame envoyée par le serveur : jamais masquée, jamais fragmentée.
+    pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= 0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Trame de fermeture avec son code.
+    pub fn close(code: u16) -> Vec<u8> {
+        Self::encode(OPCODE_CLOSE, &code.to_be_bytes())
+    }
+
+    /// Lit une trame en tête du tampon. `Ok(None)` tant qu'elle n'est pas complète,
+    /// `Err(code)` pour une trame que le client n'aurait pas dû envoyer.
+    pub fn parse(buffer: &[u8]) -> Result<Option<(Frame, usize)>, u16> {
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buffer[0] & 0x80 != 0;
+        let opcode = buffer[0] & 0x0f;
+        // Aucune extension n'est négociée : les bits réservés doivent être nuls
+        if buffer[0] & 0x70 != 0 || buffer[1] & 0x80 == 0 {
+            return Err(CLOSE_PROTOCOL_ERROR);
+        }
+        if ![OPCODE_CONTINUATION, OPCODE_TEXT, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_PING, OPCODE_PONG].contains(&opcode) {
+            return Err(CLOSE_PROTOCOL_ERROR);
+        }
+
+        let (len, mut offset) = match buffer[1] & 0x7f {
+            126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+            127 if buffer.len() >= 10 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&buffer[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            126 | 127 => {
+                return Ok(None);
+            }
+            len => (len as u64, 2),
+        };
+        // Les trames de contrôle tiennent en une trame de 125 octets au plus
+        if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+            return Err(CLOSE_PROTOCOL_ERROR);
+        }
+        if len > MAX_MESSAGE as u64 {
+            return Err(CLOSE_TOO_BIG);
+        }
+
+        let len = len as usize;
+        if buffer.len() < offset + 4 + len {
+            return Ok(None);
+        }
+        let mask = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+        offset += 4;
+        let payload = buffer[offset..offset + len]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        Ok(Some((Frame { fin, opcode, payload }, offset + len)))
+    }
+}
+
+pub struct WebSocket;
+
+impl WebSocket {
+    /// La requête demande un passage au protocole WebSocket.
+    pub fn is_upgrade(request: &Request) -> bool {
+        request
+            .header("Upgrade")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("websocket")))
+    }
+
+    /// Vérifie la poignée de main du client (RFC 6455, 4.2.1) et renvoie la valeur de
+    /// `Sec-WebSocket-Accept`, ou le code et le message d'erreur à répondre.
+    pub fn handshake(request: &Request) -> Result<String, (u16, &'static str)> {
+        if request.method != "GET" {
+            return Err((400, "Bad Request: WebSocket requires GET"));
+        }
+        let connection_upgrade = request
+            .header("Connection")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+        if !connection_upgrade {
+            return Err((400, "Bad Request: missing Connection: Upgrade"));
+        }
+        if request.header("Sec-WebSocket-Version") != Some(WEBSOCKET_VERSION) {
+            return Err((426, "Upgrade Required"));
+        }
+        let key = request.header("Sec-WebSocket-Key").unwrap_or_default();
+        if BASE64_STANDARD.decode(key).map_or(true, |nonce| nonce.len() != 16) {
+            return Err((400, "Bad Request: invalid Sec-WebSocket-Key"));
+        }
+        Ok(Self::accept_key(key))
+    }
+
+    /// `base64(sha1(clé + GUID))`
+    pub fn accept_key(key: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(HANDSHAKE_GUID.as_bytes());
+        BASE64_STANDARD.encode(hasher.finalize())
+    }
+
+    pub fn default_ping() -> u64 {
+        30000
+    }
+
+    /// Connexion non bloquante vers un serveur TCP.
+    fn connect(address: &str) -> io::Result<TcpStream> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "adresse websocket introuvable"))?;
+        TcpStream::connect(addr)
+    }
+
+    /// Lance la commande d'une route `exec:` dans le bac à sable CGI du serveur, avec
+    /// l'environnement CGI de la requête de poignée de main.
+    fn spawn(server: &Server, request: &Request, args: &[String]) -> io::Result<Child> {
+        let script = CgiScript {
+            filename: args[0].clone(),
+            script_name: request.path().to_string(),
+            path_info: String::new(),
+            runner: CgiRunner::Executable,
+        };
+        let env = CGI::build_env(server, request, &script);
+        let root = CGI::absolute(&server.root_directory);
+
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]);
+        server.cgi_sandbox.configure(&mut command, std::path::Path::new(&root));
+        command
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+    }
+}
+// -------------------------------------------------------------------------------------
+
+// -------------------------------------------------------------------------------------
+// WEBSOCKET CONNECTION
+// -------------------------------------------------------------------------------------
+/// Poignée de main acceptée, préparée par `Server::handle_request`.
+#[derive(Debug, Clone)]
+pub struct WebSocketJob {
+    pub server: Server,
+    pub request: Request,
+    pub cookie: String,
+    pub target: WebSocketTarget,
+    /// Canal de diffusion : les clients d'une même route d'un même serveur.
+    pub channel: String,
+    /// Valeur de `Sec-WebSocket-Accept`.
+    pub accept: String,
+    /// Inactivité tolérée avant un ping, en millisecondes.
+    pub ping: u64,
+}
+
+impl WebSocketJob {
+    /// `None` si la route n'a pas de `websocket` valide.
+    pub fn new(server: &Server, request: &Request, cookie: &str, route: &Route, accept: String) -> Option<Self> {
+        let target = WebSocketTarget::parse(route.websocket.as_deref()?).ok()?;
+        Some(Self {
+            server: server.clone(),
+            request: request.clone(),
+            cookie: cookie.to_string(),
+            target,
+            channel: format!("{}:{}{}", server.hostname, server.ip_addr, route.path),
+            accept,
+            ping: route.websocket_ping.unwrap_or_else(WebSocket::default_ping),
+        })
+    }
+
+    /// Réponse `101 Switching Protocols`.
+    pub fn response(&self) -> Response {
+        let mut response = Response::with_code(101, "", vec![]);
+        response.id_session = self.cookie.clone();
+        response.add_header("Upgrade", "websocket");
+        response.add_header("Connection", "Upgrade");
+        response.add_header("Sec-WebSocket-Accept", &self.accept);
+        response
+    }
+
+    pub fn log_error(&self, config: &Config, message: &str) {
+        Server::error_log(
+            &self.request,
+            config,
+            "WebSocketJob",
+            file!(),
+            line!(),
+            ServerError::WebSocketError {
+                uri: &self.request.uri,
+                message,
+            }
+        );
+    }
+}
+
+/// Destinataire des messages d'une connexion autre que le client lui-même.
+#[derive(Debug)]
+enum Backend {
+    Tcp {
+        connection: TcpStream,
+        connected: bool,
+    },
+    Command {
+        child: Child,
+        stdin: Option<Sender>,
+        stdout: Option<Receiver>,
+        /// Début de ligne reçu sans son retour à la ligne.
+        line: Vec<u8>,
+    },
+}
+
+/// Connexion WebSocket établie, pilotée par la boucle d'événements du Router.
+#[derive(Debug)]
+pub struct WebSocketConnection {
+    /// Token du client, dont le `TcpStream` reste dans `Router::clients`.
+    pub client: Token,
+    pub job: WebSocketJob,
+    backend: Option<Backend>,
+    /// Octets reçus du client pas encore découpés en trames.
+    incoming: Vec<u8>,
+    /// Trames en attente d'écriture vers le client.
+    outgoing: Vec<u8>,
+    /// Données en attente d'écriture vers le backend.
+    pending: Vec<u8>,
+    /// Message fragmenté en cours de réception : opcode et données.
+    message: Option<(u8, Vec<u8>)>,
+    /// Messages à diffuser aux autres clients du canal (opcode, données).
+    pub broadcast: Vec<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+    ping_sent: bool,
+    pub deadline: Instant,
+}
+
+impl WebSocketConnection {
+    /// Ouvre le backend de la route : connexion TCP ou commande.
+    pub fn start(job: &WebSocketJob) -> io::Result<Self> {
+        let backend = match &job.target {
+            WebSocketTarget::Echo | WebSocketTarget::Broadcast => None,
+            WebSocketTarget::Tcp(address) => Some(Backend::Tcp {
+                connection: WebSocket::connect(address)?,
+                connected: false,
+            }),
+            WebSocketTarget::Command(args) => {
+                let mut child = WebSocket::spawn(&job.server, &job.request, args)?;
+                let stdin = child.stdin.take().map(Sender::from);
+                let stdout = child.stdout.take().map(Receiver::from);
+                for sender in stdin.iter() {
+                    sender.set_nonblocking(true)?;
+                }
+                for receiver in stdout.iter() {
+                    receiver.set_nonblocking(true)?;
+                }
+                Some(Backend::Command { child, stdin, stdout, line: vec![] })
+            }
+        };
+
+        Ok(Self {
+            client: Token(0),
+            job: job.clone(),
+            backend,
+            incoming: vec![],
+            outgoing: vec![],
+            pending: vec![],
+            message: None,
+            broadcast: vec![],
+            close_sent: false,
+            close_received: false,
+            ping_sent: false,
+            deadline: Instant::now() + Duration::from_millis(job.ping),
+        })
+    }
+
+    /// Enregistre le backend dans le `Poll` du Router (connexion TCP, ou stdin et stdout).
+    pub fn register(&mut self, registry: &Registry, client: Token, tokens: [Token; 2]) -> io::Result<()> {
+        self.client = client;
+        match self.backend.as_mut() {
+            Some(Backend::Tcp { connection, .. }) => {
+                registry.register(connection, tokens[0], Interest::READABLE | Interest::WRITABLE)?;
+            }
+            Some(Backend::Command { stdin, stdout, .. }) => {
+                if let Some(stdin) = stdin.as_mut() {
+                    registry.register(stdin, tokens[0], Interest::WRITABLE)?;
+                }
+                if let Some(stdout) = stdout.as_mut() {
+                    registry.register(stdout, tokens[1], Interest::READABLE)?;
+                }
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Retire le backend du `Poll` et arrête la commande éventuelle, qui est renvoyée
+    /// pour être récupérée par le Router.
+    pub fn deregister(&mut self, registry: &Registry) -> Option<Child> {
+        match self.backend.take()? {
+            Backend::Tcp { mut connection, .. } => {
+                let _ = registry.deregister(&mut connection);
+                None
+            }
+            Backend::Command { child, stdin, stdout, .. } => {
+                if let Some(mut stdin) = stdin {
+                    let _ = registry.deregister(&mut stdin);
+                }
+                if let Some(mut stdout) = stdout {
+                    let _ = registry.deregister(&mut stdout);
+                }
+                // La commande a son propre groupe de processus (CgiSandbox::configure)
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                Some(child)
+            }
+        }
+    }
+
+    /// Lit et traite les trames du client. `Done` quand la fermeture est terminée,
+    /// `Aborted` si le client a coupé la connexion sans fermeture.
+    pub fn on_client_event(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        self.pump(stream)
+    }
+
+    /// Relaie la sortie du backend au client et lui écrit les messages en attente.
+    pub fn on_backend_event(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        self.pump(stream)
+    }
+
+    /// Fait avancer la connexion dans les deux sens. Le client n'est plus lu tant que
+    /// les données en attente dépassent `WEBSOCKET_HIGH_WATER`, ni le backend tant que
+    /// les trames pour le client la dépassent : la lecture reprend quand l'autre côté
+    /// a lu, d'où la boucle tant qu'une lecture progresse.
+    fn pump(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        loop {
+            let state = self.flush(stream)?;
+            if state != CgiState::Running {
+                return Ok(state);
+            }
+            self.write_backend()?;
+            let backend_read = self.read_backend()?;
+            let (client_read, eof) = self.read_client(stream)?;
+            if eof {
+                return match self.flush(stream)? {
+                    CgiState::Running => Ok(CgiState::Aborted),
+                    state => Ok(state),
+                };
+            }
+            if !backend_read && !client_read {
+                return self.flush(stream);
+            }
+        }
+    }
+
+    /// Lit et traite les trames du client dans la limite de la place restante ;
+    /// renvoie `(données lues, fin de connexion)`.
+    fn read_client(&mut self, stream: &mut TcpStream) -> io::Result<(bool, bool)> {
+        let room = WEBSOCKET_HIGH_WATER.saturating_sub(self.buffered());
+        if room == 0 {
+            return Ok((false, false));
+        }
+        let start = self.incoming.len();
+        let eof = drain_limit(stream, &mut self.incoming, room)?;
+        let read = self.incoming.len() > start;
+        if read {
+            self.deadline = Instant::now() + Duration::from_millis(self.job.ping);
+            self.ping_sent = false;
+        }
+        if self.close_received {
+            // Plus rien n'est attendu du client après sa fermeture
+            self.incoming.clear();
+        }
+
+        while !self.close_received {
+            let frame = match Frame::parse(&self.incoming) {
+                Ok(Some((frame, size))) => {
+                    self.incoming.drain(..size);
+                    frame
+                }
+                Ok(None) => {
+                    break;
+                }
+                Err(code) => {
+                    self.incoming.clear();
+                    self.close(code);
+                    break;
+                }
+            };
+            if let Err(code) = self.on_frame(frame) {
+                self.close(code);
+            }
+        }
+        Ok((read, eof))
+    }
+
+    /// Lit la sortie du backend dans la limite de la place restante chez le client ;
+    /// `true` si des données ont été lues.
+    fn read_backend(&mut self) -> io::Result<bool> {
+        let room = WEBSOCKET_HIGH_WATER.saturating_sub(self.outgoing.len());
+        if room == 0 {
+            return Ok(false);
+        }
+        let mut messages = vec![];
+        let mut eof = false;
+        let mut read = false;
+        match self.backend.as_mut() {
+            Some(Backend::Tcp { connection, connected }) => {
+                if !*connected {
+                    *connected = Self::is_connected(connection)?;
+                }
+                if *connected {
+                    let mut data = vec![];
+                    eof = drain_limit(connection, &mut data, room)?;
+                    if !data.is_empty() {
+                        read = true;
+                        messages.push((OPCODE_BINARY, data));
+                    }
+                }
+            }
+            Some(Backend::Command { stdout: Some(stdout), line, .. }) => {
+                let start = line.len();
+                eof = drain_limit(stdout, line, room)?;
+                read = line.len() > start;
+                while let Some(end) = line.iter().position(|byte| *byte == b'\n') {
+                    let mut text = line.drain(..=end).collect::<Vec<u8>>();
+                    text.pop();
+                    if text.last() == Some(&b'\r') {
+                        text.pop();
+                    }
+                    messages.push((OPCODE_TEXT, text));
+                }
+                // Une ligne trop longue part telle quelle plutôt que de grossir sans fin
+                if (eof || line.len() >= MAX_MESSAGE) && !line.is_empty() {
+                    messages.push((OPCODE_TEXT, std::mem::take(line)));
+                }
+            }
+            _ => (),
+        }
+
+        for (opcode, data) in messages {
+            // Une ligne de la commande qui n'est pas de l'UTF-8 part en binaire
+            let opcode = match opcode == OPCODE_TEXT && std::str::from_utf8(&data).is_err() {
+                true => OPCODE_BINARY,
+                false => opcode,
+            };
+            self.send(opcode, &data);
+        }
+        if eof {
+            // Le backend a terminé : la connexion se ferme normalement
+            self.close(CLOSE_NORMAL);
+        }
+        Ok(read)
+    }
+
+    /// Octets en attente d'écriture, vers le client, le backend ou le canal.
+    fn buffered(&self) -> usize {
+        self.outgoing.len() + self.pending.len() + self.broadcast.iter().map(|(_, data)| data.len()).sum::<usize>()
+    }
+
+    /// Ajoute une trame à envoyer au client ; elle part au prochain `flush`.
+    pub fn send(&mut self, opcode: u8, payload: &[u8]) {
+        if !self.close_sent {
+            self.outgoing.extend_from_slice(&Frame::encode(opcode, payload));
+        }
+    }
+
+    /// Commence la fermeture : plus aucune trame n'est envoyée après celle-ci.
+    pub fn close(&mut self, code: u16) {
+        if !self.close_sent {
+            self.outgoing.extend_from_slice(&Frame::close(code));
+            self.close_sent = true;
+            // Le client a quelques secondes pour répondre à la fermeture
+            self.deadline = Instant::now() + Duration::from_millis(self.job.ping.min(5000));
+        }
+    }
+
+    /// Écrit les trames en attente. `Done` quand les deux fermetures ont eu lieu,
+    /// `Aborted` si le client ne lit plus.
+    pub fn flush(&mut self, stream: &mut TcpStream) -> io::Result<CgiState> {
+        let mut written = 0;
+        while written < self.outgoing.len() {
+            match stream.write(&self.outgoing[written..]) {
+                Ok(0) => {
+                    return Ok(CgiState::Aborted);
+                }
+                Ok(n) => {
+                    written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        self.outgoing.drain(..written);
+        if self.outgoing.len() > MAX_OUTGOING {
+            return Ok(CgiState::Aborted);
+        }
+
+        if self.close_sent && self.close_received && self.outgoing.is_empty() {
+            return Ok(CgiState::Done);
+        }
+        Ok(CgiState::Running)
+    }
+
+    /// Échéance atteinte : ping si le client est silencieux, fermeture s'il ne répond
+    /// pas. Renvoie `false` quand la connexion doit être abandonnée.
+    pub fn on_timeout(&mut self) -> bool {
+        if self.close_sent || self.ping_sent {
+            return false;
+        }
+        self.send(OPCODE_PING, b"");
+        self.ping_sent = true;
+        self.deadline = Instant::now() + Duration::from_millis(self.job.ping);
+        true
+    }
+
+    /// Traite une trame complète ; `Err(code)` ferme la connexion avec ce code.
+    fn on_frame(&mut self, frame: Frame) -> Result<(), u16> {
+        match frame.opcode {
+            OPCODE_PING => {
+                self.send(OPCODE_PONG, &frame.payload);
+                Ok(())
+            }
+            OPCODE_PONG => Ok(()),
+            OPCODE_CLOSE => {
+                self.close_received = true;
+                let code = match frame.payload.len() {
+                    0 => CLOSE_NORMAL,
+                    1 => {
+                        return Err(CLOSE_PROTOCOL_ERROR);
+                    }
+                    _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                };
+                // 1005 et 1006 ne circulent jamais sur le réseau ; les autres codes hors
+                // des plages définies (RFC 6455, 7.4) sont refusés
+                if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+                // Réponse avec le même code, sauf si la fermeture vient de nous
+                self.close(code);
+                Ok(())
+            }
+            OPCODE_CONTINUATION => {
+                let Some((opcode, mut data)) = self.message.take() else {
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                };
+                if data.len() + frame.payload.len() > MAX_MESSAGE {
+                    return Err(CLOSE_TOO_BIG);
+                }
+                data.extend_from_slice(&frame.payload);
+                match frame.fin {
+                    true => self.on_message(opcode, data),
+                    false => {
+                        self.message = Some((opcode, data));
+                        Ok(())
+                    }
+                }
+            }
+            opcode => {
+                if self.message.is_some() {
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+                match frame.fin {
+                    true => self.on_message(opcode, frame.payload),
+                    false => {
+                        self.message = Some((opcode, frame.payload));
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Message complet du client, transmis selon la cible de la route.
+    fn on_message(&mut self, opcode: u8, data: Vec<u8>) -> Result<(), u16> {
+        if opcode == OPCODE_TEXT && std::str::from_utf8(&data).is_err() {
+            return Err(CLOSE_INVALID_DATA);
+        }
+        match &self.job.target {
+            WebSocketTarget::Echo => self.send(opcode, &data),
+            WebSocketTarget::Broadcast => self.broadcast.push((opcode, data)),
+            WebSocketTarget::Tcp(_) => self.pending.extend_from_slice(&data),
+            WebSocketTarget::Command(_) => {
+                self.pending.extend_from_slice(&data);
+                self.pending.push(b'\n');
+            }
+        }
+        Ok(())
+    }
+
+    /// Écrit les messages en attente vers le backend, jusqu'à ce qu'il soit plein.
+    fn write_backend(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let writer: &mut dyn Write = match self.backend.as_mut() {
+            Some(Backend::Tcp { connection, connected: true }) => connection,
+            Some(Backend::Command { stdin: Some(stdin), .. }) => stdin,
+            _ => {
+                return Ok(());
+            }
+        };
+
+        let mut written = 0;
+        let mut closed = false;
+        while written < self.pending.len() {
+            match writer.write(&self.pending[written..]) {
+                Ok(n) => {
+                    written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // Le backend ne lit plus : les messages en attente sont perdus
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    written = self.pending.len();
+                    closed = true;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        self.pending.drain(..written);
+        if closed {
+            self.close(CLOSE_GOING_AWAY);
+        }
+        Ok(())
+    }
+
+    /// `Ok(false)` tant que la connexion est en cours d'établissement.
+    fn is_connected(stream: &TcpStream) -> io::Result<bool> {
+        if let Some(e) = stream.take_error()? {
+            return Err(e);
+        }
+        match stream.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+// -------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connexion du client et celle, non bloquante, acceptée par le serveur.
+    fn socket_pair() -> (std::net::TcpStream, TcpStream) {
+        let client_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let accepted = client_listener.accept().unwrap().0;
+        accepted.set_nonblocking(true).unwrap();
+        (client, TcpStream::from_std(accepted))
+    }
+
+    fn echo_job() -> WebSocketJob {
+        let server = Server::new(
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+            vec![8080],
+            "src/www".to_string(),
+            String::new(),
+            String::new(),
+            5000,
+            vec!["GET".to_string()],
+            false,
+            vec![],
+            vec![]
+        );
+        let route: Route = toml::from_str("path = \"/ws\"\nwebsocket = \"echo\"").unwrap();
+        WebSocketJob::new(&server, &Request::default(), "", &route, String::new()).unwrap()
+    }
+
+    /// Trame masquée telle qu'un navigateur l'envoie.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = match payload.len() {
+            0..=125 => vec![first, 0x80 | (payload.len() as u8)],
+            len => [&[first, 0x80 | 126][..], &(len as u16).to_be_bytes()].concat(),
+        };
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Exemple de la RFC 6455, 1.3
+        assert_eq!(WebSocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut request = Request::default();
+        request.method = "GET".to_string();
+        for (name, value) in [
+            ("Upgrade", "websocket"),
+            ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "13"),
+        ] {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        assert!(WebSocket::is_upgrade(&request));
+        assert_eq!(WebSocket::handshake(&request).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        request.headers.insert("Sec-WebSocket-Version".to_string(), "8".to_string());
+        assert_eq!(WebSocket::handshake(&request).unwrap_err().0, 426);
+        request.headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+        request.headers.insert("Sec-WebSocket-Key".to_string(), "court".to_string());
+        assert_eq!(WebSocket::handshake(&request).unwrap_err().0, 400);
+    }
+
+    #[test]
+    fn test_target_parse() {
+        assert_eq!(WebSocketTarget::parse("echo"), Ok(WebSocketTarget::Echo));
+        assert_eq!(
+            WebSocketTarget::parse("tcp://127.0.0.1:9000"),
+            Ok(WebSocketTarget::Tcp("127.0.0.1:9000".to_string()))
+        );
+        assert_eq!(
+            WebSocketTarget::parse("exec:/usr/bin/tail -f log"),
+            Ok(WebSocketTarget::Command(vec!["/usr/bin/tail".to_string(), "-f".to_string(), "log".to_string()]))
+        );
+        assert!(WebSocketTarget::parse("tcp://localhost").is_err());
+        assert!(WebSocketTarget::parse("exec:").is_err());
+        assert!(WebSocketTarget::parse("ws://localhost:80").is_err());
+    }
+
+    #[test]
+    fn test_frame_parse() {
+        let frame = client_frame(0x81, b"Hello");
+        assert_eq!(Frame::parse(&frame[..4]), Ok(None));
+        let (parsed, size) = Frame::parse(&frame).unwrap().unwrap();
+        assert_eq!(size, frame.len());
+        assert_eq!(parsed, Frame { fin: true, opcode: OPCODE_TEXT, payload: b"Hello".to_vec() });
+
+        // Trame non masquée, bit réservé, ping fragmenté
+        assert_eq!(Frame::parse(&Frame::encode(OPCODE_TEXT, b"Hi")), Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(Frame::parse(&client_frame(0xc1, b"Hi")), Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(Frame::parse(&client_frame(0x09, b"")), Err(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn test_frame_encode_lengths() {
+        assert_eq!(Frame::encode(OPCODE_TEXT, b"Hello"), b"\x81\x05Hello");
+        let medium = Frame::encode(OPCODE_BINARY, &[0; 300]);
+        assert_eq!(&medium[..4], &[0x82, 126, 0x01, 0x2c]);
+        let large = Frame::encode(OPCODE_BINARY, &[0; 70000]);
+        assert_eq!(&large[..10], &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+        assert_eq!(Frame::close(CLOSE_NORMAL), b"\x88\x02\x03\xe8");
+    }
+
+    #[test]
+    fn test_echo_fragments_ping_and_close() {
+        let (mut client, mut stream) = socket_pair();
+        let mut connection = WebSocketConnection::start(&echo_job()).unwrap();
+
+        let mut sent = client_frame(0x01, b"Bon");
+        sent.extend(client_frame(0x89, b"p"));
+        sent.extend(client_frame(0x80, b"jour"));
+        sent.extend(client_frame(0x88, &CLOSE_NORMAL.to_be_bytes()));
+        client.write_all(&sent).unwrap();
+
+        let mut state = CgiState::Running;
+        let start = Instant::now();
+        while state == CgiState::Running && start.elapsed() < Duration::from_secs(5) {
+            state = connection.on_client_event(&mut stream).unwrap();
+        }
+        assert_eq!(state, CgiState::Done);
+
+        drop(stream);
+        let mut received = vec![];
+        io::Read::read_to_end(&mut client, &mut received).unwrap();
+        let mut expected = Frame::encode(OPCODE_PONG, b"p");
+        expected.extend(Frame::encode(OPCODE_TEXT, b"Bonjour"));
+        expected.extend(Frame::close(CLOSE_NORMAL));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_invalid_close_code() {
+        for (code, reply) in [(1000, 1000), (1001, 1001), (4000, 4000), (1005, 1002), (1006, 1002), (999, 1002), (2000, 1002), (5000, 1002)] {
+            let mut connection = WebSocketConnection::start(&echo_job()).unwrap();
+            let frame = Frame { fin: true, opcode: OPCODE_CLOSE, payload: u16::to_be_bytes(code).to_vec() };
+            if let Err(code) = connection.on_frame(frame) {
+                connection.close(code);
+            }
+            assert_eq!(connection.outgoing, Frame::close(reply), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_echo_backpressure() {
+        let (client, mut stream) = socket_pair();
+        let mut connection = WebSocketConnection::start(&echo_job()).unwrap();
+
+        // Le client envoie sans jamais lire les échos
+        let mut writer = client.try_clone().unwrap();
+        let frame = client_frame(0x82, &[0x2a; 60000]);
+        let sender = std::thread::spawn(move || {
+            for _ in 0..400 {
+                if writer.write_all(&frame).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..50 {
+            assert_eq!(connection.on_client_event(&mut stream).unwrap(), CgiState::Running);
+            assert!(connection.outgoing.len() < WEBSOCKET_HIGH_WATER + 64 * 1024);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!sender.is_finished());
+
+        drop(stream);
+        drop(client);
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,13 @@
+#!/usr/bin/env ruby
+require 'cgi'
+
+# Exemple CGI/1.1 : lit le formulaire envoyé en POST sur l'entrée standard
+length = ENV.fetch('CONTENT_LENGTH', '0').to_i
+body = length > 0 ? $stdin.read(length) : ''
+params = CGI.parse(ENV['REQUEST_METHOD'] == 'POST' ? body : ENV.fetch('QUERY_STRING', ''))
+name = CGI.escapeHTML(params.fetch('name', ['inconnu']).first)
+
+puts "Status: 200 OK"
+puts "Content-Type: text/html; charset=utf-8"
+puts
+puts "<p>Bonjour #{name} (#{ENV['REQUEST_METHOD']} #{ENV['SCRIPT_NAME']}#{ENV['PATH_INFO']})</p>"
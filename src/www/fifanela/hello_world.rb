@@ -1,3 +1,5 @@
 #!/usr/bin/env ruby
 
+puts "Content-Type: text/plain; charset=utf-8"
+puts
 puts 'Hello World'